@@ -5,9 +5,14 @@ if [[ $ext -ne 0 ]]; then
 	exit $ext
 fi
 sudo setcap cap_net_admin=eip target/release/rust_tcp
-target/release/rust_tcp &
+# ./run.sh --tap puts the stack on the far end of tap0, as 192.168.0.2
+dev=tun0
+if [[ "$1" == "--tap" ]]; then
+	dev=tap0
+fi
+target/release/rust_tcp "$@" &
 pid=$!
-sudo ip addr add 192.168.0.1/24 dev tun0
-sudo ip link set up dev tun0
+sudo ip addr add 192.168.0.1/24 dev $dev
+sudo ip link set up dev $dev
 trap "kill $pid" INT TERM
 wait $pid
@@ -0,0 +1,380 @@
+// Ethernet (and ARP, RFC 826), for when we're on the other end of a tap device rather than a tun
+//
+// A tap device hands us whole Ethernet frames, and wants them back: so everything we send has to be
+// addressed to the MAC address of whoever is to get it. Over IPv4, ARP tells us that -- we ask
+// for it with a broadcast, and answer anyone asking for ours. Whatever we want to send while an
+// answer is on its way waits for it (only the latest packet to each address, as RFC 1122 S2.3.2.2
+// has it).
+//
+// There's no routing here, nor IPv6 neighbor discovery. Instead, we remember the MAC address every
+// IP packet came from, and send anything going back to its sender there; for a packet that came
+// through a router, that's the router. Past that, an IPv6 peer has to be told our MAC address some
+// other way (with `ip neigh add`, say), and we can't send to it until it has sent to us.
+
+use crate::network_parse::{
+    self, ArpPacket, EthernetHeader, MacAddress, Protocol, VlanTag, ARP_REPLY, ARP_REQUEST,
+    ETHERTYPE_ARP, ETHERTYPE_IPV4, ETHERTYPE_IPV6,
+};
+use crate::nic::Nic;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+// how long a MAC address we've learned is good for, without hearing from it again
+const NEIGHBOR_TIMEOUT: Duration = Duration::from_secs(60);
+
+// no more than one ARP request a second for any one address (RFC 1122 S2.3.2.1)
+const ARP_INTERVAL: Duration = Duration::from_secs(1);
+
+// the most addresses we keep track of, and the most packets left waiting on ARP
+const MAX_NEIGHBORS: usize = 256;
+const MAX_PENDING: usize = 64;
+
+// frames shorter than this (less the frame check sequence, which isn't ours to add) get padded out
+const MIN_FRAME_LEN: usize = 60;
+
+pub struct Link {
+    mac: MacAddress,
+    // the IPv4 address ARP requests are answered for, and sent from
+    address: Ipv4Addr,
+    // frames go out tagged with this VLAN, and only frames tagged with it are taken
+    vlan: Option<u16>,
+    neighbors: HashMap<IpAddr, (MacAddress, Instant)>,
+    // packets waiting for an address to be resolved, and when we last asked for it
+    pending: HashMap<Ipv4Addr, (Vec<u8>, Instant)>,
+}
+
+impl Link {
+    pub fn new(mac: MacAddress, address: Ipv4Addr, vlan: Option<u16>) -> Self {
+        Link {
+            mac,
+            address,
+            vlan,
+            neighbors: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    // send an IP packet out in a frame of its own, as soon as we know where to
+    pub fn send<N: Nic>(&mut self, frames: &mut N, packet: &[u8], now: Instant) -> io::Result<()> {
+        let Some((ethertype, destination)) = destination(packet) else {
+            return Ok(());
+        };
+        if let Some(mac) = self.resolve(destination, now) {
+            return self.send_frame(frames, mac, ethertype, packet);
+        }
+        let IpAddr::V4(destination) = destination else {
+            // nothing we can do to find out
+            return Ok(());
+        };
+
+        if !self.pending.contains_key(&destination) && self.pending.len() >= MAX_PENDING {
+            self.pending
+                .retain(|_, (_, asked)| now.duration_since(*asked) < NEIGHBOR_TIMEOUT);
+            if self.pending.len() >= MAX_PENDING {
+                return Ok(());
+            }
+        }
+        let asked = match self.pending.get(&destination) {
+            Some((_, asked)) if now.duration_since(*asked) < ARP_INTERVAL => *asked,
+            _ => {
+                let request = ArpPacket {
+                    operation: ARP_REQUEST,
+                    sender_mac: self.mac,
+                    sender_ip: self.address,
+                    target_mac: MacAddress([0; 6]),
+                    target_ip: destination,
+                };
+                self.send_frame(
+                    frames,
+                    MacAddress::BROADCAST,
+                    ETHERTYPE_ARP,
+                    &request.to_bytes(),
+                )?;
+                now
+            }
+        };
+        self.pending.insert(destination, (packet.to_vec(), asked));
+        Ok(())
+    }
+
+    // a frame that has come in. if it carries an IP packet for us, returns where in the frame that
+    // starts (right after the ethertype)
+    pub fn on_frame<N: Nic>(
+        &mut self,
+        frames: &mut N,
+        frame: &[u8],
+        now: Instant,
+    ) -> io::Result<Option<usize>> {
+        let Ok((payload, header)) = network_parse::parse_ethernet(frame) else {
+            return Ok(None);
+        };
+        // (a VLAN ID of zero only carries a priority, and is as good as no tag at all)
+        let vlan = header.vlan.map(|vlan| vlan.id).filter(|id| *id != 0);
+        if vlan != self.vlan
+            || !(header.destination == self.mac || header.destination.is_multicast())
+            || header.source.is_multicast()
+        {
+            return Ok(None);
+        }
+
+        match Protocol::from(header.ethertype) {
+            Protocol::Arp => {
+                self.on_arp(frames, payload, now)?;
+                Ok(None)
+            }
+            Protocol::Ipv4 | Protocol::Ipv6 => {
+                if let Some((_, source)) = source(payload) {
+                    self.learn(source, header.source, now);
+                }
+                Ok(Some(header.header_len()))
+            }
+            Protocol::Other => Ok(None),
+        }
+    }
+
+    fn on_arp<N: Nic>(&mut self, frames: &mut N, payload: &[u8], now: Instant) -> io::Result<()> {
+        let Ok((_, arp)) = network_parse::parse_arp(payload) else {
+            return Ok(());
+        };
+        if arp.sender_ip == self.address || arp.sender_mac.is_multicast() {
+            return Ok(());
+        }
+
+        // we update what we know about the sender either way, but only start keeping track of it
+        // if it's talking to us
+        let for_us = arp.target_ip == self.address;
+        let sender = IpAddr::V4(arp.sender_ip);
+        if for_us || self.resolve(sender, now).is_some() {
+            self.learn(sender, arp.sender_mac, now);
+            if let Some((packet, _)) = self.pending.remove(&arp.sender_ip) {
+                self.send_frame(frames, arp.sender_mac, ETHERTYPE_IPV4, &packet)?;
+            }
+        }
+
+        if for_us && arp.operation == ARP_REQUEST {
+            let reply = ArpPacket {
+                operation: ARP_REPLY,
+                sender_mac: self.mac,
+                sender_ip: self.address,
+                target_mac: arp.sender_mac,
+                target_ip: arp.sender_ip,
+            };
+            self.send_frame(frames, arp.sender_mac, ETHERTYPE_ARP, &reply.to_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn learn(&mut self, ip: IpAddr, mac: MacAddress, now: Instant) {
+        if !self.neighbors.contains_key(&ip) && self.neighbors.len() >= MAX_NEIGHBORS {
+            self.neighbors
+                .retain(|_, (_, heard)| now.duration_since(*heard) < NEIGHBOR_TIMEOUT);
+            if self.neighbors.len() >= MAX_NEIGHBORS {
+                return;
+            }
+        }
+        self.neighbors.insert(ip, (mac, now));
+    }
+
+    // the MAC address to send a packet for destination to, if we know it
+    fn resolve(&self, destination: IpAddr, now: Instant) -> Option<MacAddress> {
+        match destination {
+            IpAddr::V4(ip) if ip.is_broadcast() => return Some(MacAddress::BROADCAST),
+            // (RFC 1112 S6.4, and RFC 2464 S7)
+            IpAddr::V4(ip) if ip.is_multicast() => {
+                let [_, b, c, d] = ip.octets();
+                return Some(MacAddress([0x01, 0x00, 0x5e, b & 0x7f, c, d]));
+            }
+            IpAddr::V6(ip) if ip.is_multicast() => {
+                let [.., a, b, c, d] = ip.octets();
+                return Some(MacAddress([0x33, 0x33, a, b, c, d]));
+            }
+            _ => {}
+        }
+        let (mac, heard) = self.neighbors.get(&destination)?;
+        (now.duration_since(*heard) < NEIGHBOR_TIMEOUT).then_some(*mac)
+    }
+
+    fn send_frame<N: Nic>(
+        &self,
+        frames: &mut N,
+        destination: MacAddress,
+        ethertype: u16,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let header = EthernetHeader {
+            destination,
+            source: self.mac,
+            vlan: self.vlan.map(|id| VlanTag {
+                priority: 0,
+                drop_eligible: false,
+                id,
+            }),
+            ethertype,
+        };
+        let mut frame = header.to_bytes();
+        frame.extend_from_slice(payload);
+        if frame.len() < MIN_FRAME_LEN {
+            frame.resize(MIN_FRAME_LEN, 0);
+        }
+        frames.send(&frame)?;
+        Ok(())
+    }
+}
+
+// the ethertype of an IP packet, and the address at the given offset into it, whichever version it
+// is
+fn address_at(packet: &[u8], v4: usize, v6: usize) -> Option<(u16, IpAddr)> {
+    match packet.first()? >> 4 {
+        4 => {
+            let octets: [u8; 4] = packet.get(v4..v4 + 4)?.try_into().ok()?;
+            Some((ETHERTYPE_IPV4, Ipv4Addr::from(octets).into()))
+        }
+        6 => {
+            let octets: [u8; 16] = packet.get(v6..v6 + 16)?.try_into().ok()?;
+            Some((ETHERTYPE_IPV6, Ipv6Addr::from(octets).into()))
+        }
+        _ => None,
+    }
+}
+
+fn source(packet: &[u8]) -> Option<(u16, IpAddr)> {
+    address_at(packet, 12, 8)
+}
+
+fn destination(packet: &[u8]) -> Option<(u16, IpAddr)> {
+    address_at(packet, 16, 24)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OURS: MacAddress = MacAddress([2, 0, 0, 0, 0, 1]);
+    const THEIRS: MacAddress = MacAddress([2, 0, 0, 0, 0, 2]);
+
+    fn link() -> Link {
+        Link::new(OURS, Ipv4Addr::new(192, 168, 0, 2), None)
+    }
+
+    fn arp_frame(operation: u16, target_ip: [u8; 4], target_mac: MacAddress) -> Vec<u8> {
+        let arp = ArpPacket {
+            operation,
+            sender_mac: THEIRS,
+            sender_ip: Ipv4Addr::new(192, 168, 0, 1),
+            target_mac,
+            target_ip: target_ip.into(),
+        };
+        let header = EthernetHeader {
+            destination: target_mac,
+            source: THEIRS,
+            vlan: None,
+            ethertype: ETHERTYPE_ARP,
+        };
+        [header.to_bytes(), arp.to_bytes()].concat()
+    }
+
+    fn ipv4_packet(source: [u8; 4], destination: [u8; 4]) -> Vec<u8> {
+        let ip = etherparse::Ipv4Header::new(0, 64, 253, source, destination);
+        let mut packet = Vec::new();
+        ip.write(&mut packet).unwrap();
+        packet
+    }
+
+    #[test]
+    fn arp_requests_for_our_address_are_answered() {
+        let now = Instant::now();
+        let mut frames = Vec::new();
+        let mut link = link();
+
+        // (not for anyone else's)
+        let request = arp_frame(ARP_REQUEST, [192, 168, 0, 3], MacAddress::BROADCAST);
+        assert_eq!(link.on_frame(&mut frames, &request, now).unwrap(), None);
+        assert!(frames.is_empty());
+
+        let request = arp_frame(ARP_REQUEST, [192, 168, 0, 2], MacAddress::BROADCAST);
+        assert_eq!(link.on_frame(&mut frames, &request, now).unwrap(), None);
+        let (arp, header) = network_parse::parse_ethernet(&frames[0]).unwrap();
+        assert_eq!(header.destination, THEIRS);
+        assert_eq!(header.source, OURS);
+        let (_, reply) = network_parse::parse_arp(arp).unwrap();
+        assert_eq!(reply.operation, ARP_REPLY);
+        assert_eq!(reply.sender_mac, OURS);
+        assert_eq!(reply.target_ip, Ipv4Addr::new(192, 168, 0, 1));
+
+        // and the asker is one less address to ask about
+        assert_eq!(
+            link.resolve(Ipv4Addr::new(192, 168, 0, 1).into(), now),
+            Some(THEIRS)
+        );
+    }
+
+    #[test]
+    fn packets_wait_for_their_address_to_be_resolved() {
+        let now = Instant::now();
+        let mut frames = Vec::new();
+        let mut link = link();
+
+        let packet = ipv4_packet([192, 168, 0, 2], [192, 168, 0, 1]);
+        link.send(&mut frames, &packet, now).unwrap();
+        // (only the one request, however much there is to send)
+        link.send(&mut frames, &packet, now).unwrap();
+        assert_eq!(frames.len(), 1);
+        let request = frames.remove(0);
+        let (arp, header) = network_parse::parse_ethernet(&request).unwrap();
+        assert_eq!(header.destination, MacAddress::BROADCAST);
+        let (_, request) = network_parse::parse_arp(arp).unwrap();
+        assert_eq!(request.operation, ARP_REQUEST);
+        assert_eq!(request.target_ip, Ipv4Addr::new(192, 168, 0, 1));
+
+        let reply = arp_frame(ARP_REPLY, [192, 168, 0, 2], OURS);
+        link.on_frame(&mut frames, &reply, now).unwrap();
+        link.send(&mut frames, &packet, now).unwrap();
+        assert_eq!(frames.len(), 2);
+        for frame in frames {
+            let (payload, header) = network_parse::parse_ethernet(&frame).unwrap();
+            assert_eq!(header.destination, THEIRS);
+            assert_eq!(header.ethertype, ETHERTYPE_IPV4);
+            // (padded out to the shortest a frame can be)
+            assert_eq!(&payload[..packet.len()], packet);
+        }
+    }
+
+    #[test]
+    fn only_frames_for_us_and_our_vlan_are_taken() {
+        let now = Instant::now();
+        let mut frames = Vec::new();
+        let mut link = Link::new(OURS, Ipv4Addr::new(192, 168, 0, 2), Some(100));
+
+        let packet = ipv4_packet([192, 168, 0, 1], [192, 168, 0, 2]);
+        let mut header = EthernetHeader {
+            destination: OURS,
+            source: THEIRS,
+            vlan: None,
+            ethertype: ETHERTYPE_IPV4,
+        };
+        let frame = [header.to_bytes(), packet.clone()].concat();
+        assert_eq!(link.on_frame(&mut frames, &frame, now).unwrap(), None);
+
+        header.vlan = Some(VlanTag {
+            priority: 0,
+            drop_eligible: false,
+            id: 100,
+        });
+        let frame = [header.to_bytes(), packet.clone()].concat();
+        assert_eq!(link.on_frame(&mut frames, &frame, now).unwrap(), Some(18));
+
+        header.destination = MacAddress([2, 0, 0, 0, 0, 3]);
+        let frame = [header.to_bytes(), packet.clone()].concat();
+        assert_eq!(link.on_frame(&mut frames, &frame, now).unwrap(), None);
+
+        // replies go back where the packet came from, on the same VLAN
+        let reply = ipv4_packet([192, 168, 0, 2], [192, 168, 0, 1]);
+        link.send(&mut frames, &reply, now).unwrap();
+        let (payload, header) = network_parse::parse_ethernet(&frames[0]).unwrap();
+        assert_eq!(header.destination, THEIRS);
+        assert_eq!(header.vlan.map(|vlan| vlan.id), Some(100));
+        assert_eq!(&payload[..reply.len()], reply);
+    }
+}
@@ -0,0 +1,626 @@
+// ICMP (RFC 792), and ICMPv6 (RFC 4443)
+//
+// We answer pings: an echo request gets an echo reply, carrying back the identifier, sequence
+// number and data the request came with, so whoever sent it knows we're up.
+//
+// The errors we get back quote the start of the packet that caused them, which for one of our TCP
+// segments is enough to tell which connection it's about, and where in it. Those get handed on to
+// the connection; anything else that arrives over ICMP is dropped.
+//
+// We send errors of our own too, about packets we can't do anything with. Never about another
+// error, or about a packet that wasn't for us alone, so two hosts can't set each other off, or be
+// used to flood someone else (RFC 1122 S3.2.2, RFC 4443 S2.4(e)). And only so many a second.
+
+use crate::network_parse::{self, IPV6_FRAGMENT};
+use crate::nic::Nic;
+use crate::tcp::{IcmpError, IpHeader, MTU};
+use nom::{
+    bytes::complete::take,
+    number::complete::{be_u16, be_u8},
+    sequence::tuple,
+    IResult,
+};
+use std::io;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// what IP calls ICMP, and what IPv6 calls ICMPv6
+pub const PROTOCOL: u8 = 1;
+pub const PROTOCOL_V6: u8 = 58;
+
+const ECHO_REPLY: u8 = 0;
+const DESTINATION_UNREACHABLE: u8 = 3;
+const SOURCE_QUENCH: u8 = 4;
+const REDIRECT: u8 = 5;
+const ECHO_REQUEST: u8 = 8;
+const TIME_EXCEEDED: u8 = 11;
+const PARAMETER_PROBLEM: u8 = 12;
+
+// (codes, for destination unreachable and time exceeded)
+const PROTOCOL_UNREACHABLE: u8 = 2;
+const PORT_UNREACHABLE: u8 = 3;
+const FRAGMENTATION_NEEDED: u8 = 4;
+const REASSEMBLY_TIME_EXCEEDED: u8 = 1;
+
+// every ICMPv6 type below this is an error (RFC 4443 S2.1)
+const ERRORS_V6: u8 = 128;
+const DESTINATION_UNREACHABLE_V6: u8 = 1;
+const PACKET_TOO_BIG_V6: u8 = 2;
+const TIME_EXCEEDED_V6: u8 = 3;
+const PARAMETER_PROBLEM_V6: u8 = 4;
+const ECHO_REQUEST_V6: u8 = 128;
+const ECHO_REPLY_V6: u8 = 129;
+
+// (codes, for destination unreachable and parameter problem)
+const PORT_UNREACHABLE_V6: u8 = 4;
+const UNRECOGNIZED_NEXT_HEADER_V6: u8 = 1;
+
+// where the next header field is in an IPv6 header
+const NEXT_HEADER_OFFSET_V6: u32 = 6;
+
+const HOP_LIMIT: u8 = 64;
+
+// the biggest an error gets, quoted packet and all: as much as any IPv4 host can take (RFC 1812
+// S4.3.2.3), and no more than the IPv6 minimum MTU (RFC 4443 S2.4(c))
+const MAX_ERROR_LEN: usize = 576;
+const MAX_ERROR_LEN_V6: usize = 1280;
+
+const ICMP_HEADER_LEN: usize = 8;
+
+// the most errors we send a second, to anyone (RFC 1812 S4.3.2.8, RFC 4443 S2.4(f))
+const ERROR_LIMIT: u32 = 100;
+
+// an error about one of our TCP segments, which starts at seq, on the connection between local and
+// remote
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpError {
+    pub local: (IpAddr, u16),
+    pub remote: (IpAddr, u16),
+    pub seq: u32,
+    pub error: IcmpError,
+}
+
+// the errors we send
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorMessage {
+    // nothing here handles the protocol the packet carries
+    ProtocolUnreachable,
+    // nothing is bound to the port it's for
+    PortUnreachable,
+    // the rest of the packet's fragments never turned up
+    ReassemblyTimeExceeded,
+}
+
+#[derive(Default)]
+pub struct ErrorLimit {
+    // when the current second started, and how many have gone out in it
+    second_started: Option<Instant>,
+    sent: u32,
+}
+
+impl ErrorLimit {
+    fn allow(&mut self, now: Instant) -> bool {
+        if self
+            .second_started
+            .is_none_or(|at| now >= at + Duration::from_secs(1))
+        {
+            self.second_started = Some(now);
+            self.sent = 0;
+        }
+        if self.sent >= ERROR_LIMIT {
+            return false;
+        }
+        self.sent += 1;
+        true
+    }
+}
+
+#[derive(Debug)]
+pub struct IcmpMessage<'a> {
+    pub icmp_type: u8,
+    pub code: u8,
+    // what this is depends on the type: for an echo, the identifier and the sequence number
+    pub rest_of_header: [u8; 4],
+    pub data: &'a [u8],
+}
+
+pub fn parse_icmp(input: &[u8]) -> IResult<&[u8], IcmpMessage<'_>> {
+    // (the checksum is checked over the raw message)
+    let (data, (icmp_type, code, _checksum, rest_of_header)) =
+        tuple((be_u8, be_u8, be_u16, take(4usize)))(input)?;
+    Ok((
+        &data[data.len()..],
+        IcmpMessage {
+            icmp_type,
+            code,
+            rest_of_header: rest_of_header.try_into().expect("took 4 bytes"),
+            data,
+        },
+    ))
+}
+
+// an ICMP message that has come in (the payload of an IP packet with protocol either PROTOCOL or
+// PROTOCOL_V6). if it's an error about one of our TCP segments, that's for the connection to deal
+// with
+pub fn on_packet<N: Nic>(
+    nic: &mut N,
+    ip_header: &IpHeader,
+    protocol: u8,
+    input: &[u8],
+) -> io::Result<Option<TcpError>> {
+    let v6 = ip_header.source().is_ipv6();
+    if protocol != if v6 { PROTOCOL_V6 } else { PROTOCOL } {
+        return Ok(None);
+    }
+    if checksum(ip_header.source(), ip_header.destination(), input) != 0 {
+        return Ok(None);
+    }
+    let Ok((_, message)) = parse_icmp(input) else {
+        return Ok(None);
+    };
+
+    let reply = match (v6, message.icmp_type, message.code) {
+        (false, ECHO_REQUEST, 0) => ECHO_REPLY,
+        (true, ECHO_REQUEST_V6, 0) => ECHO_REPLY_V6,
+        _ => return Ok(tcp_error(ip_header, &message)),
+    };
+    // (not to pings sent to everyone at once, RFC 1122 S3.2.2.6)
+    if !is_unicast(ip_header.destination()) {
+        return Ok(None);
+    }
+    send(
+        nic,
+        ip_header.destination(),
+        ip_header.source(),
+        reply,
+        0,
+        message.rest_of_header,
+        message.data,
+    )?;
+    Ok(None)
+}
+
+// what an error says about the TCP segment it quotes, if it quotes one of ours. hearing the peer
+// doesn't do TCP, or that nothing's listening on the port, means the connection is refused; being
+// told the segment was too big comes with how big it can be
+fn tcp_error(ip_header: &IpHeader, message: &IcmpMessage) -> Option<TcpError> {
+    let rest_of_header = message.rest_of_header;
+    let error = match (
+        ip_header.source().is_ipv6(),
+        message.icmp_type,
+        message.code,
+    ) {
+        (false, DESTINATION_UNREACHABLE, PROTOCOL_UNREACHABLE | PORT_UNREACHABLE) => {
+            IcmpError::Refused
+        }
+        // (the next-hop MTU is in the bottom half of the rest of the header, RFC 1191 S4)
+        (false, DESTINATION_UNREACHABLE, FRAGMENTATION_NEEDED) => IcmpError::PacketTooBig(
+            u16::from_be_bytes([rest_of_header[2], rest_of_header[3]]) as u32,
+        ),
+        (false, DESTINATION_UNREACHABLE | TIME_EXCEEDED | PARAMETER_PROBLEM, _) => {
+            IcmpError::Unreachable
+        }
+        (true, DESTINATION_UNREACHABLE_V6, PORT_UNREACHABLE_V6)
+        | (true, PARAMETER_PROBLEM_V6, UNRECOGNIZED_NEXT_HEADER_V6) => IcmpError::Refused,
+        (true, PACKET_TOO_BIG_V6, 0) => IcmpError::PacketTooBig(u32::from_be_bytes(rest_of_header)),
+        (true, DESTINATION_UNREACHABLE_V6 | TIME_EXCEEDED_V6 | PARAMETER_PROBLEM_V6, _) => {
+            IcmpError::Unreachable
+        }
+        _ => return None,
+    };
+
+    let (source, destination, protocol, segment) = parse_original(message.data)?;
+    if protocol != etherparse::ip_number::TCP
+        || source != ip_header.destination()
+        || segment.len() < 8
+    {
+        return None;
+    }
+    // all we can count on being quoted is the first 8 bytes of the segment: the ports, and the
+    // sequence number
+    let source_port = u16::from_be_bytes([segment[0], segment[1]]);
+    let destination_port = u16::from_be_bytes([segment[2], segment[3]]);
+    let seq = u32::from_be_bytes([segment[4], segment[5], segment[6], segment[7]]);
+    Some(TcpError {
+        local: (source, source_port),
+        remote: (destination, destination_port),
+        seq,
+        error,
+    })
+}
+
+// the source, destination and protocol of a packet (quoted in an ICMP error, or one we're sending
+// an error about), and what it carries. only for the first fragment of a packet, the only one
+// that starts with what the packet carries
+fn parse_original(packet: &[u8]) -> Option<(IpAddr, IpAddr, u8, &[u8])> {
+    match packet.first()? >> 4 {
+        4 => {
+            let header = etherparse::Ipv4HeaderSlice::from_slice(packet).ok()?;
+            if header.fragments_offset() != 0 {
+                return None;
+            }
+            Some((
+                header.source_addr().into(),
+                header.destination_addr().into(),
+                header.protocol(),
+                &packet[header.slice().len()..],
+            ))
+        }
+        6 => {
+            let (rest, header) = network_parse::parse_ipv6(packet).ok()?;
+            let (rest, protocol) =
+                network_parse::parse_ipv6_extension_headers(header.next_header, rest).ok()?;
+            if protocol == IPV6_FRAGMENT {
+                return None;
+            }
+            Some((
+                header.source_address.into(),
+                header.destination_address.into(),
+                protocol,
+                rest,
+            ))
+        }
+        _ => None,
+    }
+}
+
+// tell whoever sent us original (an IP packet, header and all) about what went wrong with it
+pub fn send_error<N: Nic>(
+    nic: &mut N,
+    limit: &mut ErrorLimit,
+    message: ErrorMessage,
+    original: &[u8],
+    now: Instant,
+) -> io::Result<()> {
+    let Some((source, destination, protocol, payload)) = parse_original(original) else {
+        return Ok(());
+    };
+    let v6 = source.is_ipv6();
+    let about_error = protocol == if v6 { PROTOCOL_V6 } else { PROTOCOL }
+        && payload
+            .first()
+            .is_none_or(|&icmp_type| is_error(v6, icmp_type));
+    if about_error || !is_unicast(source) || source.is_unspecified() || !is_unicast(destination) {
+        return Ok(());
+    }
+
+    let (icmp_type, code, rest_of_header) = match (v6, message) {
+        (false, ErrorMessage::ProtocolUnreachable) => {
+            (DESTINATION_UNREACHABLE, PROTOCOL_UNREACHABLE, [0; 4])
+        }
+        (false, ErrorMessage::PortUnreachable) => {
+            (DESTINATION_UNREACHABLE, PORT_UNREACHABLE, [0; 4])
+        }
+        (false, ErrorMessage::ReassemblyTimeExceeded) => {
+            (TIME_EXCEEDED, REASSEMBLY_TIME_EXCEEDED, [0; 4])
+        }
+        // IPv6 says which next header field it didn't recognize. we only point at the one in the
+        // IPv6 header itself, so not past any extension headers
+        (true, ErrorMessage::ProtocolUnreachable)
+            if original.get(NEXT_HEADER_OFFSET_V6 as usize) == Some(&protocol) =>
+        {
+            (
+                PARAMETER_PROBLEM_V6,
+                UNRECOGNIZED_NEXT_HEADER_V6,
+                NEXT_HEADER_OFFSET_V6.to_be_bytes(),
+            )
+        }
+        (true, ErrorMessage::PortUnreachable) => {
+            (DESTINATION_UNREACHABLE_V6, PORT_UNREACHABLE_V6, [0; 4])
+        }
+        // (and we don't put IPv6 fragments back together)
+        (true, _) => return Ok(()),
+    };
+
+    if !limit.allow(now) {
+        return Ok(());
+    }
+    // quoting as much of the packet as fits
+    let (ip_header_len, max_len) = if v6 {
+        (40, MAX_ERROR_LEN_V6)
+    } else {
+        (20, MAX_ERROR_LEN)
+    };
+    let quoted =
+        &original[..std::cmp::min(original.len(), max_len - ip_header_len - ICMP_HEADER_LEN)];
+    send(
+        nic,
+        destination,
+        source,
+        icmp_type,
+        code,
+        rest_of_header,
+        quoted,
+    )
+}
+
+fn is_error(v6: bool, icmp_type: u8) -> bool {
+    if v6 {
+        icmp_type < ERRORS_V6
+    } else {
+        matches!(
+            icmp_type,
+            DESTINATION_UNREACHABLE | SOURCE_QUENCH | REDIRECT | TIME_EXCEEDED | PARAMETER_PROBLEM
+        )
+    }
+}
+
+fn send<N: Nic>(
+    nic: &mut N,
+    local: IpAddr,
+    remote: IpAddr,
+    icmp_type: u8,
+    code: u8,
+    rest_of_header: [u8; 4],
+    data: &[u8],
+) -> io::Result<()> {
+    let mut message = vec![icmp_type, code, 0, 0];
+    message.extend(rest_of_header);
+    message.extend_from_slice(data);
+    let checksum = checksum(local, remote, &message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    let mut packet = Vec::new();
+    match (local, remote) {
+        (IpAddr::V4(local), IpAddr::V4(remote)) => etherparse::Ipv4Header::new(
+            message.len() as u16,
+            HOP_LIMIT,
+            PROTOCOL,
+            local.octets(),
+            remote.octets(),
+        )
+        .write(&mut packet),
+        (IpAddr::V6(local), IpAddr::V6(remote)) => etherparse::Ipv6Header {
+            traffic_class: 0,
+            flow_label: 0,
+            payload_length: message.len() as u16,
+            next_header: PROTOCOL_V6,
+            hop_limit: HOP_LIMIT,
+            source: local.octets(),
+            destination: remote.octets(),
+        }
+        .write(&mut packet),
+        _ => return Ok(()),
+    }
+    .map_err(io::Error::other)?;
+    packet.extend(message);
+
+    // we don't fragment what we send, so a ping too big to answer in one packet goes unanswered
+    if packet.len() > MTU {
+        return Ok(());
+    }
+    nic.send(&packet)?;
+    Ok(())
+}
+
+fn is_unicast(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => !ip.is_broadcast() && !ip.is_multicast(),
+        IpAddr::V6(ip) => !ip.is_multicast(),
+    }
+}
+
+// the checksum of an ICMP message. over IPv6, the pseudo-header is included, just as it is for TCP
+// (RFC 4443 S2.3). over a message with a correct checksum in it, this is zero
+fn checksum(source: IpAddr, destination: IpAddr, message: &[u8]) -> u16 {
+    let pseudo_header = if source.is_ipv6() {
+        network_parse::pseudo_header(source, destination, PROTOCOL_V6, message.len())
+    } else {
+        Vec::new()
+    };
+    network_parse::internet_checksum(&pseudo_header, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const US: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2));
+    const THEM: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1));
+
+    // the ip header of a packet from them to us
+    fn ip_header(them: IpAddr, us: IpAddr) -> IpHeader {
+        let mut packet = Vec::new();
+        send(&mut packet, them, us, 0, 0, [0; 4], &[]).unwrap();
+        let packet = &packet[0];
+        match (them, us) {
+            (IpAddr::V4(_), IpAddr::V4(_)) => {
+                IpHeader::from(&etherparse::Ipv4HeaderSlice::from_slice(packet).unwrap())
+            }
+            _ => IpHeader::from(&crate::network_parse::parse_ipv6(packet).unwrap().1),
+        }
+    }
+
+    fn ping(them: IpAddr, us: IpAddr, icmp_type: u8) -> Vec<u8> {
+        let mut packet = Vec::new();
+        send(
+            &mut packet,
+            them,
+            us,
+            icmp_type,
+            0,
+            [0, 24, 0, 1],
+            b"are you up",
+        )
+        .unwrap();
+        let ip_header_len = if us.is_ipv6() { 40 } else { 20 };
+        packet[0][ip_header_len..].to_vec()
+    }
+
+    #[test]
+    fn echo_requests_are_answered() {
+        let mut nic = Vec::new();
+        let request = ping(THEM, US, ECHO_REQUEST);
+        on_packet(&mut nic, &ip_header(THEM, US), PROTOCOL, &request).unwrap();
+
+        let ip_header = etherparse::Ipv4HeaderSlice::from_slice(&nic[0]).unwrap();
+        let message = &nic[0][ip_header.slice().len()..];
+        assert_eq!(IpAddr::from(ip_header.source_addr()), US);
+        assert_eq!(IpAddr::from(ip_header.destination_addr()), THEM);
+        assert_eq!(ip_header.protocol(), PROTOCOL);
+        assert_eq!(checksum(US, THEM, message), 0);
+        let (_, reply) = parse_icmp(message).unwrap();
+        assert_eq!(reply.icmp_type, ECHO_REPLY);
+        assert_eq!(reply.code, 0);
+        assert_eq!(reply.rest_of_header, [0, 24, 0, 1]);
+        assert_eq!(reply.data, b"are you up");
+    }
+
+    #[test]
+    fn echo_requests_are_answered_over_ipv6() {
+        let us = IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2));
+        let them = IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1));
+        let mut nic = Vec::new();
+        let request = ping(them, us, ECHO_REQUEST_V6);
+        on_packet(&mut nic, &ip_header(them, us), PROTOCOL_V6, &request).unwrap();
+
+        let (message, ip_header) = crate::network_parse::parse_ipv6(&nic[0]).unwrap();
+        assert_eq!(IpAddr::from(ip_header.source_address), us);
+        assert_eq!(ip_header.next_header, PROTOCOL_V6);
+        assert_eq!(checksum(us, them, message), 0);
+        let (_, reply) = parse_icmp(message).unwrap();
+        assert_eq!(reply.icmp_type, ECHO_REPLY_V6);
+        assert_eq!(reply.data, b"are you up");
+    }
+
+    #[test]
+    fn only_good_echo_requests_are_answered() {
+        let mut nic = Vec::new();
+
+        let mut corrupted = ping(THEM, US, ECHO_REQUEST);
+        corrupted[8] ^= 1;
+        on_packet(&mut nic, &ip_header(THEM, US), PROTOCOL, &corrupted).unwrap();
+
+        let reply = ping(THEM, US, ECHO_REPLY);
+        on_packet(&mut nic, &ip_header(THEM, US), PROTOCOL, &reply).unwrap();
+
+        let broadcast = IpAddr::V4(Ipv4Addr::BROADCAST);
+        let request = ping(THEM, broadcast, ECHO_REQUEST);
+        on_packet(&mut nic, &ip_header(THEM, broadcast), PROTOCOL, &request).unwrap();
+
+        assert!(nic.is_empty());
+    }
+
+    // a packet from us to them, carrying the given protocol
+    fn original(protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        let (IpAddr::V4(us), IpAddr::V4(them)) = (US, THEM) else {
+            unreachable!()
+        };
+        etherparse::Ipv4Header::new(
+            payload.len() as u16,
+            64,
+            protocol,
+            us.octets(),
+            them.octets(),
+        )
+        .write(&mut packet)
+        .unwrap();
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn errors_quote_what_caused_them() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let packet = original(253, &[7; 1000]);
+        send_error(
+            &mut nic,
+            &mut ErrorLimit::default(),
+            ErrorMessage::ProtocolUnreachable,
+            &packet,
+            now,
+        )
+        .unwrap();
+
+        // back to whoever sent it, with as much of it as fits
+        assert_eq!(nic[0].len(), MAX_ERROR_LEN);
+        let ip_header = etherparse::Ipv4HeaderSlice::from_slice(&nic[0]).unwrap();
+        assert_eq!(IpAddr::from(ip_header.destination_addr()), US);
+        let (_, error) = parse_icmp(&nic[0][20..]).unwrap();
+        assert_eq!(error.icmp_type, DESTINATION_UNREACHABLE);
+        assert_eq!(error.code, PROTOCOL_UNREACHABLE);
+        assert_eq!(error.data, &packet[..MAX_ERROR_LEN - 28]);
+    }
+
+    #[test]
+    fn errors_are_not_sent_about_errors() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut limit = ErrorLimit::default();
+        let error = original(PROTOCOL, &[DESTINATION_UNREACHABLE, 0, 0, 0, 0, 0, 0, 0]);
+        send_error(
+            &mut nic,
+            &mut limit,
+            ErrorMessage::ProtocolUnreachable,
+            &error,
+            now,
+        )
+        .unwrap();
+        assert!(nic.is_empty());
+
+        // and only so many about anything else
+        let packet = original(253, &[]);
+        for _ in 0..2 * ERROR_LIMIT {
+            send_error(
+                &mut nic,
+                &mut limit,
+                ErrorMessage::ProtocolUnreachable,
+                &packet,
+                now,
+            )
+            .unwrap();
+        }
+        assert_eq!(nic.len(), ERROR_LIMIT as usize);
+    }
+
+    #[test]
+    fn errors_about_our_segments_say_which_connection() {
+        let us = IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2));
+        let router = IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0xff));
+        let them = IpAddr::V6(Ipv6Addr::new(0xfd01, 0, 0, 0, 0, 0, 0, 1));
+
+        // a segment from us to them, quoted in a packet too big from a router on the way
+        let mut segment = Vec::new();
+        send(&mut segment, us, them, 0, 0, [0; 4], &[]).unwrap();
+        let mut segment = segment.remove(0);
+        segment.truncate(40);
+        segment[6] = etherparse::ip_number::TCP;
+        segment.extend(5000u16.to_be_bytes());
+        segment.extend(80u16.to_be_bytes());
+        segment.extend(1234u32.to_be_bytes());
+        let mut error = Vec::new();
+        send(
+            &mut error,
+            router,
+            us,
+            PACKET_TOO_BIG_V6,
+            0,
+            1400u32.to_be_bytes(),
+            &segment,
+        )
+        .unwrap();
+
+        let mut nic = Vec::new();
+        let error = on_packet(
+            &mut nic,
+            &ip_header(router, us),
+            PROTOCOL_V6,
+            &error[0][40..],
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            error,
+            TcpError {
+                local: (us, 5000),
+                remote: (them, 80),
+                seq: 1234,
+                error: IcmpError::PacketTooBig(1400),
+            }
+        );
+        assert!(nic.is_empty());
+    }
+}
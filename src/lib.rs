@@ -0,0 +1,1828 @@
+use std::borrow::Cow;
+use std::collections::{hash_map::Entry, HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::ethernet::Link;
+use crate::network_parse::MacAddress;
+use crate::nic::{Device, Nic};
+use crate::reassembly::{FragmentKey, Reassembler};
+use crate::tcp::{
+    ChallengeAckLimit, FastOpenCookies, IcmpError, IpHeader, IsnGenerator, PathMtuCache,
+    SynCookies, TcpState,
+};
+
+mod ethernet;
+mod icmp;
+pub mod network_parse;
+mod nic;
+mod reassembly;
+mod tcp;
+mod udp;
+
+pub use tcp::congestion;
+pub use tcp::Keepalive;
+
+// how much data the application can have written to a connection, but not yet had acknowledged,
+// before writes start blocking
+const SEND_QUEUE_SIZE: usize = 64 * 1024;
+
+// the longest the packet loop waits for a packet before running the connection timers anyway
+const MAX_POLL_WAIT: Duration = Duration::from_millis(10);
+
+// how many handshakes a listener can have going at once. past that, it answers SYNs with SYN
+// cookies, rather than setting up a connection for each one
+const SYN_QUEUE_SIZE: usize = 256;
+
+// a listener with this many handshakes going may be under a SYN flood, so it starts answering with
+// SYN cookies early -- but only SYNs a cookie can answer in full. one asking for what a cookie has
+// no room for (ECN, or fast open) still gets a place in the queue, until it's full
+const SYN_COOKIE_THRESHOLD: usize = SYN_QUEUE_SIZE / 2;
+
+// how many fast open connections a listener can have taken data from while their handshakes are
+// still going (RFC 7413 S5.1's max_qlen). past that, a SYN gets an ordinary handshake, cookie or no
+// cookie, and the data that came with it is dropped
+const FAST_OPEN_QUEUE_SIZE: usize = 64;
+
+// where the local ports for outgoing connections come from (RFC 6335 S6)
+const EPHEMERAL_PORTS: std::ops::RangeInclusive<Port> = 49152..=65535;
+
+type Port = u16;
+
+// what to do with IPv4 packets whose sender has picked the route they take (RFC 791 S3.1). a host
+// is meant to send its replies back along the same route (RFC 1122 S3.2.1.8), which lets anyone
+// who can get on it pass themselves off as someone else, so by default they're dropped. ignoring
+// the route instead handles them like any other packet, with the replies going the usual way
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SourceRoutePolicy {
+    #[default]
+    Drop,
+    Ignore,
+}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+struct Quad {
+    source: (IpAddr, Port),
+    destination: (IpAddr, Port),
+}
+
+#[derive(Default)]
+struct ConnectionManager {
+    // the packet loop has stopped, or is to stop: nothing is going to happen on any connection or
+    // socket from now on
+    terminate: bool,
+    connections: HashMap<Quad, TcpState>,
+    // one entry for each bound port
+    listeners: HashMap<Port, Listener>,
+    // and for each bound UDP port, which are another set of ports altogether
+    udp_sockets: HashMap<Port, udp::Socket>,
+    // where our connections' initial sequence numbers come from
+    isns: IsnGenerator,
+    syn_cookies: SynCookies,
+    challenge_acks: ChallengeAckLimit,
+    // the cookies we hand out to fast open clients, and the ones servers have handed us
+    fast_open: FastOpenCookies,
+    fast_open_cache: HashMap<IpAddr, Vec<u8>>,
+    // the IPv4 datagrams that have only arrived in pieces so far
+    fragments: Reassembler,
+    source_routes: SourceRoutePolicy,
+    icmp_errors: icmp::ErrorLimit,
+    // the path MTUs routers have told us about, for new connections to start out with
+    path_mtus: PathMtuCache,
+}
+
+#[derive(Default)]
+struct Listener {
+    // connections still in the middle of the handshake. they move on to pending once it's done,
+    // or are dropped if the peer never finishes it (once our SYN-ACK has been retransmitted as
+    // many times as it's going to be)
+    syn_queue: VecDeque<Quad>,
+    // connections waiting to be accepted
+    pending: VecDeque<Quad>,
+    // the congestion controller new connections start out with
+    congestion: congestion::Algorithm,
+    // take data that comes with a SYN from clients with a fast open cookie
+    fast_open: bool,
+}
+
+// everything the packet loop shares with the application
+#[derive(Default)]
+struct Shared {
+    manager: Mutex<ConnectionManager>,
+    // a connection has arrived on a bound port
+    pending_var: Condvar,
+    // a connection (or UDP socket) has something new to read
+    read_var: Condvar,
+    // a connection has had some of its data acknowledged, or a UDP socket's datagrams have gone
+    write_var: Condvar,
+}
+
+type InterfaceHandle = Arc<Shared>;
+
+pub struct Interface {
+    handle: Option<InterfaceHandle>,
+    join: Option<thread::JoinHandle<io::Result<()>>>,
+}
+
+impl Interface {
+    pub fn new() -> io::Result<Self> {
+        let nic = tun_tap::Iface::new("tun0", tun_tap::Mode::Tun)?;
+        Ok(Self::start(nic))
+    }
+
+    // open a tap device instead, and be a host on the Ethernet link at its other end: with our own
+    // mac address, and address (for ARP to find us by, and to send ARP requests from). with a vlan,
+    // only frames tagged with it are ours, and everything we send is tagged with it
+    pub fn new_tap(mac: MacAddress, address: Ipv4Addr, vlan: Option<u16>) -> io::Result<Self> {
+        let iface = tun_tap::Iface::new("tap0", tun_tap::Mode::Tap)?;
+        let nic = nic::Tap::new(iface, Link::new(mac, address, vlan));
+        Ok(Self::start(nic))
+    }
+
+    fn start<D: Device + Send + 'static>(nic: D) -> Self {
+        let handle: InterfaceHandle = Arc::default();
+        let join = {
+            let handle = handle.clone();
+            thread::spawn(move || packet_loop(nic, handle))
+        };
+
+        Interface {
+            handle: Some(handle),
+            join: Some(join),
+        }
+    }
+
+    // listen on port, over IPv4 and IPv6 alike
+    pub fn bind(&mut self, port: Port) -> io::Result<TcpListener> {
+        let handle = self.handle.as_ref().expect("interface is alive");
+        let mut manager = handle.manager.lock().unwrap();
+        match manager.listeners.entry(port) {
+            Entry::Vacant(v) => {
+                v.insert(Listener::default());
+            }
+            Entry::Occupied(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "port already bound",
+                ));
+            }
+        };
+        drop(manager);
+
+        Ok(TcpListener {
+            port,
+            handle: handle.clone(),
+        })
+    }
+
+    // bind a UDP socket to local: the address this end of the tun device goes by, or an unspecified
+    // one to receive datagrams sent to any address (but send none). port 0 picks a free port
+    pub fn bind_udp(&mut self, local: SocketAddr) -> io::Result<UdpSocket> {
+        let handle = self.handle.as_ref().expect("interface is alive");
+        let mut manager = handle.manager.lock().unwrap();
+        let port = if local.port() == 0 {
+            EPHEMERAL_PORTS
+                .into_iter()
+                .find(|port| !manager.udp_sockets.contains_key(port))
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::AddrNotAvailable, "no local ports left")
+                })?
+        } else {
+            local.port()
+        };
+        match manager.udp_sockets.entry(port) {
+            Entry::Vacant(v) => {
+                v.insert(udp::Socket::new(local.ip()));
+            }
+            Entry::Occupied(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "port already bound",
+                ));
+            }
+        };
+        drop(manager);
+
+        Ok(UdpSocket {
+            port,
+            handle: handle.clone(),
+        })
+    }
+
+    pub fn set_source_route_policy(&mut self, policy: SourceRoutePolicy) {
+        let handle = self.handle.as_ref().expect("interface is alive");
+        handle.manager.lock().unwrap().source_routes = policy;
+    }
+
+    // open a connection to remote, from local (the address this end of the tun device goes by,
+    // for the same version of IP as remote), and wait for the handshake to finish
+    pub fn connect(&mut self, local: IpAddr, remote: SocketAddr) -> io::Result<TcpStream> {
+        self.open(local, remote, None)
+    }
+
+    // connect, with data to send straight away. if we've had a fast open cookie from the server
+    // before, the data goes in our SYN, and the server can have it a round trip sooner; if not,
+    // we ask for one for next time, and the data goes once the handshake is done
+    pub fn connect_fast_open(
+        &mut self,
+        local: IpAddr,
+        remote: SocketAddr,
+        data: &[u8],
+    ) -> io::Result<TcpStream> {
+        self.open(local, remote, Some(data))
+    }
+
+    fn open(
+        &mut self,
+        local: IpAddr,
+        remote: SocketAddr,
+        fast_open: Option<&[u8]>,
+    ) -> io::Result<TcpStream> {
+        if local.is_ipv4() != remote.is_ipv4() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "local and remote addresses are for different versions of ip",
+            ));
+        }
+
+        let handle = self.handle.as_ref().expect("interface is alive");
+        let mut manager = handle.manager.lock().unwrap();
+
+        let port = EPHEMERAL_PORTS
+            .into_iter()
+            .find(|port| {
+                !manager.listeners.contains_key(port)
+                    && !manager
+                        .connections
+                        .keys()
+                        .any(|quad| quad.destination.1 == *port)
+            })
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::AddrNotAvailable, "no local ports left")
+            })?;
+        let quad = Quad {
+            source: (remote.ip(), remote.port()),
+            destination: (local, port),
+        };
+        // the packet loop sends our SYN on its next tick
+        let now = Instant::now();
+        let iss = manager.isns.generate(quad.destination, quad.source, now);
+        let mut connection = TcpState::connect(
+            quad.destination,
+            quad.source,
+            iss,
+            congestion::Algorithm::default(),
+            now,
+        );
+        if let Some(mtu) = manager.path_mtus.get(remote.ip(), now) {
+            connection.set_path_mtu(mtu, now);
+        }
+        if let Some(data) = fast_open {
+            connection.set_fast_open(manager.fast_open_cache.get(&remote.ip()).cloned());
+            connection.unacked.extend(data);
+        }
+        manager.connections.insert(quad, connection);
+
+        loop {
+            let connection = manager.connections.get(&quad).ok_or_else(terminated)?;
+            if let Some(reason) = connection.aborted() {
+                manager.connections.remove(&quad);
+                return Err(reason.into());
+            }
+            if !connection.is_connecting() {
+                if let Some(cookie) = connection.fast_open_cookie() {
+                    let cookie = cookie.to_vec();
+                    manager.fast_open_cache.insert(remote.ip(), cookie);
+                }
+                return Ok(TcpStream {
+                    quad,
+                    handle: handle.clone(),
+                });
+            }
+
+            if manager.terminate {
+                return Err(stopped());
+            }
+            // (a connection becomes writable once it's established)
+            manager = handle.write_var.wait(manager).unwrap();
+        }
+    }
+}
+
+impl Drop for Interface {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.manager.lock().unwrap().terminate = true;
+        }
+
+        if let Some(join) = self.join.take() {
+            if let Ok(Err(e)) = join.join() {
+                eprintln!("packet loop failed: {e}");
+            }
+        }
+    }
+}
+
+fn packet_loop<D: Device>(nic: D, handle: InterfaceHandle) -> io::Result<()> {
+    let result = run_packet_loop(nic, &handle);
+    stop(&handle);
+    result
+}
+
+// once the packet loop is gone, anyone waiting on it would wait forever, so wake them all up to
+// find out
+fn stop(handle: &Shared) {
+    handle.manager.lock().unwrap().terminate = true;
+    handle.pending_var.notify_all();
+    handle.read_var.notify_all();
+    handle.write_var.notify_all();
+}
+
+// only a device we can no longer wait on or read from stops the loop. failing to send is just
+// another way for a packet to get lost, and the connection that sent it gets over it the same way
+fn run_packet_loop<D: Device>(mut nic: D, handle: &Shared) -> io::Result<()> {
+    let mut buf = [0u8; nic::MAX_LEN];
+
+    loop {
+        // wait for the next packet, but not forever -- the timers on our connections (for
+        // retransmissions, pacing and the like) need to run even when the network has gone quiet
+        let timeout = {
+            let manager = handle.manager.lock().unwrap();
+            let now = Instant::now();
+            manager
+                .connections
+                .values()
+                .filter_map(|connection| connection.next_deadline())
+                .min()
+                .map_or(MAX_POLL_WAIT, |deadline| {
+                    std::cmp::min(deadline.saturating_duration_since(now), MAX_POLL_WAIT)
+                })
+        };
+        let mut pfd = [nix::poll::PollFd::new(
+            nic.as_raw_fd(),
+            nix::poll::PollFlags::POLLIN,
+        )];
+        // poll only does milliseconds, so round up rather than spin until the deadline
+        let timeout = timeout.as_micros().div_ceil(1000) as i32;
+        let ready = nix::poll::poll(&mut pfd[..], timeout).map_err(io::Error::from)?;
+
+        let now = Instant::now();
+        let mut manager = handle.manager.lock().unwrap();
+        if manager.terminate {
+            return Ok(());
+        }
+        let mut aborted = false;
+        for connection in manager.connections.values_mut() {
+            let was_aborted = connection.aborted().is_some();
+            if let Err(e) = connection.on_tick(&mut nic, now) {
+                eprintln!("failed to send: {e}");
+            }
+            aborted |= !was_aborted && connection.aborted().is_some();
+        }
+        let mut flushed = false;
+        for (port, socket) in manager.udp_sockets.iter_mut() {
+            match socket.flush(&mut nic, *port) {
+                Ok(sent) => flushed |= sent,
+                Err(e) => eprintln!("failed to send: {e}"),
+            }
+        }
+        manager.update_syn_queues();
+        manager.remove_finished();
+        manager.path_mtus.on_tick(now);
+        for original in manager.fragments.on_tick(now) {
+            if let Err(e) = icmp::send_error(
+                &mut nic,
+                &mut manager.icmp_errors,
+                icmp::ErrorMessage::ReassemblyTimeExceeded,
+                &original,
+                now,
+            ) {
+                eprintln!("failed to send: {e}");
+            }
+        }
+
+        if ready == 0 {
+            if aborted || flushed {
+                // anyone waiting on an aborted connection needs to hear about it, as does anyone
+                // waiting for room to send datagrams
+                drop(manager);
+                handle.read_var.notify_all();
+                handle.write_var.notify_all();
+            }
+            continue;
+        }
+
+        // (over a tap device, what came in may have been for the link layer alone)
+        if let Some(packet) = nic.recv(&mut buf[..])? {
+            if let Err(e) = manager.on_packet(&mut nic, packet, now) {
+                eprintln!("failed to send: {e}");
+            }
+        }
+        drop(manager);
+
+        handle.pending_var.notify_all();
+        handle.read_var.notify_all();
+        handle.write_var.notify_all();
+    }
+}
+
+impl ConnectionManager {
+    fn on_packet<N: Nic>(&mut self, nic: &mut N, input: &[u8], now: Instant) -> io::Result<()> {
+        // the tun interface gives us the packet info header first, which tells us what kind of
+        // packet follows it
+        let tun_header = match network_parse::TunTapHeader::from_slice(input) {
+            Ok(tun_header) => tun_header,
+            Err(_) => {
+                eprintln!("something went wrong");
+                return Ok(());
+            }
+        };
+        //eprintln!("tun_header:{tun_header:?}");
+
+        let packet = &input[tun_header.header_len()..];
+
+        let Some((ip_header, protocol, input)) = self.parse_ip(tun_header.protocol(), packet, now)
+        else {
+            return Ok(());
+        };
+
+        match protocol {
+            icmp::PROTOCOL | icmp::PROTOCOL_V6 => {
+                let Some(error) = icmp::on_packet(nic, &ip_header, protocol, &input)? else {
+                    return Ok(());
+                };
+                let quad = Quad {
+                    source: error.remote,
+                    destination: error.local,
+                };
+                if let Some(c) = self.connections.get_mut(&quad) {
+                    let path_mtu = c.path_mtu();
+                    c.on_icmp_error(nic, error.error, error.seq, now)?;
+                    // (only once the connection has taken it seriously)
+                    if let IcmpError::PacketTooBig(_) = error.error {
+                        if c.path_mtu() < path_mtu {
+                            self.path_mtus.insert(quad.source.0, c.path_mtu(), now);
+                        }
+                    }
+                }
+                return Ok(());
+            }
+            etherparse::ip_number::TCP => {}
+            udp::PROTOCOL => return self.on_datagram(nic, &ip_header, &input, packet, now),
+            // nothing else
+            _ => {
+                return icmp::send_error(
+                    nic,
+                    &mut self.icmp_errors,
+                    icmp::ErrorMessage::ProtocolUnreachable,
+                    packet,
+                    now,
+                );
+            }
+        }
+
+        let tcp_header = match etherparse::TcpHeaderSlice::from_slice(&input) {
+            Ok(tcp_header) => tcp_header,
+            Err(err) => {
+                eprintln!("ignoring weird packet {err:?}");
+                return Ok(());
+            }
+        };
+
+        // Once here, we know we have recieved a tcp packet.
+        // From here, we want to check to see if we have receieved data from this
+        // address before (and if so, continue from the current state in the tcp
+        // handshake process with that address), or add it as a new connection (and
+        // thus start the tcp handshake process)
+
+        // (source_ip, source_port, destination_ip, destination_port)
+        // This is a single connection in the TCP/IP protocol
+        // When we use TCP/IP, we will generate a map from this quad, to the state for the
+        // connection it represents
+
+        let source_port = tcp_header.source_port();
+        let destination_port = tcp_header.destination_port();
+        let payload = &input[tcp_header.slice().len()..];
+
+        let quad = Quad {
+            source: (ip_header.source(), source_port),
+            destination: (ip_header.destination(), destination_port),
+        };
+        if let Some(c) = self.connections.get_mut(&quad) {
+            c.on_packet(
+                nic,
+                ip_header,
+                tcp_header,
+                payload,
+                &mut self.challenge_acks,
+                now,
+            )?;
+            // this may have finished a handshake
+            self.update_syn_queues();
+            return Ok(());
+        }
+
+        // only connections to ports someone is listening on
+        let Some(listener) = self.listeners.get_mut(&destination_port) else {
+            return Ok(());
+        };
+        let connection = if !tcp_header.syn() {
+            // perhaps the end of a handshake we answered with a cookie
+            match TcpState::from_syn_cookie(
+                &ip_header,
+                &tcp_header,
+                &self.syn_cookies,
+                listener.congestion,
+                now,
+            ) {
+                Some(mut c) => {
+                    c.on_packet(
+                        nic,
+                        ip_header,
+                        tcp_header,
+                        payload,
+                        &mut self.challenge_acks,
+                        now,
+                    )?;
+                    Some(c)
+                }
+                None => None,
+            }
+        } else if listener.syn_queue.len() >= SYN_QUEUE_SIZE
+            || (listener.syn_queue.len() >= SYN_COOKIE_THRESHOLD
+                && TcpState::fits_in_syn_cookie(&tcp_header, listener.fast_open))
+        {
+            // we may be getting flooded with SYNs, so don't keep anything for this one
+            TcpState::send_syn_cookie(nic, ip_header, tcp_header, &mut self.syn_cookies, now)?;
+            None
+        } else {
+            // a fast open connection skips the SYN queue, so it needs a limit of its own
+            let fast_open_queued = listener
+                .pending
+                .iter()
+                .filter(|quad| {
+                    self.connections
+                        .get(quad)
+                        .is_some_and(|c| c.is_connecting())
+                })
+                .count();
+            let fast_open = listener.fast_open && fast_open_queued < FAST_OPEN_QUEUE_SIZE;
+            let iss = self.isns.generate(quad.destination, quad.source, now);
+            TcpState::accept(
+                nic,
+                ip_header,
+                tcp_header,
+                payload,
+                iss,
+                listener.congestion,
+                fast_open.then_some(&self.fast_open),
+                now,
+            )?
+        };
+        if let Some(mut c) = connection {
+            if let Some(mtu) = self.path_mtus.get(quad.source.0, now) {
+                c.set_path_mtu(mtu, now);
+            }
+            // (one set up from a SYN cookie has already finished its handshake, and one with data
+            // from a fast open SYN can be read from straight away)
+            if c.is_connecting() && c.incoming.is_empty() {
+                listener.syn_queue.push_back(quad);
+            } else {
+                listener.pending.push_back(quad);
+            }
+            self.connections.insert(quad, c);
+        }
+
+        Ok(())
+    }
+
+    // a UDP datagram (carried in packet), for whichever socket is bound to its port
+    fn on_datagram<N: Nic>(
+        &mut self,
+        nic: &mut N,
+        ip_header: &IpHeader,
+        input: &[u8],
+        packet: &[u8],
+        now: Instant,
+    ) -> io::Result<()> {
+        let Some((udp_header, data)) = udp::parse(ip_header, input) else {
+            return Ok(());
+        };
+        match self.udp_sockets.get_mut(&udp_header.destination_port) {
+            Some(socket) if socket.accepts(ip_header.destination()) => {
+                let from = SocketAddr::new(ip_header.source(), udp_header.source_port);
+                socket.on_datagram(from, data);
+                Ok(())
+            }
+            _ => icmp::send_error(
+                nic,
+                &mut self.icmp_errors,
+                icmp::ErrorMessage::PortUnreachable,
+                packet,
+                now,
+            ),
+        }
+    }
+
+    // the IP header at the front of a packet, the protocol of what it carries, and what it carries
+    // (less anything past the end of the IP packet, or in IPv6's extension headers). an IPv4
+    // fragment only gets this far once it completes its datagram, and then carries all of it
+    fn parse_ip<'a>(
+        &mut self,
+        protocol: Option<network_parse::Protocol>,
+        input: &'a [u8],
+        now: Instant,
+    ) -> Option<(IpHeader, u8, Cow<'a, [u8]>)> {
+        match protocol {
+            Some(network_parse::Protocol::Ipv4) => {
+                let ip_header = match etherparse::Ipv4HeaderSlice::from_slice(input) {
+                    Ok(ip_header) => ip_header,
+                    Err(err) => {
+                        eprintln!("ignoring weird packet {err:?}");
+                        return None;
+                    }
+                };
+                match network_parse::parse_ipv4_options(ip_header.options()) {
+                    Ok((_, options))
+                        if self.source_routes == SourceRoutePolicy::Drop
+                            && options
+                                .iter()
+                                .any(network_parse::IPv4Option::is_source_route) =>
+                    {
+                        return None;
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        eprintln!("ignoring packet with weird options {err:?}");
+                        return None;
+                    }
+                }
+
+                let end = std::cmp::min(input.len(), ip_header.total_len() as usize);
+                let start = std::cmp::min(ip_header.slice().len(), end);
+                let payload = &input[start..end];
+
+                if !ip_header.more_fragments() && ip_header.fragments_offset() == 0 {
+                    return Some((
+                        IpHeader::from(&ip_header),
+                        ip_header.protocol(),
+                        Cow::Borrowed(payload),
+                    ));
+                }
+                let key = FragmentKey {
+                    source: ip_header.source_addr(),
+                    destination: ip_header.destination_addr(),
+                    protocol: ip_header.protocol(),
+                    identification: ip_header.identification(),
+                };
+                // (the offset is in units of 8 bytes)
+                let datagram = self.fragments.insert(
+                    key,
+                    ip_header.slice(),
+                    ip_header.fragments_offset() as usize * 8,
+                    ip_header.more_fragments(),
+                    payload,
+                    now,
+                )?;
+                Some((
+                    IpHeader::from(&ip_header),
+                    ip_header.protocol(),
+                    Cow::Owned(datagram),
+                ))
+            }
+            Some(network_parse::Protocol::Ipv6) => {
+                let (input, ip_header) = match network_parse::parse_ipv6(input) {
+                    Ok(parsed) if parsed.1.version == 6 => parsed,
+                    _ => {
+                        eprintln!("ignoring weird packet");
+                        return None;
+                    }
+                };
+                let input = &input[..std::cmp::min(input.len(), ip_header.payload_length as usize)];
+                let (input, protocol) =
+                    match network_parse::parse_ipv6_extension_headers(ip_header.next_header, input)
+                    {
+                        Ok(parsed) => parsed,
+                        Err(err) => {
+                            eprintln!("ignoring weird packet {err:?}");
+                            return None;
+                        }
+                    };
+                Some((IpHeader::from(&ip_header), protocol, Cow::Borrowed(input)))
+            }
+            // no other network protocols
+            _ => None,
+        }
+    }
+
+    // move connections that have finished their handshake on to be accepted, and drop the ones
+    // that never will
+    fn update_syn_queues(&mut self) {
+        for listener in self.listeners.values_mut() {
+            listener
+                .syn_queue
+                .retain(|quad| match self.connections.get(quad) {
+                    Some(c) if c.aborted().is_some() => {
+                        self.connections.remove(quad);
+                        false
+                    }
+                    Some(c) if c.is_connecting() => true,
+                    Some(_) => {
+                        listener.pending.push_back(*quad);
+                        false
+                    }
+                    None => false,
+                });
+            // nor is there any point keeping one for accept that's already over. (a fast open
+            // connection in here would otherwise take up a FAST_OPEN_QUEUE_SIZE slot for good)
+            listener
+                .pending
+                .retain(|quad| match self.connections.get(quad) {
+                    Some(c) if c.is_finished() => {
+                        self.connections.remove(quad);
+                        false
+                    }
+                    Some(_) => true,
+                    None => false,
+                });
+        }
+    }
+
+    // forget connections that are over -- closed, aborted or out of TIME-WAIT -- once the
+    // application has let go of them too. (a listener holds the ones it hasn't handed out yet)
+    fn remove_finished(&mut self) {
+        self.connections
+            .retain(|_, connection| !(connection.is_released() && connection.is_finished()));
+    }
+}
+
+pub struct TcpListener {
+    port: Port,
+    handle: InterfaceHandle,
+}
+
+impl TcpListener {
+    pub fn accept(&mut self) -> io::Result<TcpStream> {
+        let mut manager = self.handle.manager.lock().unwrap();
+        loop {
+            if let Some(quad) = manager
+                .listeners
+                .get_mut(&self.port)
+                .expect("port closed while listener still active")
+                .pending
+                .pop_front()
+            {
+                if manager
+                    .connections
+                    .get(&quad)
+                    .is_none_or(|connection| connection.aborted().is_some())
+                {
+                    // it went away before anyone got to it
+                    manager.connections.remove(&quad);
+                    continue;
+                }
+
+                return Ok(TcpStream {
+                    quad,
+                    handle: self.handle.clone(),
+                });
+            }
+
+            if manager.terminate {
+                return Err(stopped());
+            }
+            manager = self.handle.pending_var.wait(manager).unwrap();
+        }
+    }
+
+    // the congestion controller for connections accepted from now on
+    pub fn set_congestion_control(&mut self, algorithm: congestion::Algorithm) {
+        let mut manager = self.handle.manager.lock().unwrap();
+        manager
+            .listeners
+            .get_mut(&self.port)
+            .expect("port closed while listener still active")
+            .congestion = algorithm;
+    }
+
+    // accept data sent along with a SYN, from clients that have had a fast open cookie from us
+    // before (RFC 7413). such connections can be accepted, and read from, before their handshake
+    // is done. off to start with, as the data in a SYN may be a duplicate
+    pub fn set_fast_open(&mut self, enabled: bool) {
+        let mut manager = self.handle.manager.lock().unwrap();
+        manager
+            .listeners
+            .get_mut(&self.port)
+            .expect("port closed while listener still active")
+            .fast_open = enabled;
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let mut manager = self.handle.manager.lock().unwrap();
+        let listener = manager
+            .listeners
+            .remove(&self.port)
+            .expect("port closed while listener still active");
+
+        // nobody is going to accept these now, so the packet loop resets them and then forgets them
+        for quad in listener.syn_queue.into_iter().chain(listener.pending) {
+            if let Some(connection) = manager.connections.get_mut(&quad) {
+                connection.reset();
+            }
+        }
+    }
+}
+
+pub struct TcpStream {
+    quad: Quad,
+    handle: InterfaceHandle,
+}
+
+impl TcpStream {
+    pub fn set_congestion_control(&self, algorithm: congestion::Algorithm) -> io::Result<()> {
+        let mut manager = self.handle.manager.lock().unwrap();
+        manager
+            .connections
+            .get_mut(&self.quad)
+            .ok_or_else(terminated)?
+            .set_congestion_control(algorithm);
+        Ok(())
+    }
+
+    // turn Nagle's algorithm off, so small writes go out straight away rather than waiting for
+    // outstanding data to be acknowledged
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        let mut manager = self.handle.manager.lock().unwrap();
+        manager
+            .connections
+            .get_mut(&self.quad)
+            .ok_or_else(terminated)?
+            .set_nodelay(nodelay);
+        Ok(())
+    }
+
+    // send keepalives once the connection has been idle for a while, and abort it if the peer
+    // stops answering them. off to start with
+    pub fn set_keepalive(&self, keepalive: Option<Keepalive>) -> io::Result<()> {
+        let mut manager = self.handle.manager.lock().unwrap();
+        manager
+            .connections
+            .get_mut(&self.quad)
+            .ok_or_else(terminated)?
+            .set_keepalive(keepalive);
+        Ok(())
+    }
+
+    // abort the connection if data we've sent goes unacknowledged for this long (RFC 5482). with
+    // none, we give up after a fixed number of retransmissions instead
+    pub fn set_user_timeout(&self, user_timeout: Option<Duration>) -> io::Result<()> {
+        let mut manager = self.handle.manager.lock().unwrap();
+        manager
+            .connections
+            .get_mut(&self.quad)
+            .ok_or_else(terminated)?
+            .set_user_timeout(user_timeout);
+        Ok(())
+    }
+
+    // close the reading half of the connection, the writing half (which sends our FIN once
+    // everything written so far has gone), or both
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let mut manager = self.handle.manager.lock().unwrap();
+        let connection = manager
+            .connections
+            .get_mut(&self.quad)
+            .ok_or_else(terminated)?;
+        if let Shutdown::Read | Shutdown::Both = how {
+            connection.shutdown_read();
+        }
+        if let Shutdown::Write | Shutdown::Both = how {
+            connection.close();
+        }
+        drop(manager);
+
+        // anyone blocked on this stream needs to notice
+        self.handle.read_var.notify_all();
+        self.handle.write_var.notify_all();
+        Ok(())
+    }
+
+    // how long acks may be held back for (40ms to start with, and at most 500ms), in the hope of
+    // sending them along with some data. zero acks every segment as soon as it arrives
+    pub fn set_ack_delay(&self, delay: Duration) -> io::Result<()> {
+        let mut manager = self.handle.manager.lock().unwrap();
+        manager
+            .connections
+            .get_mut(&self.quad)
+            .ok_or_else(terminated)?
+            .set_ack_delay(delay);
+        Ok(())
+    }
+
+    // urgent data (RFC 6093): the peer is told where the urgent data ends straight away, though the
+    // data itself goes in order with everything else. otherwise, just like write
+    pub fn send_urgent(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send(buf, true)
+    }
+
+    // queue up as much of buf as there's room for, once there's any room at all
+    fn send(&mut self, buf: &[u8], urgent: bool) -> io::Result<usize> {
+        let mut manager = self.handle.manager.lock().unwrap();
+        loop {
+            let connection = manager
+                .connections
+                .get_mut(&self.quad)
+                .ok_or_else(terminated)?;
+
+            if let Some(reason) = connection.aborted() {
+                return Err(reason.into());
+            }
+
+            if connection.is_send_closed() {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "connection is closing",
+                ));
+            }
+
+            if connection.unacked.len() < SEND_QUEUE_SIZE {
+                // the packet loop picks this up and sends it on its next tick
+                let n = std::cmp::min(buf.len(), SEND_QUEUE_SIZE - connection.unacked.len());
+                connection.unacked.extend(&buf[..n]);
+                if urgent {
+                    connection.mark_urgent();
+                }
+                return Ok(n);
+            }
+
+            if manager.terminate {
+                return Err(stopped());
+            }
+            manager = self.handle.write_var.wait(manager).unwrap();
+        }
+    }
+
+    // how much more there is to read before getting past the urgent data the peer has sent, if
+    // any. reads stop at the end of it, so zero means the last read ended right there
+    pub fn urgent_mark(&self) -> io::Result<Option<usize>> {
+        let manager = self.handle.manager.lock().unwrap();
+        Ok(manager
+            .connections
+            .get(&self.quad)
+            .ok_or_else(terminated)?
+            .urgent_mark())
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let mut manager = self.handle.manager.lock().unwrap();
+        if let Some(connection) = manager.connections.get_mut(&self.quad) {
+            connection.close();
+            connection.release();
+        }
+    }
+}
+
+pub struct UdpSocket {
+    port: Port,
+    handle: InterfaceHandle,
+}
+
+impl UdpSocket {
+    pub fn local_addr(&self) -> SocketAddr {
+        let manager = self.handle.manager.lock().unwrap();
+        let socket = manager
+            .udp_sockets
+            .get(&self.port)
+            .expect("port closed while socket still active");
+        SocketAddr::new(socket.local, self.port)
+    }
+
+    // send buf to remote, in a datagram of its own. it goes out the next time the packet loop
+    // gets to it, so this only waits if there are a lot of datagrams still to go
+    pub fn send_to(&self, buf: &[u8], remote: SocketAddr) -> io::Result<usize> {
+        let mut manager = self.handle.manager.lock().unwrap();
+        loop {
+            let socket = manager
+                .udp_sockets
+                .get_mut(&self.port)
+                .expect("port closed while socket still active");
+            if socket.local.is_unspecified() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    "socket has no address to send from",
+                ));
+            }
+            if socket.local.is_ipv4() != remote.is_ipv4() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "local and remote addresses are for different versions of ip",
+                ));
+            }
+            if buf.len() > udp::max_payload(socket.local) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "datagram too big to send in one packet",
+                ));
+            }
+
+            if socket.outgoing.len() < udp::QUEUE_LEN {
+                socket.outgoing.push_back((remote, buf.to_vec()));
+                return Ok(buf.len());
+            }
+
+            if manager.terminate {
+                return Err(stopped());
+            }
+            manager = self.handle.write_var.wait(manager).unwrap();
+        }
+    }
+
+    // wait for a datagram to arrive. as much of it as fits in buf is copied there, and the rest of
+    // it is dropped
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut manager = self.handle.manager.lock().unwrap();
+        loop {
+            let socket = manager
+                .udp_sockets
+                .get_mut(&self.port)
+                .expect("port closed while socket still active");
+            if let Some((from, data)) = socket.incoming.pop_front() {
+                let n = std::cmp::min(buf.len(), data.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                return Ok((n, from));
+            }
+
+            if manager.terminate {
+                return Err(stopped());
+            }
+            manager = self.handle.read_var.wait(manager).unwrap();
+        }
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        let mut manager = self.handle.manager.lock().unwrap();
+        manager.udp_sockets.remove(&self.port);
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::other("the interface's packet loop has stopped")
+}
+
+fn terminated() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "stream was terminated unexpectedly",
+    )
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut manager = self.handle.manager.lock().unwrap();
+        loop {
+            let connection = manager
+                .connections
+                .get_mut(&self.quad)
+                .ok_or_else(terminated)?;
+
+            if !connection.incoming.is_empty() {
+                // if this opens up the receive window far enough, the packet loop lets the peer
+                // know on its next tick
+                return Ok(connection.read(buf));
+            }
+
+            if let Some(reason) = connection.aborted() {
+                return Err(reason.into());
+            }
+
+            if connection.is_recv_closed() {
+                // no more data is coming
+                return Ok(0);
+            }
+
+            if manager.terminate {
+                return Err(stopped());
+            }
+            manager = self.handle.read_var.wait(manager).unwrap();
+        }
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send(buf, false)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut manager = self.handle.manager.lock().unwrap();
+        loop {
+            let connection = manager
+                .connections
+                .get_mut(&self.quad)
+                .ok_or_else(terminated)?;
+
+            if let Some(reason) = connection.aborted() {
+                return Err(reason.into());
+            }
+
+            if connection.unacked.is_empty() {
+                return Ok(());
+            }
+
+            if manager.terminate {
+                return Err(stopped());
+            }
+            manager = self.handle.write_var.wait(manager).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a segment from 192.168.0.2:port to us on 192.168.0.1:9000, as the tun device hands it over
+    fn segment(port: Port, seq: u32, ack: Option<u32>, syn: bool) -> Vec<u8> {
+        let mut tcp = etherparse::TcpHeader::new(port, 9000, seq, 65535);
+        tcp.syn = syn;
+        if let Some(ack) = ack {
+            tcp.ack = true;
+            tcp.acknowledgment_number = ack;
+        }
+        let ip = etherparse::Ipv4Header::new(
+            tcp.header_len(),
+            64,
+            etherparse::ip_number::TCP,
+            [192, 168, 0, 2],
+            [192, 168, 0, 1],
+        );
+
+        let mut packet = vec![0, 0, 0x08, 0x00];
+        ip.write(&mut packet).unwrap();
+        tcp.write(&mut packet).unwrap();
+        packet
+    }
+
+    // the same, but from [fd00::2]:port to [fd00::1]:9000
+    fn segment_v6(port: Port, seq: u32, ack: Option<u32>, syn: bool) -> Vec<u8> {
+        let mut tcp = etherparse::TcpHeader::new(port, 9000, seq, 65535);
+        tcp.syn = syn;
+        if let Some(ack) = ack {
+            tcp.ack = true;
+            tcp.acknowledgment_number = ack;
+        }
+        let ip = etherparse::Ipv6Header {
+            traffic_class: 0,
+            flow_label: 0,
+            payload_length: tcp.header_len(),
+            next_header: etherparse::ip_number::TCP,
+            hop_limit: 64,
+            source: [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2],
+            destination: [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+        };
+
+        let mut packet = vec![0, 0, 0x86, 0xdd];
+        ip.write(&mut packet).unwrap();
+        tcp.write(&mut packet).unwrap();
+        packet
+    }
+
+    fn listening() -> ConnectionManager {
+        let mut manager = ConnectionManager::default();
+        manager.listeners.insert(9000, Listener::default());
+        manager
+    }
+
+    // the sequence number of the SYN-ACK the stack has just sent
+    fn syn_ack_iss(nic: &[Vec<u8>]) -> u32 {
+        let packet = nic.last().unwrap();
+        let ip_header_len = match packet[0] >> 4 {
+            6 => 40,
+            _ => (packet[0] & 0xf) as usize * 4,
+        };
+        let tcp_header = etherparse::TcpHeaderSlice::from_slice(&packet[ip_header_len..]).unwrap();
+        assert!(tcp_header.syn() && tcp_header.ack());
+        tcp_header.sequence_number()
+    }
+
+    #[test]
+    fn handshakes_wait_in_the_syn_queue() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut manager = listening();
+
+        manager
+            .on_packet(&mut nic, &segment(4000, 1000, None, true), now)
+            .unwrap();
+        assert_eq!(manager.listeners[&9000].syn_queue.len(), 1);
+        assert!(manager.listeners[&9000].pending.is_empty());
+
+        // only the final ack makes it ready to be accepted
+        let iss = syn_ack_iss(&nic);
+        manager
+            .on_packet(
+                &mut nic,
+                &segment(4000, 1001, Some(iss.wrapping_add(1)), false),
+                now,
+            )
+            .unwrap();
+        assert!(manager.listeners[&9000].syn_queue.is_empty());
+        assert_eq!(manager.listeners[&9000].pending.len(), 1);
+    }
+
+    #[test]
+    fn listeners_take_ipv4_and_ipv6_connections() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut manager = listening();
+
+        let segments: [(fn(_, _, _, _) -> _, _); 2] = [(segment, 4), (segment_v6, 6)];
+        for (segment, version) in segments {
+            let iss = 1000;
+            manager
+                .on_packet(&mut nic, &segment(4000, iss, None, true), now)
+                .unwrap();
+            // the SYN-ACK goes back over the same version of IP
+            assert_eq!(nic.last().unwrap()[0] >> 4, version);
+            let ack = syn_ack_iss(&nic).wrapping_add(1);
+            manager
+                .on_packet(&mut nic, &segment(4000, iss + 1, Some(ack), false), now)
+                .unwrap();
+        }
+
+        let pending = &manager.listeners[&9000].pending;
+        assert_eq!(pending.len(), 2);
+        assert!(pending[0].source.0.is_ipv4());
+        assert!(pending[1].source.0.is_ipv6());
+    }
+
+    #[test]
+    fn fragmented_segments_are_reassembled() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut manager = listening();
+
+        // split the SYN after the first 8 bytes of its TCP header
+        let syn = segment(4000, 1000, None, true);
+        let (tun, ip_header, tcp) = (&syn[..4], &syn[4..24], &syn[24..]);
+        let fragment = |offset: usize, more: bool, data: &[u8]| {
+            let mut ip = etherparse::Ipv4HeaderSlice::from_slice(ip_header)
+                .unwrap()
+                .to_header();
+            ip.identification = 1234;
+            ip.more_fragments = more;
+            ip.fragments_offset = (offset / 8) as u16;
+            ip.set_payload_len(data.len()).unwrap();
+            ip.header_checksum = ip.calc_header_checksum().unwrap();
+            let mut packet = tun.to_vec();
+            ip.write(&mut packet).unwrap();
+            packet.extend_from_slice(data);
+            packet
+        };
+
+        manager
+            .on_packet(&mut nic, &fragment(8, false, &tcp[8..]), now)
+            .unwrap();
+        assert!(nic.is_empty());
+        manager
+            .on_packet(&mut nic, &fragment(0, true, &tcp[..8]), now)
+            .unwrap();
+        assert_eq!(manager.listeners[&9000].syn_queue.len(), 1);
+        syn_ack_iss(&nic);
+    }
+
+    #[test]
+    fn source_routed_packets_follow_the_policy() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut manager = listening();
+
+        // a loose source route through 10.0.0.1, which has been followed
+        let syn = segment(4000, 1000, None, true);
+        let mut ip = etherparse::Ipv4HeaderSlice::from_slice(&syn[4..])
+            .unwrap()
+            .to_header();
+        ip.set_options(&[131, 7, 8, 10, 0, 0, 1, 0]).unwrap();
+        ip.set_payload_len(syn.len() - 24).unwrap();
+        ip.header_checksum = ip.calc_header_checksum().unwrap();
+        let mut routed = syn[..4].to_vec();
+        ip.write(&mut routed).unwrap();
+        routed.extend_from_slice(&syn[24..]);
+
+        manager.on_packet(&mut nic, &routed, now).unwrap();
+        assert!(nic.is_empty());
+        assert!(manager.connections.is_empty());
+
+        manager.source_routes = SourceRoutePolicy::Ignore;
+        manager.on_packet(&mut nic, &routed, now).unwrap();
+        syn_ack_iss(&nic);
+        assert_eq!(manager.listeners[&9000].syn_queue.len(), 1);
+    }
+
+    #[test]
+    fn pings_are_answered() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut manager = ConnectionManager::default();
+
+        // a ping from 192.168.0.1 to us on 192.168.0.2, as the tun device hands it over
+        let data: Vec<u8> = (0..56).collect();
+        let icmp = etherparse::Icmpv4Header::with_checksum(
+            etherparse::Icmpv4Type::EchoRequest(etherparse::IcmpEchoHeader { id: 24, seq: 1 }),
+            &data,
+        );
+        let ip = etherparse::Ipv4Header::new(
+            (icmp.header_len() + data.len()) as u16,
+            64,
+            etherparse::ip_number::ICMP,
+            [192, 168, 0, 1],
+            [192, 168, 0, 2],
+        );
+        let mut ping = vec![0, 0, 0x08, 0x00];
+        ip.write(&mut ping).unwrap();
+        icmp.write(&mut ping).unwrap();
+        ping.extend(&data);
+        manager.on_packet(&mut nic, &ping, now).unwrap();
+
+        // the reply: the same, but from us, and of type echo reply
+        let reply = &nic[0];
+        assert_eq!(reply.len(), 84);
+        assert_eq!(&reply[12..16], &[192, 168, 0, 2]);
+        assert_eq!(&reply[16..20], &[192, 168, 0, 1]);
+        assert_eq!(reply[20], 0);
+        assert_eq!(&reply[24..], &ping[28..]);
+    }
+
+    #[test]
+    fn unreachable_ports_refuse_connections() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut manager = ConnectionManager::default();
+        let local = (IpAddr::from([192, 168, 0, 1]), 5000);
+        let remote = (IpAddr::from([192, 168, 0, 2]), 80);
+        let mut connection =
+            TcpState::connect(local, remote, 0, congestion::Algorithm::default(), now);
+        connection.on_tick(&mut nic, now).unwrap();
+        let quad = Quad {
+            source: remote,
+            destination: local,
+        };
+        manager.connections.insert(quad, connection);
+
+        // the reply to our SYN quotes its IP header, and the start of its TCP header
+        let quoted = &nic.pop().unwrap()[..28];
+        let icmp = etherparse::Icmpv4Header::with_checksum(
+            etherparse::Icmpv4Type::DestinationUnreachable(
+                etherparse::icmpv4::DestUnreachableHeader::Port,
+            ),
+            quoted,
+        );
+        let ip = etherparse::Ipv4Header::new(
+            (icmp.header_len() + quoted.len()) as u16,
+            64,
+            etherparse::ip_number::ICMP,
+            [192, 168, 0, 2],
+            [192, 168, 0, 1],
+        );
+        let mut unreachable = vec![0, 0, 0x08, 0x00];
+        ip.write(&mut unreachable).unwrap();
+        icmp.write(&mut unreachable).unwrap();
+        unreachable.extend(quoted);
+        manager.on_packet(&mut nic, &unreachable, now).unwrap();
+
+        assert_eq!(
+            manager.connections[&quad].aborted(),
+            Some(tcp::AbortReason::Refused)
+        );
+    }
+
+    #[test]
+    fn path_mtus_are_shared_between_connections() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut manager = listening();
+        let local = (IpAddr::from([192, 168, 0, 1]), 5000);
+        let remote = (IpAddr::from([192, 168, 0, 2]), 80);
+        let mut connection =
+            TcpState::connect(local, remote, 0, congestion::Algorithm::default(), now);
+        connection.on_tick(&mut nic, now).unwrap();
+        manager.connections.insert(
+            Quad {
+                source: remote,
+                destination: local,
+            },
+            connection,
+        );
+
+        // a router says our SYN was too big to go any further
+        let quoted = &nic.pop().unwrap()[..28];
+        let icmp = etherparse::Icmpv4Header::with_checksum(
+            etherparse::Icmpv4Type::DestinationUnreachable(
+                etherparse::icmpv4::DestUnreachableHeader::FragmentationNeeded {
+                    next_hop_mtu: 1400,
+                },
+            ),
+            quoted,
+        );
+        let ip = etherparse::Ipv4Header::new(
+            (icmp.header_len() + quoted.len()) as u16,
+            64,
+            etherparse::ip_number::ICMP,
+            [10, 0, 0, 1],
+            [192, 168, 0, 1],
+        );
+        let mut too_big = vec![0, 0, 0x08, 0x00];
+        ip.write(&mut too_big).unwrap();
+        icmp.write(&mut too_big).unwrap();
+        too_big.extend(quoted);
+        manager.on_packet(&mut nic, &too_big, now).unwrap();
+        assert_eq!(manager.path_mtus.get(remote.0, now), Some(1400));
+
+        // so another connection with the same peer starts out knowing that
+        manager
+            .on_packet(&mut nic, &segment(4000, 1000, None, true), now)
+            .unwrap();
+        let quad = manager.listeners[&9000].syn_queue[0];
+        assert_eq!(manager.connections[&quad].path_mtu(), 1400);
+    }
+
+    #[test]
+    fn unknown_protocols_are_unreachable() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut manager = ConnectionManager::default();
+
+        let ip = etherparse::Ipv4Header::new(4, 64, 253, [192, 168, 0, 2], [192, 168, 0, 1]);
+        let mut packet = vec![0, 0, 0x08, 0x00];
+        ip.write(&mut packet).unwrap();
+        packet.extend(b"test");
+        manager.on_packet(&mut nic, &packet, now).unwrap();
+
+        // a protocol unreachable, quoting all of it
+        let reply = &nic[0];
+        assert_eq!(&reply[16..20], &[192, 168, 0, 2]);
+        assert_eq!(reply[9], icmp::PROTOCOL);
+        assert_eq!(&reply[20..22], &[3, 2]);
+        assert_eq!(&reply[28..], &packet[4..]);
+    }
+
+    #[test]
+    fn datagrams_go_to_the_socket_bound_to_their_port() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut manager = ConnectionManager::default();
+        manager
+            .udp_sockets
+            .insert(53, udp::Socket::new(IpAddr::from([192, 168, 0, 1])));
+
+        let from = SocketAddr::from(([192, 168, 0, 2], 4000));
+        let mut datagram = Vec::new();
+        udp::send(
+            &mut datagram,
+            from,
+            SocketAddr::from(([192, 168, 0, 1], 53)),
+            b"hello",
+        )
+        .unwrap();
+        let mut packet = vec![0, 0, 0x08, 0x00];
+        packet.extend(&datagram[0]);
+        manager.on_packet(&mut nic, &packet, now).unwrap();
+        assert!(nic.is_empty());
+        assert_eq!(
+            manager.udp_sockets[&53].incoming.front(),
+            Some(&(from, b"hello".to_vec()))
+        );
+
+        // a datagram for any other port gets a port unreachable back, quoting it
+        let mut datagram = Vec::new();
+        udp::send(
+            &mut datagram,
+            from,
+            SocketAddr::from(([192, 168, 0, 1], 54)),
+            b"hello",
+        )
+        .unwrap();
+        let mut packet = vec![0, 0, 0x08, 0x00];
+        packet.extend(&datagram[0]);
+        manager.on_packet(&mut nic, &packet, now).unwrap();
+        let reply = &nic[0];
+        assert_eq!(reply[9], icmp::PROTOCOL);
+        assert_eq!(&reply[20..22], &[3, 3]);
+        assert_eq!(&reply[28..], &packet[4..]);
+    }
+
+    #[test]
+    fn queued_datagrams_are_sent_from_the_sockets_port() {
+        let mut nic = Vec::new();
+        let mut socket = udp::Socket::new(IpAddr::from([192, 168, 0, 1]));
+        let to = SocketAddr::from(([192, 168, 0, 2], 53));
+        socket.outgoing.push_back((to, b"hello".to_vec()));
+        assert!(socket.flush(&mut nic, 4000).unwrap());
+        assert!(!socket.flush(&mut nic, 4000).unwrap());
+
+        let packet = &nic[0];
+        assert_eq!(&packet[16..20], &[192, 168, 0, 2]);
+        assert_eq!(&packet[20..22], &4000u16.to_be_bytes());
+        assert_eq!(&packet[22..24], &53u16.to_be_bytes());
+        assert_eq!(&packet[28..], b"hello");
+    }
+
+    #[test]
+    fn waiters_find_out_when_the_packet_loop_stops() {
+        let handle: InterfaceHandle = Arc::default();
+        handle
+            .manager
+            .lock()
+            .unwrap()
+            .listeners
+            .insert(9000, Listener::default());
+        let mut listener = TcpListener {
+            port: 9000,
+            handle: handle.clone(),
+        };
+        let accept = thread::spawn(move || listener.accept().map(|_| ()));
+
+        thread::sleep(Duration::from_millis(10));
+        stop(&handle);
+        assert!(accept.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn abandoned_handshakes_are_dropped() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut manager = listening();
+
+        manager
+            .on_packet(&mut nic, &segment(4000, 1000, None, true), now)
+            .unwrap();
+        // the SYN-ACK goes unanswered, however many times it's sent
+        for minute in 1..=10 {
+            for connection in manager.connections.values_mut() {
+                connection
+                    .on_tick(&mut nic, now + Duration::from_secs(60 * minute))
+                    .unwrap();
+            }
+            manager.update_syn_queues();
+        }
+        assert!(manager.listeners[&9000].syn_queue.is_empty());
+        assert!(manager.listeners[&9000].pending.is_empty());
+        assert!(manager.connections.is_empty());
+    }
+
+    #[test]
+    fn finished_connections_are_forgotten_once_let_go_of() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let handle: InterfaceHandle = Arc::default();
+        let mut manager = handle.manager.lock().unwrap();
+        manager.listeners.insert(9000, Listener::default());
+        for port in [4000, 4001] {
+            manager
+                .on_packet(&mut nic, &segment(port, 1000, None, true), now)
+                .unwrap();
+            let iss = syn_ack_iss(&nic);
+            manager
+                .on_packet(
+                    &mut nic,
+                    &segment(port, 1001, Some(iss.wrapping_add(1)), false),
+                    now,
+                )
+                .unwrap();
+        }
+        manager.update_syn_queues();
+        let mut streams: Vec<TcpStream> = manager
+            .listeners
+            .get_mut(&9000)
+            .unwrap()
+            .pending
+            .drain(..)
+            .map(|quad| TcpStream {
+                quad,
+                handle: handle.clone(),
+            })
+            .collect();
+        drop(manager);
+
+        // both peers reset their connections, but only one of them has been let go of
+        let dropped = streams.pop().unwrap().quad;
+        let mut manager = handle.manager.lock().unwrap();
+        for port in [4000, 4001] {
+            let mut tcp = etherparse::TcpHeader::new(port, 9000, 1001, 65535);
+            tcp.rst = true;
+            let ip = etherparse::Ipv4Header::new(
+                tcp.header_len(),
+                64,
+                etherparse::ip_number::TCP,
+                [192, 168, 0, 2],
+                [192, 168, 0, 1],
+            );
+            let mut packet = vec![0, 0, 0x08, 0x00];
+            ip.write(&mut packet).unwrap();
+            tcp.write(&mut packet).unwrap();
+            manager.on_packet(&mut nic, &packet, now).unwrap();
+        }
+        manager.remove_finished();
+        assert!(!manager.connections.contains_key(&dropped));
+        assert!(manager.connections.contains_key(&streams[0].quad));
+
+        drop(manager);
+        drop(streams);
+        let mut manager = handle.manager.lock().unwrap();
+        manager.remove_finished();
+        assert!(manager.connections.is_empty());
+    }
+
+    #[test]
+    fn dropped_listeners_reset_what_they_never_accepted() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let handle: InterfaceHandle = Arc::default();
+        let listener = TcpListener {
+            port: 9000,
+            handle: handle.clone(),
+        };
+        let mut manager = handle.manager.lock().unwrap();
+        manager.listeners.insert(9000, Listener::default());
+        manager
+            .on_packet(&mut nic, &segment(4000, 1000, None, true), now)
+            .unwrap();
+        let iss = syn_ack_iss(&nic);
+        manager
+            .on_packet(
+                &mut nic,
+                &segment(4000, 1001, Some(iss.wrapping_add(1)), false),
+                now,
+            )
+            .unwrap();
+        assert_eq!(manager.listeners[&9000].pending.len(), 1);
+        drop(manager);
+
+        drop(listener);
+        let mut manager = handle.manager.lock().unwrap();
+        nic.clear();
+        for connection in manager.connections.values_mut() {
+            connection.on_tick(&mut nic, now).unwrap();
+        }
+        let reset = etherparse::TcpHeaderSlice::from_slice(&nic[0][20..]).unwrap();
+        assert!(reset.rst());
+        assert_eq!(reset.sequence_number(), iss.wrapping_add(1));
+
+        manager.remove_finished();
+        assert!(manager.connections.is_empty());
+    }
+
+    #[test]
+    fn fast_open_requests_are_capped() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut manager = listening();
+        manager.listeners.get_mut(&9000).unwrap().fast_open = true;
+        let cookie = manager.fast_open.cookie(IpAddr::from([192, 168, 0, 2]));
+
+        // a SYN with our cookie in it, and some data
+        let syn = |port: Port| {
+            let mut tcp = etherparse::TcpHeader::new(port, 9000, 1000, 65535);
+            tcp.syn = true;
+            let mut options = vec![1, 1, 34, 2 + cookie.len() as u8];
+            options.extend(cookie);
+            tcp.set_options_raw(&options).unwrap();
+            let data = b"hello";
+            let ip = etherparse::Ipv4Header::new(
+                tcp.header_len() + data.len() as u16,
+                64,
+                etherparse::ip_number::TCP,
+                [192, 168, 0, 2],
+                [192, 168, 0, 1],
+            );
+            let mut packet = vec![0, 0, 0x08, 0x00];
+            ip.write(&mut packet).unwrap();
+            tcp.write(&mut packet).unwrap();
+            packet.extend(data);
+            packet
+        };
+
+        for port in 10000..10000 + FAST_OPEN_QUEUE_SIZE as Port {
+            manager.on_packet(&mut nic, &syn(port), now).unwrap();
+        }
+        assert_eq!(manager.listeners[&9000].pending.len(), FAST_OPEN_QUEUE_SIZE);
+        assert!(manager.listeners[&9000].syn_queue.is_empty());
+
+        // past that, the data is dropped, and the handshake goes as it would without a cookie
+        manager.on_packet(&mut nic, &syn(4000), now).unwrap();
+        assert_eq!(manager.listeners[&9000].pending.len(), FAST_OPEN_QUEUE_SIZE);
+        let quad = manager.listeners[&9000].syn_queue[0];
+        assert!(manager.connections[&quad].incoming.is_empty());
+        let syn_ack = etherparse::TcpHeaderSlice::from_slice(&nic.last().unwrap()[20..]).unwrap();
+        assert_eq!(syn_ack.acknowledgment_number(), 1001);
+
+        // once one of them is reset, its slot is free again
+        let mut tcp = etherparse::TcpHeader::new(10000, 9000, 1006, 65535);
+        tcp.rst = true;
+        let ip = etherparse::Ipv4Header::new(
+            tcp.header_len(),
+            64,
+            etherparse::ip_number::TCP,
+            [192, 168, 0, 2],
+            [192, 168, 0, 1],
+        );
+        let mut reset = vec![0, 0, 0x08, 0x00];
+        ip.write(&mut reset).unwrap();
+        tcp.write(&mut reset).unwrap();
+        manager.on_packet(&mut nic, &reset, now).unwrap();
+        manager.update_syn_queues();
+        assert_eq!(
+            manager.listeners[&9000].pending.len(),
+            FAST_OPEN_QUEUE_SIZE - 1
+        );
+
+        manager.on_packet(&mut nic, &syn(4001), now).unwrap();
+        assert_eq!(manager.listeners[&9000].pending.len(), FAST_OPEN_QUEUE_SIZE);
+        let quad = *manager.listeners[&9000].pending.back().unwrap();
+        assert_eq!(manager.connections[&quad].incoming, b"hello");
+    }
+
+    #[test]
+    fn full_syn_queues_fall_back_to_syn_cookies() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut manager = listening();
+
+        for port in 10000..10000 + SYN_COOKIE_THRESHOLD as Port {
+            manager
+                .on_packet(&mut nic, &segment(port, 1000, None, true), now)
+                .unwrap();
+        }
+        assert_eq!(manager.connections.len(), SYN_COOKIE_THRESHOLD);
+
+        // this one still gets an answer, but nothing is kept for it
+        manager
+            .on_packet(&mut nic, &segment(4000, 1000, None, true), now)
+            .unwrap();
+        assert_eq!(manager.connections.len(), SYN_COOKIE_THRESHOLD);
+
+        // until it comes back with the cookie, already past the handshake
+        let iss = syn_ack_iss(&nic);
+        manager
+            .on_packet(
+                &mut nic,
+                &segment(4000, 1001, Some(iss.wrapping_add(1)), false),
+                now,
+            )
+            .unwrap();
+        assert_eq!(manager.connections.len(), SYN_COOKIE_THRESHOLD + 1);
+        assert_eq!(manager.listeners[&9000].pending.len(), 1);
+    }
+
+    #[test]
+    fn syns_a_cookie_cant_answer_in_full_get_the_rest_of_the_queue() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut manager = listening();
+
+        // a SYN asking for ECN, which a cookie has no room for
+        let ecn_syn = |port: Port| {
+            let mut tcp = etherparse::TcpHeader::new(port, 9000, 1000, 65535);
+            tcp.syn = true;
+            tcp.ece = true;
+            tcp.cwr = true;
+            let ip = etherparse::Ipv4Header::new(
+                tcp.header_len(),
+                64,
+                etherparse::ip_number::TCP,
+                [192, 168, 0, 2],
+                [192, 168, 0, 1],
+            );
+            let mut packet = vec![0, 0, 0x08, 0x00];
+            ip.write(&mut packet).unwrap();
+            tcp.write(&mut packet).unwrap();
+            packet
+        };
+
+        for port in 10000..10000 + SYN_COOKIE_THRESHOLD as Port {
+            manager
+                .on_packet(&mut nic, &segment(port, 1000, None, true), now)
+                .unwrap();
+        }
+        for port in 20000..20000 + (SYN_QUEUE_SIZE - SYN_COOKIE_THRESHOLD) as Port {
+            manager.on_packet(&mut nic, &ecn_syn(port), now).unwrap();
+        }
+        assert_eq!(manager.listeners[&9000].syn_queue.len(), SYN_QUEUE_SIZE);
+
+        // but once the queue is full, it's a cookie or nothing
+        manager.on_packet(&mut nic, &ecn_syn(4000), now).unwrap();
+        assert_eq!(manager.connections.len(), SYN_QUEUE_SIZE);
+        let packet = nic.last().unwrap();
+        let syn_ack = etherparse::TcpHeaderSlice::from_slice(&packet[20..]).unwrap();
+        assert!(syn_ack.syn() && !syn_ack.ece());
+    }
+}
@@ -1,10 +1,12 @@
 use nom::{
     bits::{bits, streaming::take},
-    error::Error,
+    bytes::complete::take as take_bytes,
+    error::{Error, ErrorKind},
+    number::complete::{be_u16, be_u8},
     sequence::tuple,
     IResult,
 };
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 #[derive(Debug, Clone, Copy)]
 pub struct TunTapHeader {
@@ -23,28 +25,43 @@ pub enum Flags {
 #[derive(Debug, Clone, Copy)]
 pub enum Protocol {
     Ipv4,
+    Ipv6,
+    Arp,
     Other,
 }
 
-impl TunTapHeader {
-    pub fn from_slice(slice: &[u8]) -> Result<TunTapHeader, Box<dyn std::error::Error>> {
-        // CHECK
-        // Is the slice big enough to hold the header?
-        assert!(slice.len() >= 2);
+// the EtherTypes we know about, which say what an Ethernet frame (or the packet information header
+// in front of a packet from the tun device) carries
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const ETHERTYPE_VLAN: u16 = 0x8100;
+pub const ETHERTYPE_IPV6: u16 = 0x86dd;
 
-        // Flags
-        let (input, flags) = parse_flags(slice).unwrap();
+impl From<u16> for Protocol {
+    fn from(ethertype: u16) -> Self {
+        match ethertype {
+            ETHERTYPE_IPV4 => Protocol::Ipv4,
+            ETHERTYPE_IPV6 => Protocol::Ipv6,
+            ETHERTYPE_ARP => Protocol::Arp,
+            _ => Protocol::Other,
+        }
+    }
+}
 
-        // Protocol
-        // If the 'No Packet Information' flag is set, skip this !
+impl TunTapHeader {
+    // (whatever the device hands us, a bad header is an error rather than a panic -- one odd frame
+    // mustn't take the packet loop down with it)
+    pub fn from_slice(slice: &[u8]) -> Result<TunTapHeader, nom::Err<Error<&[u8]>>> {
+        let (input, flags) = parse_flags(slice)?;
+
+        // with no packet information, there's no protocol either
         let protocol = if let Flags::IffNoPi = flags {
             None
         } else {
-            let (_, protocol) = parse_protocol(input).unwrap();
+            let (_, protocol) = parse_protocol(input)?;
             Some(protocol)
         };
 
-        // Seems fine!
         Ok(TunTapHeader { flags, protocol })
     }
 
@@ -65,7 +82,7 @@ impl TunTapHeader {
     }
 }
 fn parse_flags(input: &[u8]) -> IResult<&[u8], Flags> {
-    let (input, flags) = take_16b(input).unwrap();
+    let (rest, flags) = take_16b(input)?;
 
     // IFF_NO_PI = 0x1000
     // IFF_TUN = 0x0001
@@ -75,107 +92,399 @@ fn parse_flags(input: &[u8]) -> IResult<&[u8], Flags> {
         0x1000 => Flags::IffNoPi,
         0x0001 => Flags::IffTun,
         0x0002 => Flags::IffTap,
-        _ => {
-            panic!("Something went wrong with the tun/tap header flags");
-        }
+        _ => return Err(nom::Err::Error(Error::new(input, ErrorKind::Verify))),
     };
 
-    Ok((input, flags))
+    Ok((rest, flags))
 }
 
 fn parse_protocol(input: &[u8]) -> IResult<&[u8], Protocol> {
-    let (input, protocol) = take_16b(input).unwrap();
+    let (input, protocol) = take_16b(input)?;
+    Ok((input, Protocol::from(protocol)))
+}
 
-    // IPv4 = 0x0800
-    let protocol = match protocol {
-        0x0800 => Protocol::Ipv4,
-        _ => Protocol::Other,
-    };
+// Ethernet II (and IEEE 802.1Q, for frames tagged with a VLAN)
+pub const ETHERNET_HEADER_LEN: usize = 14;
+pub const VLAN_TAG_LEN: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MacAddress(pub [u8; 6]);
 
-    Ok((input, protocol))
+impl MacAddress {
+    pub const BROADCAST: MacAddress = MacAddress([0xff; 6]);
+
+    // (which includes broadcast)
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 1 != 0
+    }
 }
 
-#[derive(Debug)]
-pub struct IPv4Header {
-    version: u8,
-    ihl: u8,
-    type_of_service: u8,
-    total_length: u16,
-    identification: u16,
-    flags: u8,
-    fragment_offset: u16,
-    time_to_live: u8,
-    protocol: u8,
-    header_checksum: u16,
-    source_address: Ipv4Addr,
-    destination_address: Ipv4Addr,
-    //options: Option<u32>,
-    //padding: Option<u32>,
+impl std::fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
 }
 
-impl IPv4Header {
-    pub fn from_slice(slice: &[u8]) -> Result<IPv4Header, Box<dyn std::error::Error>> {
-        // CHECK
-        // Is the slice big enough to hold the minimum-sized header?
-        assert!(slice.len() >= 30);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VlanTag {
+    // the priority code point (IEEE 802.1p), and whether the frame can be dropped first when
+    // there's congestion
+    pub priority: u8,
+    pub drop_eligible: bool,
+    pub id: u16,
+}
 
-        // Version & IHL
-        let (input, (version, ihl)) = parse_version_ihl(slice).unwrap();
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EthernetHeader {
+    pub destination: MacAddress,
+    pub source: MacAddress,
+    pub vlan: Option<VlanTag>,
+    // of whatever comes after the header (and the tag, if there is one)
+    pub ethertype: u16,
+}
 
-        // CHECK
-        // Is the version 4?
-        assert!(version == 4);
+impl EthernetHeader {
+    pub fn header_len(&self) -> usize {
+        match self.vlan {
+            Some(_) => ETHERNET_HEADER_LEN + VLAN_TAG_LEN,
+            None => ETHERNET_HEADER_LEN,
+        }
+    }
 
-        // CHECK
-        // Is the IHL too small?
-        assert!(ihl >= 5);
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.header_len());
+        bytes.extend(self.destination.0);
+        bytes.extend(self.source.0);
+        if let Some(vlan) = self.vlan {
+            let tci = (vlan.priority as u16) << 13 | (vlan.drop_eligible as u16) << 12 | vlan.id;
+            bytes.extend(ETHERTYPE_VLAN.to_be_bytes());
+            bytes.extend(tci.to_be_bytes());
+        }
+        bytes.extend(self.ethertype.to_be_bytes());
+        bytes
+    }
+}
 
-        // CHECK
-        // Is the slice big enough to fit all of the data the IHL indicates?
-        assert!(slice.len() >= (ihl as usize * 4));
+fn parse_mac_address(input: &[u8]) -> IResult<&[u8], MacAddress> {
+    let (input, bytes) = take_bytes(6usize)(input)?;
+    Ok((input, MacAddress(bytes.try_into().expect("took 6 bytes"))))
+}
 
-        // Type of Service
-        let (input, type_of_service) = parse_type_of_service(input).unwrap();
+// a frame with more than one VLAN tag comes out with the ethertype of the second one, which nothing
+// will know what to do with
+pub fn parse_ethernet(input: &[u8]) -> IResult<&[u8], EthernetHeader> {
+    let (input, (destination, source, ethertype)) =
+        tuple((parse_mac_address, parse_mac_address, be_u16))(input)?;
+    let (input, vlan, ethertype) = if ethertype == ETHERTYPE_VLAN {
+        let (input, (tci, ethertype)) = tuple((be_u16, be_u16))(input)?;
+        let vlan = VlanTag {
+            priority: (tci >> 13) as u8,
+            drop_eligible: tci & 0x1000 != 0,
+            id: tci & 0x0fff,
+        };
+        (input, Some(vlan), ethertype)
+    } else {
+        (input, None, ethertype)
+    };
+    Ok((
+        input,
+        EthernetHeader {
+            destination,
+            source,
+            vlan,
+            ethertype,
+        },
+    ))
+}
 
-        // Total Length
-        let (input, total_length) = parse_total_length(input).unwrap();
+// ARP (RFC 826), for IPv4 over Ethernet: the only kind there is any more
+pub const ARP_REQUEST: u16 = 1;
+pub const ARP_REPLY: u16 = 2;
+const ARP_HARDWARE_ETHERNET: u16 = 1;
 
-        // CHECK
-        // Is the total length indicated big enough to fit all of the data the IHL indicates?
-        assert!(total_length >= (ihl as u16 * 4));
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpPacket {
+    pub operation: u16,
+    pub sender_mac: MacAddress,
+    pub sender_ip: Ipv4Addr,
+    // (which a request leaves as zeros, as finding it out is the point)
+    pub target_mac: MacAddress,
+    pub target_ip: Ipv4Addr,
+}
 
-        // Identification
+impl ArpPacket {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(28);
+        bytes.extend(ARP_HARDWARE_ETHERNET.to_be_bytes());
+        bytes.extend(ETHERTYPE_IPV4.to_be_bytes());
+        bytes.extend([6, 4]);
+        bytes.extend(self.operation.to_be_bytes());
+        bytes.extend(self.sender_mac.0);
+        bytes.extend(self.sender_ip.octets());
+        bytes.extend(self.target_mac.0);
+        bytes.extend(self.target_ip.octets());
+        bytes
+    }
+}
 
-        // Flags
+fn parse_ipv4_address(input: &[u8]) -> IResult<&[u8], Ipv4Addr> {
+    let (input, bytes) = take_bytes(4usize)(input)?;
+    Ok((input, Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])))
+}
 
-        // Fragment Offset
+pub fn parse_arp(input: &[u8]) -> IResult<&[u8], ArpPacket> {
+    let (input, (hardware, protocol, hardware_len, protocol_len, operation)) =
+        tuple((be_u16, be_u16, be_u8, be_u8, be_u16))(input)?;
+    if hardware != ARP_HARDWARE_ETHERNET
+        || protocol != ETHERTYPE_IPV4
+        || hardware_len != 6
+        || protocol_len != 4
+    {
+        return Err(nom::Err::Error(Error::new(input, ErrorKind::Verify)));
+    }
+    let (input, (sender_mac, sender_ip, target_mac, target_ip)) = tuple((
+        parse_mac_address,
+        parse_ipv4_address,
+        parse_mac_address,
+        parse_ipv4_address,
+    ))(input)?;
+    Ok((
+        input,
+        ArpPacket {
+            operation,
+            sender_mac,
+            sender_ip,
+            target_mac,
+            target_ip,
+        },
+    ))
+}
 
-        // Time to Live
+#[derive(Debug)]
+pub struct IPv4Header {
+    pub version: u8,
+    pub ihl: u8,
+    pub type_of_service: u8,
+    pub total_length: u16,
+    pub identification: u16,
+    pub flags: u8,
+    pub fragment_offset: u16,
+    pub time_to_live: u8,
+    pub protocol: u8,
+    pub header_checksum: u16,
+    pub source_address: Ipv4Addr,
+    pub destination_address: Ipv4Addr,
+    // whatever options come after the fixed part of the header, up to the end of the options list.
+    // (any padding after that is dropped)
+    pub options: Vec<IPv4Option>,
+}
 
-        // Protocol
+// the IPv4 options we know about (RFC 791 S3.1, RFC 1108, and the CIPSO draft)
+pub const IPV4_OPTION_END: u8 = 0;
+pub const IPV4_OPTION_NOP: u8 = 1;
+pub const IPV4_OPTION_RECORD_ROUTE: u8 = 7;
+pub const IPV4_OPTION_TIMESTAMP: u8 = 68;
+pub const IPV4_OPTION_SECURITY: u8 = 130;
+pub const IPV4_OPTION_LOOSE_SOURCE_ROUTE: u8 = 131;
+pub const IPV4_OPTION_EXTENDED_SECURITY: u8 = 133;
+pub const IPV4_OPTION_COMMERCIAL_SECURITY: u8 = 134;
+pub const IPV4_OPTION_STRICT_SOURCE_ROUTE: u8 = 137;
 
-        // Header Checksum
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IPv4Option {
+    EndOfList,
+    NoOperation,
+    // the addresses the packet has been through so far. pointer is where (counting from the start
+    // of the option, from 1) the next one goes
+    RecordRoute {
+        pointer: u8,
+        route: Vec<Ipv4Addr>,
+    },
+    // the route the sender wants the packet to take, either through these addresses among others
+    // (loose), or through these and only these (strict). pointer is where the next hop is
+    LooseSourceRoute {
+        pointer: u8,
+        route: Vec<Ipv4Addr>,
+    },
+    StrictSourceRoute {
+        pointer: u8,
+        route: Vec<Ipv4Addr>,
+    },
+    // milliseconds since midnight UT, each from the address it's paired with if flags asks for
+    // them. overflow counts the hops that found no room left to add theirs
+    Timestamp {
+        pointer: u8,
+        overflow: u8,
+        flags: u8,
+        entries: Vec<(Option<Ipv4Addr>, u32)>,
+    },
+    // security labels of one kind or another, which we have no use for
+    Security {
+        kind: u8,
+        data: Vec<u8>,
+    },
+    Unknown {
+        kind: u8,
+        data: Vec<u8>,
+    },
+}
 
-        // Source Address
+impl IPv4Option {
+    pub fn is_source_route(&self) -> bool {
+        matches!(
+            self,
+            IPv4Option::LooseSourceRoute { .. } | IPv4Option::StrictSourceRoute { .. }
+        )
+    }
+}
 
-        // Destination Address
+// decode the options area of an IPv4 header (all IHL * 4 - 20 bytes of it)
+pub fn parse_ipv4_options(mut input: &[u8]) -> IResult<&[u8], Vec<IPv4Option>> {
+    let malformed = |input| nom::Err::Error(Error::new(input, ErrorKind::LengthValue));
 
-        unimplemented!()
+    let mut options = Vec::new();
+    while let Some(&kind) = input.first() {
+        match kind {
+            IPV4_OPTION_END => {
+                // the rest is padding
+                options.push(IPv4Option::EndOfList);
+                return Ok((&input[input.len()..], options));
+            }
+            IPV4_OPTION_NOP => {
+                options.push(IPv4Option::NoOperation);
+                input = &input[1..];
+                continue;
+            }
+            _ => {}
+        }
+
+        // everything else is a kind, a length (counting both), and then the option's own data
+        let (rest, length) = be_u8(&input[1..])?;
+        if length < 2 {
+            return Err(malformed(input));
+        }
+        let (rest, data) = take_bytes(length as usize - 2)(rest)?;
+
+        let option = match kind {
+            IPV4_OPTION_RECORD_ROUTE
+            | IPV4_OPTION_LOOSE_SOURCE_ROUTE
+            | IPV4_OPTION_STRICT_SOURCE_ROUTE => {
+                let Some((&pointer, route)) = data.split_first() else {
+                    return Err(malformed(input));
+                };
+                if route.len() % 4 != 0 {
+                    return Err(malformed(input));
+                }
+                let route = route
+                    .chunks_exact(4)
+                    .map(|address| Ipv4Addr::new(address[0], address[1], address[2], address[3]))
+                    .collect();
+                match kind {
+                    IPV4_OPTION_RECORD_ROUTE => IPv4Option::RecordRoute { pointer, route },
+                    IPV4_OPTION_LOOSE_SOURCE_ROUTE => {
+                        IPv4Option::LooseSourceRoute { pointer, route }
+                    }
+                    _ => IPv4Option::StrictSourceRoute { pointer, route },
+                }
+            }
+            IPV4_OPTION_TIMESTAMP => {
+                let [pointer, overflow_flags, entries @ ..] = data else {
+                    return Err(malformed(input));
+                };
+                let (overflow, flags) = (overflow_flags >> 4, overflow_flags & 0xf);
+                // just timestamps, or each one after the address it's from (flags 1), or after an
+                // address the sender picked (flags 3)
+                let with_addresses = flags != 0;
+                let entry_len = if with_addresses { 8 } else { 4 };
+                if entries.len() % entry_len != 0 {
+                    return Err(malformed(input));
+                }
+                let entries = entries
+                    .chunks_exact(entry_len)
+                    .map(|entry| {
+                        let (address, timestamp) = entry.split_at(entry_len - 4);
+                        let address = with_addresses
+                            .then(|| Ipv4Addr::new(address[0], address[1], address[2], address[3]));
+                        let timestamp = u32::from_be_bytes(timestamp.try_into().unwrap());
+                        (address, timestamp)
+                    })
+                    .collect();
+                IPv4Option::Timestamp {
+                    pointer: *pointer,
+                    overflow,
+                    flags,
+                    entries,
+                }
+            }
+            IPV4_OPTION_SECURITY
+            | IPV4_OPTION_EXTENDED_SECURITY
+            | IPV4_OPTION_COMMERCIAL_SECURITY => IPv4Option::Security {
+                kind,
+                data: data.to_vec(),
+            },
+            _ => IPv4Option::Unknown {
+                kind,
+                data: data.to_vec(),
+            },
+        };
+        options.push(option);
+        input = rest;
     }
+
+    Ok((input, options))
 }
 
-fn parse_version_ihl(input: &[u8]) -> IResult<&[u8], (u8, u8)> {
-    let (input, (version, ihl)) = take_4b_twice(input).unwrap();
+// The bottom two bits of the type of service byte (RFC 3168 S5): whether the sender of a packet
+// understands ECN, and if so, whether a router on the way has marked it to say it's congested
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ecn {
+    NotEct,
+    Ect1,
+    Ect0,
+    Ce,
+}
 
-    Ok((input, (version, ihl)))
+impl From<u8> for Ecn {
+    fn from(bits: u8) -> Self {
+        match bits & 0b11 {
+            0b00 => Ecn::NotEct,
+            0b01 => Ecn::Ect1,
+            0b10 => Ecn::Ect0,
+            _ => Ecn::Ce,
+        }
+    }
 }
 
-fn parse_type_of_service(input: &[u8]) -> IResult<&[u8], u8> {
-    take_8b(input)
+impl From<Ecn> for u8 {
+    fn from(ecn: Ecn) -> Self {
+        match ecn {
+            Ecn::NotEct => 0b00,
+            Ecn::Ect1 => 0b01,
+            Ecn::Ect0 => 0b10,
+            Ecn::Ce => 0b11,
+        }
+    }
 }
 
-fn parse_total_length(input: &[u8]) -> IResult<&[u8], u16> {
-    take_16b(input)
+impl IPv4Header {
+    // the top six bits of the type of service byte (RFC 2474)
+    pub fn dscp(&self) -> u8 {
+        self.type_of_service >> 2
+    }
+
+    pub fn ecn(&self) -> Ecn {
+        Ecn::from(self.type_of_service)
+    }
+
+    // the sender wants to pick the route the packet takes (RFC 791 S3.1)
+    pub fn is_source_routed(&self) -> bool {
+        self.options.iter().any(IPv4Option::is_source_route)
+    }
+
+    pub fn from_slice(slice: &[u8]) -> Result<IPv4Header, nom::Err<Error<&[u8]>>> {
+        parse_ipv4(slice).map(|(_, header)| header)
+    }
 }
 
 pub fn parse_ipv4(input: &[u8]) -> IResult<&[u8], IPv4Header> {
@@ -191,8 +500,6 @@ pub fn parse_ipv4(input: &[u8]) -> IResult<&[u8], IPv4Header> {
     let header_checksum = take(16usize);
     let source_address = take(32usize);
     let destination_address = take(32usize);
-    //let options = ;
-    //let padding =;
 
     let parser = tuple((
         version,
@@ -226,12 +533,18 @@ pub fn parse_ipv4(input: &[u8]) -> IResult<&[u8], IPv4Header> {
             destination_address,
         ),
     ): (_, (_, _, _, _, _, _, _, _, _, _, u32, u32)) =
-        bits::<&[u8], _, Error<(&[u8], usize)>, Error<&[u8]>, _>(parser)(input)
-            .expect("Input should contain atleast 20 bytes (the IPv4 header)");
+        bits::<&[u8], _, Error<(&[u8], usize)>, Error<&[u8]>, _>(parser)(input)?;
 
     let source_address = Ipv4Addr::from(source_address);
     let destination_address = Ipv4Addr::from(destination_address);
 
+    // the IHL says where the options end, and the payload starts
+    if ihl < 5 {
+        return Err(nom::Err::Error(Error::new(input, ErrorKind::Verify)));
+    }
+    let (input, options) = take_bytes((ihl as usize - 5) * 4)(input)?;
+    let (_, options) = parse_ipv4_options(options)?;
+
     Ok((
         input,
         IPv4Header {
@@ -247,19 +560,206 @@ pub fn parse_ipv4(input: &[u8]) -> IResult<&[u8], IPv4Header> {
             header_checksum,
             source_address,
             destination_address,
+            options,
         },
     ))
 }
 
-fn take_4b_twice(input: &[u8]) -> IResult<&[u8], (u8, u8)> {
-    bits::<&[u8], (u8, u8), Error<(&[u8], usize)>, Error<&[u8]>, _>(tuple((
-        take(4usize),
-        take(4usize),
-    )))(input)
+#[derive(Debug)]
+pub struct IPv6Header {
+    pub version: u8,
+    pub traffic_class: u8,
+    pub flow_label: u32,
+    pub payload_length: u16,
+    pub next_header: u8,
+    pub hop_limit: u8,
+    pub source_address: Ipv6Addr,
+    pub destination_address: Ipv6Addr,
+}
+
+impl IPv6Header {
+    // the traffic class is laid out just like the IPv4 type of service byte (RFC 8200 S7)
+    pub fn dscp(&self) -> u8 {
+        self.traffic_class >> 2
+    }
+
+    pub fn ecn(&self) -> Ecn {
+        Ecn::from(self.traffic_class)
+    }
 }
 
-fn take_8b(input: &[u8]) -> IResult<&[u8], u8> {
-    bits::<&[u8], u8, Error<(&[u8], usize)>, Error<&[u8]>, _>(take(8usize))(input)
+pub fn parse_ipv6(input: &[u8]) -> IResult<&[u8], IPv6Header> {
+    let version = take(4usize);
+    let traffic_class = take(8usize);
+    let flow_label = take(20usize);
+    let payload_length = take(16usize);
+    let next_header = take(8usize);
+    let hop_limit = take(8usize);
+    let source_address = take(128usize);
+    let destination_address = take(128usize);
+
+    let parser = tuple((
+        version,
+        traffic_class,
+        flow_label,
+        payload_length,
+        next_header,
+        hop_limit,
+        source_address,
+        destination_address,
+    ));
+
+    // (a short packet is an error here, rather than a panic -- it may well be from the network)
+    let (
+        input,
+        (
+            version,
+            traffic_class,
+            flow_label,
+            payload_length,
+            next_header,
+            hop_limit,
+            source_address,
+            destination_address,
+        ),
+    ): (_, (_, _, _, _, _, _, u128, u128)) =
+        bits::<&[u8], _, Error<(&[u8], usize)>, Error<&[u8]>, _>(parser)(input)?;
+
+    Ok((
+        input,
+        IPv6Header {
+            version,
+            traffic_class,
+            flow_label,
+            payload_length,
+            next_header,
+            hop_limit,
+            source_address: Ipv6Addr::from(source_address),
+            destination_address: Ipv6Addr::from(destination_address),
+        },
+    ))
+}
+
+// the extension headers (RFC 8200 S4) that can come between the IPv6 header and the upper-layer one
+pub const IPV6_HOP_BY_HOP: u8 = 0;
+pub const IPV6_ROUTING: u8 = 43;
+pub const IPV6_FRAGMENT: u8 = 44;
+pub const IPV6_AUTHENTICATION: u8 = 51;
+pub const IPV6_DESTINATION_OPTIONS: u8 = 60;
+
+// walk past the extension headers that start input (next_header being the IPv6 header's), to the
+// upper-layer header. gives back its protocol number, and input from there on.
+//
+// a fragment (anything but an atomic one, RFC 6946) stops the walk, with IPV6_FRAGMENT as the
+// protocol: there's no knowing what the upper layer is until it has been put back together
+pub fn parse_ipv6_extension_headers(mut next_header: u8, mut input: &[u8]) -> IResult<&[u8], u8> {
+    loop {
+        let size = match next_header {
+            IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DESTINATION_OPTIONS => {
+                // Hdr Ext Len is in 8 byte units, not counting the first 8
+                let (_, (_, length)) = tuple((be_u8, be_u8))(input)?;
+                (length as usize + 1) * 8
+            }
+            IPV6_AUTHENTICATION => {
+                // which counts in 4 byte units, not counting the first 8 (RFC 4302 S2.2)
+                let (_, (_, length)) = tuple((be_u8, be_u8))(input)?;
+                (length as usize + 2) * 4
+            }
+            IPV6_FRAGMENT => {
+                // the offset, two reserved bits, and the more fragments flag
+                let (_, (_, _, offset_flags)) = tuple((be_u8, be_u8, be_u16))(input)?;
+                if offset_flags & !0b110 != 0 {
+                    return Ok((input, IPV6_FRAGMENT));
+                }
+                8
+            }
+            _ => return Ok((input, next_header)),
+        };
+
+        // every one of them starts with the next header's protocol number
+        let (rest, header) = take_bytes(size)(input)?;
+        next_header = header[0];
+        input = rest;
+    }
+}
+
+// UDP (RFC 768)
+pub const UDP_HEADER_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpHeader {
+    pub source_port: u16,
+    pub destination_port: u16,
+    // of the header and the data together
+    pub length: u16,
+    pub checksum: u16,
+}
+
+impl UdpHeader {
+    pub fn to_bytes(&self) -> [u8; UDP_HEADER_LEN] {
+        let mut bytes = [0; UDP_HEADER_LEN];
+        bytes[0..2].copy_from_slice(&self.source_port.to_be_bytes());
+        bytes[2..4].copy_from_slice(&self.destination_port.to_be_bytes());
+        bytes[4..6].copy_from_slice(&self.length.to_be_bytes());
+        bytes[6..8].copy_from_slice(&self.checksum.to_be_bytes());
+        bytes
+    }
+}
+
+pub fn parse_udp(input: &[u8]) -> IResult<&[u8], UdpHeader> {
+    let (input, (source_port, destination_port, length, checksum)) =
+        tuple((be_u16, be_u16, be_u16, be_u16))(input)?;
+    Ok((
+        input,
+        UdpHeader {
+            source_port,
+            destination_port,
+            length,
+            checksum,
+        },
+    ))
+}
+
+// what goes into the checksum of an upper-layer packet of len bytes, along with the packet itself:
+// its addresses, protocol and length (RFC 768, RFC 8200 S8.1)
+pub fn pseudo_header(source: IpAddr, destination: IpAddr, protocol: u8, len: usize) -> Vec<u8> {
+    let mut pseudo_header = Vec::new();
+    match (source, destination) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            pseudo_header.extend(source.octets());
+            pseudo_header.extend(destination.octets());
+            pseudo_header.extend([0, protocol]);
+            pseudo_header.extend((len as u16).to_be_bytes());
+        }
+        (source, destination) => {
+            pseudo_header.extend(ipv6_octets(source));
+            pseudo_header.extend(ipv6_octets(destination));
+            pseudo_header.extend((len as u32).to_be_bytes());
+            pseudo_header.extend([0, 0, 0, protocol]);
+        }
+    }
+    pseudo_header
+}
+
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+// the internet checksum (RFC 1071) of a pseudo-header (which is always a whole number of 16 bit
+// words) followed by a packet. over a packet with a correct checksum in it, this is zero
+pub fn internet_checksum(pseudo_header: &[u8], packet: &[u8]) -> u16 {
+    let mut sum: u32 = pseudo_header
+        .chunks(2)
+        .chain(packet.chunks(2))
+        .map(|word| u16::from_be_bytes([word[0], word.get(1).copied().unwrap_or(0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
 }
 
 fn take_16b(input: &[u8]) -> IResult<&[u8], u16> {
@@ -279,10 +779,15 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
     fn tun_tap_parser_missing_arguments() {
         let input: &[u8] = &[0];
-        TunTapHeader::from_slice(input).unwrap();
+        assert!(TunTapHeader::from_slice(input).is_err());
+    }
+
+    #[test]
+    fn tun_tap_parser_unknown_flags() {
+        let input: &[u8] = &[0x12, 0x34, 8, 0, 2, 3];
+        assert!(TunTapHeader::from_slice(input).is_err());
     }
 
     #[test]
@@ -317,5 +822,281 @@ mod tests {
         assert_eq!(header.header_checksum, 29170);
         assert_eq!(header.source_address, Ipv4Addr::new(192, 168, 0, 1));
         assert_eq!(header.destination_address, Ipv4Addr::new(192, 168, 0, 2));
+        assert_eq!(header.dscp(), 0);
+        assert_eq!(header.ecn(), Ecn::NotEct);
+    }
+
+    // an IPv6 header from fd00::2 to fd00::1, with payload_length bytes after it
+    fn ipv6_header(next_header: u8, payload_length: u16) -> Vec<u8> {
+        let mut packet = vec![0x60, 0x00, 0x00, 0x00];
+        packet.extend(payload_length.to_be_bytes());
+        packet.extend([next_header, 64]);
+        packet.extend(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2).octets());
+        packet.extend(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1).octets());
+        packet
+    }
+
+    #[test]
+    fn ipv6_parser() {
+        let mut input = ipv6_header(6, 4);
+        // (the traffic class straddles the first two bytes) AF41, congestion experienced
+        input[0] = 0x68;
+        input[1] = 0b1011_0000;
+        input.extend([1, 2, 3, 4]);
+        let (remaining, header) = parse_ipv6(&input).unwrap();
+
+        assert_eq!(remaining, &[1, 2, 3, 4]);
+        assert_eq!(header.version, 6);
+        assert_eq!(header.traffic_class, 0b1000_1011);
+        assert_eq!(header.dscp(), 0b10_0010);
+        assert_eq!(header.ecn(), Ecn::Ce);
+        assert_eq!(header.flow_label, 0);
+        assert_eq!(header.payload_length, 4);
+        assert_eq!(header.next_header, 6);
+        assert_eq!(header.hop_limit, 64);
+        assert_eq!(
+            header.source_address,
+            Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2)
+        );
+        assert_eq!(
+            header.destination_address,
+            Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1)
+        );
+
+        // too short to be an IPv6 header
+        assert!(parse_ipv6(&input[..39]).is_err());
+    }
+
+    #[test]
+    fn ipv6_extension_headers_are_skipped() {
+        let mut input = Vec::new();
+        // hop-by-hop options, 8 bytes long, then destination options, 16 bytes long
+        input.extend([IPV6_DESTINATION_OPTIONS, 0, 1, 4, 0, 0, 0, 0]);
+        input.extend([IPV6_AUTHENTICATION, 1, 1, 12, 0, 0, 0, 0]);
+        input.extend([0; 8]);
+        // then an authentication header, 12 bytes long
+        input.extend([IPV6_FRAGMENT, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        // and an atomic fragment
+        input.extend([6, 0, 0, 0, 0, 0, 0, 1]);
+        input.extend([1, 2, 3, 4]);
+        let (remaining, protocol) = parse_ipv6_extension_headers(IPV6_HOP_BY_HOP, &input).unwrap();
+        assert_eq!(protocol, 6);
+        assert_eq!(remaining, &[1, 2, 3, 4]);
+
+        // a real fragment is as far as it goes
+        let input = [6, 0, 0, 1, 0, 0, 0, 1, 1, 2, 3, 4];
+        let (remaining, protocol) = parse_ipv6_extension_headers(IPV6_FRAGMENT, &input).unwrap();
+        assert_eq!(protocol, IPV6_FRAGMENT);
+        assert_eq!(remaining, &input);
+
+        // and a header that runs off the end is an error
+        assert!(parse_ipv6_extension_headers(IPV6_HOP_BY_HOP, &[6, 1, 0, 0, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn ipv4_options_come_before_the_payload() {
+        let mut input = vec![
+            0x48, 0, 0, 36, 0, 0, 0, 0, 64, 6, 0, 0, 192, 168, 0, 2, 192, 168, 0, 1,
+        ];
+        // a no-op, room to record one hop, then the end of the list and some padding
+        input.extend([1, 7, 7, 4, 0, 0, 0, 0, 0, 0, 0, 0]);
+        input.extend([1, 2, 3, 4]);
+        let (remaining, header) = parse_ipv4(&input).unwrap();
+
+        assert_eq!(remaining, &[1, 2, 3, 4]);
+        assert_eq!(header.ihl, 8);
+        assert_eq!(
+            header.options,
+            vec![
+                IPv4Option::NoOperation,
+                IPv4Option::RecordRoute {
+                    pointer: 4,
+                    route: vec![Ipv4Addr::UNSPECIFIED],
+                },
+                IPv4Option::EndOfList,
+            ]
+        );
+        assert!(!header.is_source_routed());
+
+        // an IHL too big for the packet
+        input[0] = 0x4f;
+        assert!(parse_ipv4(&input).is_err());
+    }
+
+    #[test]
+    fn truncated_ipv4_headers_are_errors() {
+        let input = [
+            0x45, 0, 0, 20, 0, 0, 0, 0, 64, 6, 0, 0, 192, 168, 0, 2, 192, 168, 0, 1,
+        ];
+        assert!(parse_ipv4(&input).is_ok());
+        for len in 0..input.len() {
+            assert!(parse_ipv4(&input[..len]).is_err());
+        }
+        assert!(IPv4Header::from_slice(&input[..10]).is_err());
+    }
+
+    #[test]
+    fn ipv4_option_parser() {
+        let mut input = Vec::new();
+        // a timestamp from 10.0.0.1, and room for one more
+        input.extend([68, 20, 13, 0x01, 10, 0, 0, 1, 0, 0, 1, 0]);
+        input.extend([0, 0, 0, 0, 0, 0, 0, 0]);
+        // a strict source route through 10.0.0.2, which is next
+        input.extend([137, 7, 4, 10, 0, 0, 2]);
+        // a security label
+        input.extend([130, 4, 0xab, 0xcd]);
+        // and something we've never heard of
+        input.extend([99, 3, 1]);
+        let (_, options) = parse_ipv4_options(&input).unwrap();
+        assert_eq!(
+            options,
+            vec![
+                IPv4Option::Timestamp {
+                    pointer: 13,
+                    overflow: 0,
+                    flags: 1,
+                    entries: vec![
+                        (Some(Ipv4Addr::new(10, 0, 0, 1)), 256),
+                        (Some(Ipv4Addr::UNSPECIFIED), 0),
+                    ],
+                },
+                IPv4Option::StrictSourceRoute {
+                    pointer: 4,
+                    route: vec![Ipv4Addr::new(10, 0, 0, 2)],
+                },
+                IPv4Option::Security {
+                    kind: IPV4_OPTION_SECURITY,
+                    data: vec![0xab, 0xcd],
+                },
+                IPv4Option::Unknown {
+                    kind: 99,
+                    data: vec![1],
+                },
+            ]
+        );
+        assert!(options[1].is_source_route());
+
+        // options whose length doesn't fit
+        assert!(parse_ipv4_options(&[7, 1]).is_err());
+        assert!(parse_ipv4_options(&[7, 8, 4, 0]).is_err());
+        assert!(parse_ipv4_options(&[7]).is_err());
+    }
+
+    #[test]
+    fn type_of_service_splits_into_dscp_and_ecn() {
+        // expedited forwarding, marked congestion experienced
+        let input: &[u8] = &[
+            69,
+            0b1011_1011,
+            0,
+            20,
+            0,
+            0,
+            64,
+            0,
+            64,
+            6,
+            0,
+            0,
+            192,
+            168,
+            0,
+            2,
+            192,
+            168,
+            0,
+            1,
+        ];
+        let (_, header) = parse_ipv4(input).unwrap();
+        assert_eq!(header.dscp(), 46);
+        assert_eq!(header.ecn(), Ecn::Ce);
+
+        for ecn in [Ecn::NotEct, Ecn::Ect1, Ecn::Ect0, Ecn::Ce] {
+            assert_eq!(Ecn::from(u8::from(ecn)), ecn);
+        }
+    }
+
+    #[test]
+    fn ethernet_parser() {
+        let header = EthernetHeader {
+            destination: MacAddress::BROADCAST,
+            source: MacAddress([2, 0, 0, 0, 0, 1]),
+            vlan: None,
+            ethertype: ETHERTYPE_ARP,
+        };
+        let mut frame = header.to_bytes();
+        assert_eq!(frame.len(), ETHERNET_HEADER_LEN);
+        frame.extend(b"payload");
+        let (rest, parsed) = parse_ethernet(&frame).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(rest, b"payload");
+        assert!(parsed.destination.is_multicast() && !parsed.source.is_multicast());
+        assert_eq!(parsed.source.to_string(), "02:00:00:00:00:01");
+    }
+
+    #[test]
+    fn vlan_tags_come_before_the_ethertype() {
+        let header = EthernetHeader {
+            destination: MacAddress([2, 0, 0, 0, 0, 2]),
+            source: MacAddress([2, 0, 0, 0, 0, 1]),
+            vlan: Some(VlanTag {
+                priority: 5,
+                drop_eligible: true,
+                id: 100,
+            }),
+            ethertype: ETHERTYPE_IPV6,
+        };
+        let frame = header.to_bytes();
+        assert_eq!(frame.len(), ETHERNET_HEADER_LEN + VLAN_TAG_LEN);
+        assert_eq!(&frame[12..18], &[0x81, 0x00, 0xb0, 100, 0x86, 0xdd]);
+        assert_eq!(parse_ethernet(&frame).unwrap().1, header);
+    }
+
+    #[test]
+    fn arp_parser() {
+        let request = ArpPacket {
+            operation: ARP_REQUEST,
+            sender_mac: MacAddress([2, 0, 0, 0, 0, 1]),
+            sender_ip: Ipv4Addr::new(192, 168, 0, 1),
+            target_mac: MacAddress([0; 6]),
+            target_ip: Ipv4Addr::new(192, 168, 0, 2),
+        };
+        let bytes = request.to_bytes();
+        assert_eq!(bytes.len(), 28);
+        assert_eq!(parse_arp(&bytes).unwrap().1, request);
+
+        // only for IPv4 over Ethernet
+        let mut other = bytes.clone();
+        other[2..4].copy_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+        assert!(parse_arp(&other).is_err());
+    }
+
+    #[test]
+    fn udp_parser() {
+        let header = UdpHeader {
+            source_port: 4000,
+            destination_port: 53,
+            length: 12,
+            checksum: 0xbeef,
+        };
+        let mut input = header.to_bytes().to_vec();
+        input.extend(b"data");
+        assert_eq!(input[..8], [0x0f, 0xa0, 0, 53, 0, 12, 0xbe, 0xef]);
+        assert_eq!(parse_udp(&input).unwrap(), (&b"data"[..], header));
+        assert!(parse_udp(&input[..7]).is_err());
+    }
+
+    #[test]
+    fn checksums_over_the_pseudo_header() {
+        let source = IpAddr::from([192, 168, 0, 1]);
+        let destination = IpAddr::from([192, 168, 0, 2]);
+        let pseudo_header = pseudo_header(source, destination, 17, 9);
+        assert_eq!(pseudo_header, [192, 168, 0, 1, 192, 168, 0, 2, 0, 17, 0, 9]);
+
+        // an odd length is padded out with a zero byte
+        let mut packet = vec![1, 2, 0, 0, 5, 6, 7, 8, 9];
+        let checksum = internet_checksum(&pseudo_header, &packet);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+        assert_eq!(internet_checksum(&pseudo_header, &packet), 0);
     }
 }
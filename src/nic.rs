@@ -0,0 +1,123 @@
+use crate::ethernet::Link;
+use crate::network_parse::{ETHERNET_HEADER_LEN, VLAN_TAG_LEN};
+use crate::tcp::MTU;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Instant;
+
+// the packet information header the tun (or tap) device puts in front of everything
+const PI_HEADER_LEN: usize = 4;
+
+// the most the packet loop can be handed at once: a full-sized packet, in a frame with a VLAN tag
+pub const MAX_LEN: usize = PI_HEADER_LEN + ETHERNET_HEADER_LEN + VLAN_TAG_LEN + MTU;
+
+// Anywhere we can push a finished IP packet out of.
+//
+// The tun (or tap) device is the only real one, but keeping the connection code behind this trait
+// means it never has to know how packets get framed for the device, and lets tests capture
+// whatever the stack would have sent.
+pub trait Nic {
+    fn send(&mut self, packet: &[u8]) -> io::Result<usize>;
+}
+
+// A device the packet loop reads packets from, as well as sending them out of
+pub trait Device: Nic + AsRawFd {
+    // read what has come in into buf. if it was an IP packet, returns it, with the packet
+    // information header in front of it just as the tun device has it
+    fn recv<'a>(&mut self, buf: &'a mut [u8]) -> io::Result<Option<&'a [u8]>>;
+}
+
+// The tun device is opened with packet information, so it hands us (and expects back) a 4 byte
+// header in front of every IP packet: 2 bytes of flags, and 2 bytes of ethertype
+impl Nic for tun_tap::Iface {
+    fn send(&mut self, packet: &[u8]) -> io::Result<usize> {
+        let mut buf = [0u8; 1504];
+        let protocol: u16 = match packet.first().map(|b| b >> 4) {
+            Some(6) => 0x86dd,
+            _ => 0x0800,
+        };
+        buf[2..4].copy_from_slice(&protocol.to_be_bytes());
+
+        let size = std::cmp::min(buf.len() - 4, packet.len());
+        buf[4..4 + size].copy_from_slice(&packet[..size]);
+
+        let sent = tun_tap::Iface::send(self, &buf[..4 + size])?;
+        Ok(sent.saturating_sub(4))
+    }
+}
+
+impl Device for tun_tap::Iface {
+    fn recv<'a>(&mut self, buf: &'a mut [u8]) -> io::Result<Option<&'a [u8]>> {
+        let nbytes = tun_tap::Iface::recv(self, buf)?;
+        Ok(Some(&buf[..nbytes]))
+    }
+}
+
+// A tap device, which has Ethernet frames (still with packet information in front of them) where
+// a tun device has IP packets
+pub struct Tap {
+    iface: tun_tap::Iface,
+    link: Link,
+}
+
+impl Tap {
+    pub fn new(iface: tun_tap::Iface, link: Link) -> Self {
+        Tap { iface, link }
+    }
+}
+
+// what the link layer sends out of a tap device: whole frames
+struct Frames<'a>(&'a tun_tap::Iface);
+
+impl Nic for Frames<'_> {
+    fn send(&mut self, frame: &[u8]) -> io::Result<usize> {
+        // (the device goes by the frame's own ethertype, but we may as well say)
+        let mut buf = vec![0u8; PI_HEADER_LEN];
+        buf[2..4].copy_from_slice(&frame[12..14]);
+        buf.extend_from_slice(frame);
+        let sent = self.0.send(&buf)?;
+        Ok(sent.saturating_sub(PI_HEADER_LEN))
+    }
+}
+
+impl Nic for Tap {
+    fn send(&mut self, packet: &[u8]) -> io::Result<usize> {
+        self.link
+            .send(&mut Frames(&self.iface), packet, Instant::now())?;
+        Ok(packet.len())
+    }
+}
+
+impl Device for Tap {
+    fn recv<'a>(&mut self, buf: &'a mut [u8]) -> io::Result<Option<&'a [u8]>> {
+        let nbytes = self.iface.recv(buf)?;
+        let frame = &buf[PI_HEADER_LEN.min(nbytes)..nbytes];
+        let Some(offset) = self
+            .link
+            .on_frame(&mut Frames(&self.iface), frame, Instant::now())?
+        else {
+            return Ok(None);
+        };
+        // the frame's ethertype is right in front of the packet, so blanking out the two bytes
+        // before that leaves a packet information header there
+        let packet = PI_HEADER_LEN + offset;
+        let start = packet - PI_HEADER_LEN;
+        buf[start..start + 2].fill(0);
+        Ok(Some(&buf[start..nbytes]))
+    }
+}
+
+impl AsRawFd for Tap {
+    fn as_raw_fd(&self) -> RawFd {
+        self.iface.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+// Stands in for the tun device in tests: everything sent ends up in the vector
+impl Nic for Vec<Vec<u8>> {
+    fn send(&mut self, packet: &[u8]) -> io::Result<usize> {
+        self.push(packet.to_vec());
+        Ok(packet.len())
+    }
+}
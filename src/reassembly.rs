@@ -0,0 +1,344 @@
+// IPv4 fragment reassembly (RFC 791 S3.2, RFC 815)
+//
+// A datagram too big for a link on the way gets split into fragments, each with its own IP header,
+// which all have to arrive before any of the datagram can be handed on. We keep the fragments of
+// each datagram (going by its source, destination, protocol and identification) until they're all
+// here, or until we've waited long enough that the rest clearly aren't coming.
+//
+// Fragments that overlap are how attacks on reassembly (and on the firewalls in front of it) tend
+// to work, and nobody sends them otherwise. So, as RFC 5722 has it for IPv6: a fragment that
+// overlaps any other fragment of its datagram -- other than one that's an exact duplicate of it --
+// gets the whole datagram thrown away, including any of its fragments still to arrive.
+//
+// Anyone can send us fragments of datagrams that will never be finished, so the memory held on to
+// is limited: past that, the oldest datagrams go first. A datagram that times out is reported to
+// its sender with an ICMP error, as long as we got its first fragment (RFC 792, RFC 1122 S3.3.2):
+// that's what the error has to quote.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+// how long after its first fragment arrives a datagram has to be finished (RFC 1122 S3.3.2 asks
+// for somewhere between 60 seconds and 2 minutes; like Linux, we don't wait that long)
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// the most fragment data held on to at once, and the most datagrams being put back together
+const MAX_MEMORY: usize = 256 * 1024;
+const MAX_DATAGRAMS: usize = 64;
+
+// no datagram is longer than this, header and all
+const MAX_DATAGRAM_LEN: usize = 65535;
+const MIN_HEADER_LEN: usize = 20;
+
+// how much of the first fragment's data we keep, to quote in an ICMP error (RFC 792)
+const QUOTED_DATA_LEN: usize = 8;
+
+// fragments which all belong to the same datagram
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub struct FragmentKey {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub protocol: u8,
+    pub identification: u16,
+}
+
+struct Datagram {
+    // the fragments we have so far, by offset. none of them overlap
+    fragments: BTreeMap<usize, Vec<u8>>,
+    // how long the whole datagram is, once its last fragment has turned up
+    len: Option<usize>,
+    received: usize,
+    expires: Instant,
+    // the IP header of the first fragment, and the start of its data, once it's here
+    first_fragment: Option<Vec<u8>>,
+    // two fragments overlapped, so there's nothing left of it. this stays around until it would
+    // have timed out, so the rest of its fragments don't start it over
+    discarded: bool,
+}
+
+#[derive(Default)]
+pub struct Reassembler {
+    datagrams: HashMap<FragmentKey, Datagram>,
+    // how much fragment data all of them are holding
+    memory: usize,
+    // the first fragments of datagrams that have timed out, still to be reported
+    timed_out: Vec<Vec<u8>>,
+}
+
+impl Reassembler {
+    // a fragment (with the given IP header), whose data starts offset bytes into the payload of its
+    // datagram. once the last fragment is here, the payload of the whole datagram
+    pub fn insert(
+        &mut self,
+        key: FragmentKey,
+        header: &[u8],
+        offset: usize,
+        more_fragments: bool,
+        data: &[u8],
+        now: Instant,
+    ) -> Option<Vec<u8>> {
+        self.expire(now);
+
+        let end = offset + data.len();
+        // every fragment but the last carries some multiple of 8 bytes, and the datagram they add
+        // up to has to fit in an IP packet
+        if (more_fragments && (data.is_empty() || !data.len().is_multiple_of(8)))
+            || end > MAX_DATAGRAM_LEN - MIN_HEADER_LEN
+        {
+            return None;
+        }
+
+        if !self.datagrams.contains_key(&key) {
+            self.make_room(MAX_DATAGRAMS - 1, MAX_MEMORY, None);
+            self.datagrams.insert(
+                key,
+                Datagram {
+                    fragments: BTreeMap::new(),
+                    len: None,
+                    received: 0,
+                    expires: now + TIMEOUT,
+                    first_fragment: None,
+                    discarded: false,
+                },
+            );
+        }
+        let datagram = self.datagrams.get_mut(&key).expect("just inserted");
+        if datagram.discarded {
+            return None;
+        }
+
+        let before = datagram.fragments.range(..=offset).next_back();
+        let after = datagram.fragments.range(offset..).next();
+        if let Some((&at, fragment)) = before {
+            if at == offset && fragment.as_slice() == data {
+                // a duplicate, which is harmless
+                return None;
+            }
+        }
+        let overlaps = before.is_some_and(|(&at, fragment)| at + fragment.len() > offset)
+            || after.is_some_and(|(&at, _)| at < end);
+        // the last fragment says how long the datagram is, and nothing may go past that
+        let past_end = match datagram.len {
+            Some(len) => end > len || (!more_fragments && end != len),
+            None => {
+                !more_fragments
+                    && datagram
+                        .fragments
+                        .last_key_value()
+                        .is_some_and(|(&at, fragment)| at + fragment.len() > end)
+            }
+        };
+        if overlaps || past_end {
+            self.memory -= datagram.received;
+            datagram.fragments.clear();
+            datagram.received = 0;
+            datagram.discarded = true;
+            return None;
+        }
+        if !more_fragments {
+            datagram.len = Some(end);
+        }
+        if offset == 0 {
+            let quoted = std::cmp::min(data.len(), QUOTED_DATA_LEN);
+            datagram.first_fragment = Some([header, &data[..quoted]].concat());
+        }
+
+        if self.memory + data.len() > MAX_MEMORY {
+            self.make_room(MAX_DATAGRAMS, MAX_MEMORY - data.len(), Some(key));
+            if self.memory + data.len() > MAX_MEMORY {
+                return None;
+            }
+        }
+        let datagram = self
+            .datagrams
+            .get_mut(&key)
+            .expect("never made room with it");
+        datagram.fragments.insert(offset, data.to_vec());
+        datagram.received += data.len();
+        self.memory += data.len();
+
+        // with nothing overlapping, having as much as the whole datagram means having all of it
+        if datagram.len != Some(datagram.received) {
+            return None;
+        }
+        let datagram = self.datagrams.remove(&key).expect("still here");
+        self.memory -= datagram.received;
+        Some(datagram.fragments.into_values().flatten().collect())
+    }
+
+    // drop the datagrams whose time is up. gives back the first fragments of the ones that are to
+    // be reported
+    pub fn on_tick(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        self.expire(now);
+        std::mem::take(&mut self.timed_out)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let memory = &mut self.memory;
+        let timed_out = &mut self.timed_out;
+        self.datagrams.retain(|_, datagram| {
+            let expired = datagram.expires <= now;
+            if expired {
+                *memory -= datagram.received;
+                // (one we threw away ourselves didn't time out)
+                if !datagram.discarded {
+                    timed_out.extend(datagram.first_fragment.take());
+                }
+            }
+            !expired
+        });
+    }
+
+    // throw away the oldest datagrams (other than keep) until there are no more than datagrams of
+    // them, holding no more than memory between them
+    fn make_room(&mut self, datagrams: usize, memory: usize, keep: Option<FragmentKey>) {
+        while self.datagrams.len() > datagrams || self.memory > memory {
+            let Some(oldest) = self
+                .datagrams
+                .iter()
+                .filter(|(key, _)| Some(**key) != keep)
+                .min_by_key(|(_, datagram)| datagram.expires)
+                .map(|(key, _)| *key)
+            else {
+                return;
+            };
+            let datagram = self.datagrams.remove(&oldest).expect("just found");
+            self.memory -= datagram.received;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: [u8; 20] = [0x45; 20];
+
+    const KEY: FragmentKey = FragmentKey {
+        source: Ipv4Addr::new(192, 168, 0, 2),
+        destination: Ipv4Addr::new(192, 168, 0, 1),
+        protocol: 6,
+        identification: 1234,
+    };
+
+    #[test]
+    fn fragments_are_put_back_together() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::default();
+        let data: Vec<u8> = (0..40).collect();
+
+        // in any order
+        assert_eq!(
+            reassembler.insert(KEY, &HEADER, 32, false, &data[32..], now),
+            None
+        );
+        assert_eq!(
+            reassembler.insert(KEY, &HEADER, 0, true, &data[..16], now),
+            None
+        );
+        // (a duplicate doesn't hurt)
+        assert_eq!(
+            reassembler.insert(KEY, &HEADER, 0, true, &data[..16], now),
+            None
+        );
+        assert_eq!(
+            reassembler.insert(KEY, &HEADER, 16, true, &data[16..32], now),
+            Some(data)
+        );
+        assert!(reassembler.datagrams.is_empty());
+        assert_eq!(reassembler.memory, 0);
+    }
+
+    #[test]
+    fn overlapping_fragments_throw_the_datagram_away() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::default();
+        let data = [7u8; 40];
+
+        assert_eq!(
+            reassembler.insert(KEY, &HEADER, 0, true, &data[..16], now),
+            None
+        );
+        assert_eq!(
+            reassembler.insert(KEY, &HEADER, 8, true, &data[8..24], now),
+            None
+        );
+        assert_eq!(reassembler.memory, 0);
+
+        // including anything more of it that turns up
+        assert_eq!(
+            reassembler.insert(KEY, &HEADER, 16, false, &data[16..], now),
+            None
+        );
+        assert_eq!(
+            reassembler.insert(KEY, &HEADER, 0, true, &data[..16], now),
+            None
+        );
+
+        // until it would have timed out anyway
+        let later = now + TIMEOUT;
+        assert_eq!(
+            reassembler.insert(KEY, &HEADER, 16, false, &data[16..], later),
+            None
+        );
+        assert_eq!(
+            reassembler.insert(KEY, &HEADER, 0, true, &data[..16], later),
+            Some(data.to_vec())
+        );
+    }
+
+    #[test]
+    fn unfinished_datagrams_time_out() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::default();
+        let data = [7u8; 16];
+
+        reassembler.insert(KEY, &HEADER, 0, true, &data[..8], now);
+        // it's reported with the first fragment's header, and the start of its data
+        assert!(reassembler.on_tick(now).is_empty());
+        assert_eq!(
+            reassembler.on_tick(now + TIMEOUT),
+            vec![[&HEADER[..], &data[..8]].concat()]
+        );
+        assert!(reassembler.datagrams.is_empty());
+        assert_eq!(reassembler.memory, 0);
+        assert_eq!(
+            reassembler.insert(KEY, &HEADER, 8, false, &data[8..], now + TIMEOUT),
+            None
+        );
+    }
+
+    #[test]
+    fn the_oldest_datagrams_make_way_for_new_ones() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::default();
+        let data = vec![7u8; 32 * 1024];
+
+        for identification in 0..MAX_MEMORY / data.len() {
+            let key = FragmentKey {
+                identification: identification as u16,
+                ..KEY
+            };
+            let later = now + Duration::from_millis(identification as u64);
+            reassembler.insert(key, &HEADER, 0, true, &data, later);
+        }
+        assert_eq!(reassembler.memory, MAX_MEMORY);
+
+        let key = FragmentKey {
+            identification: 1000,
+            ..KEY
+        };
+        let later = now + Duration::from_secs(1);
+        assert_eq!(
+            reassembler.insert(key, &HEADER, 0, true, &data, later),
+            None
+        );
+        assert_eq!(reassembler.memory, MAX_MEMORY);
+        assert!(!reassembler.datagrams.contains_key(&FragmentKey {
+            identification: 0,
+            ..KEY
+        }));
+        assert!(reassembler.datagrams.contains_key(&key));
+    }
+}
@@ -1,25 +1,98 @@
-use std::io;
+use crate::network_parse::Ecn;
+use crate::nic::Nic;
+use congestion::{Ack, AckResponse, Algorithm, CongestionControl};
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+pub mod congestion;
+mod fast_open;
+mod ip;
+mod isn;
+mod path_mtu;
+mod secret;
+mod syn_cookies;
+
+pub use fast_open::FastOpenCookies;
+pub use ip::{IpHeader, MTU};
+pub use isn::IsnGenerator;
+pub use path_mtu::PathMtuCache;
+pub use syn_cookies::SynCookies;
+
+// what we have to assume the peer can take, if its SYN doesn't say (RFC 9293 S3.7.1)
+const DEFAULT_MSS: u32 = 536;
+
+// the TCP options we know about (RFC 9293 S3.2, RFC 7413 S4.1.1)
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+const OPTION_FAST_OPEN: u8 = 34;
+
+// RFC 6298 S2: the RTO starts at 1 second, is never allowed below 1 second, and may be capped at
+// (no less than) 60 seconds. our clock ticks every 10ms or so
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_secs(1);
+const MAX_RTO: Duration = Duration::from_secs(60);
+const CLOCK_GRANULARITY: Duration = Duration::from_millis(10);
+
+// RFC 1122 S4.2.3.2: an ack may be delayed, but by less than 0.5 seconds
+const DEFAULT_ACK_DELAY: Duration = Duration::from_millis(40);
+const MAX_ACK_DELAY: Duration = Duration::from_millis(500);
+
+// how much data the peer can send us that the application hasn't read yet. without window scaling,
+// this is as big a window as we can advertise
+const RECV_QUEUE_SIZE: usize = u16::MAX as usize;
+
+// RFC 1122 S4.2.3.4: how long the sender holds back a segment too small to be worth sending, before
+// giving up on the window opening any further (it suggests 0.1 to 1 second)
+const SWS_OVERRIDE: Duration = Duration::from_millis(200);
+
+// RFC 1122 S4.2.3.5: how many times in a row the retransmission timer may go off before we give up
+// on the connection. these are the numbers Linux uses (tcp_synack_retries and tcp_retries2)
+const MAX_SYN_RETRANSMISSIONS: u32 = 5;
+const MAX_RETRANSMISSIONS: u32 = 15;
+
+// the maximum segment lifetime (RFC 9293 S3.4.2). TIME-WAIT lasts twice this, so anything still
+// out there from the connection is gone before the same ports can be used again. RFC 793 has it as
+// 2 minutes; Linux, which waits 60 seconds in TIME-WAIT, has it as 30
+const MSL: Duration = Duration::from_secs(30);
+
+// how many times in a row the retransmission timer can go off with full-sized segments in flight
+// before we suspect they're too big for the path, and nobody is telling us (RFC 2923 S2.1)
+const BLACK_HOLE_RETRANSMISSIONS: u32 = 2;
+
+// RFC 5961 S7: how many challenge acks we send a second, over all connections put together (as
+// Linux's tcp_challenge_ack_limit)
+const CHALLENGE_ACK_LIMIT: u32 = 1000;
 
 enum ConnectionState {
-    //Closed,
+    Closed,
     //Listen,
+    SynSent,
     SynRcvd,
     Estab,
     FinWait1,
     FinWait2,
     Closing,
     TimeWait,
+    CloseWait,
+    LastAck,
 }
 
 impl ConnectionState {
     fn is_synchronized(&self) -> bool {
         match *self {
+            ConnectionState::Closed => false,
+            ConnectionState::SynSent => false,
             ConnectionState::SynRcvd => false,
             ConnectionState::Estab => true,
             ConnectionState::FinWait1 => true,
             ConnectionState::FinWait2 => true,
             ConnectionState::Closing => true,
             ConnectionState::TimeWait => true,
+            ConnectionState::CloseWait => true,
+            ConnectionState::LastAck => true,
         }
     }
 }
@@ -28,8 +101,109 @@ pub struct TcpState {
     connection_state: ConnectionState,
     send: SendSequence,
     recieve: RecieveSequence,
-    ip: etherparse::Ipv4Header,
+    ip: ip::Header,
     tcp: etherparse::TcpHeader,
+    timers: Timers,
+    delayed_ack: DelayedAck,
+    ecn: EcnState,
+    // send small segments straight away, rather than holding them back with Nagle's algorithm
+    nodelay: bool,
+    // the most data we put in one segment to this peer: as much as fits in the path MTU, or less if
+    // the peer asked for less
+    mss: u32,
+    peer_mss: u32,
+    path_mtu: path_mtu::PathMtu,
+    // duplicate acks for a path MTU probe that's gone missing, which we count for ourselves
+    probe_dup_acks: u32,
+    algorithm: Algorithm,
+    congestion: Box<dyn CongestionControl>,
+    // data the peer has sent that the application hasn't read yet
+    pub(crate) incoming: VecDeque<u8>,
+    // data the application has written that the peer hasn't acknowledged yet -- once our SYN has
+    // been acknowledged, the front of the queue is the byte at SND.UNA
+    pub(crate) unacked: VecDeque<u8>,
+    // once we've decided to close, the sequence number our FIN takes up (just past the last byte of
+    // data)
+    closed_at: Option<u32>,
+    // the application has said it won't read any more, so anything else that arrives is dropped
+    recv_shutdown: bool,
+    // TCP Fast Open (RFC 7413): the cookie option that goes on our SYN -- a client's cookie from an
+    // earlier connection (or an empty one, asking for a cookie), or one a server is handing out
+    fast_open_option: Option<Vec<u8>>,
+    // the cookie the server sent back in its SYN-ACK, for next time
+    fast_open_cookie: Option<Vec<u8>>,
+    keepalive: Option<Keepalive>,
+    // how long data may go unacknowledged before we give up on the connection (RFC 5482)
+    user_timeout: Option<Duration>,
+    // why the connection was torn down, if it was. nothing more is sent or received after that
+    aborted: Option<AbortReason>,
+    // the last ICMP error we heard about, which is what went wrong if we time out
+    soft_error: Option<AbortReason>,
+    // the application is done with the connection, so once it's finished, nothing needs it
+    released: bool,
+    // the application has thrown the connection away, and the peer is to get a reset for it
+    reset_due: bool,
+}
+
+// Keepalives (RFC 1122 S4.2.3.6)
+//
+// A peer that crashes or drops off the network doesn't tell us, and on a connection where neither
+// side has anything to say, nothing would ever find out. Once a connection has been idle for a
+// while, we send the peer a segment it has to ack; if enough of them go unanswered, we give up on
+// it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    // how long the connection has to be idle before the first probe
+    pub idle: Duration,
+    // how long to wait for an answer to each probe before sending the next
+    pub interval: Duration,
+    // how many probes can go unanswered before the connection is aborted
+    pub probes: u32,
+}
+
+impl Default for Keepalive {
+    // RFC 1122 says the idle time must default to no less than two hours. the rest are as Linux
+    // has them
+    fn default() -> Self {
+        Keepalive {
+            idle: Duration::from_secs(2 * 60 * 60),
+            interval: Duration::from_secs(75),
+            probes: 9,
+        }
+    }
+}
+
+// Why a connection was torn down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbortReason {
+    // the peer stopped answering
+    TimedOut,
+    // the peer reset an established connection
+    Reset,
+    // the peer reset the connection before it was established (or said over ICMP that there was
+    // nothing there to connect to)
+    Refused,
+    // ICMP said the peer couldn't be reached, and it never answered
+    Unreachable,
+}
+
+impl From<AbortReason> for io::Error {
+    fn from(reason: AbortReason) -> Self {
+        match reason {
+            AbortReason::TimedOut => {
+                io::Error::new(io::ErrorKind::TimedOut, "connection timed out")
+            }
+            AbortReason::Reset => {
+                io::Error::new(io::ErrorKind::ConnectionReset, "connection reset by peer")
+            }
+            AbortReason::Refused => {
+                io::Error::new(io::ErrorKind::ConnectionRefused, "connection refused")
+            }
+            AbortReason::Unreachable => {
+                io::Error::new(io::ErrorKind::HostUnreachable, "no route to host")
+            }
+        }
+    }
 }
 
 // the send sequence space is the list of positions of the data we have sent
@@ -39,296 +213,3136 @@ pub struct TcpState {
 // wnd is the size of data that is sent at one time, so between una and una+wnd, we can keep
 // sending more data, but once we reach that, we must stop and wait for a response
 // everything after una+wnd cannot be sent yet
+//
+// max isn't part of the RFC: after a retransmission timeout we go back and send everything again
+// from una, which drags nxt back with it, so max remembers how far we had actually got (SND.MAX in
+// BSD). acks up to max are still acceptable
+//
+// up is the urgent pointer: just past the last byte of urgent data the application has written
+// (RFC 6093 S3), until all of it has been acked
 #[derive(Copy, Clone, Debug)]
 pub struct SendSequence {
     una: u32,
     nxt: u32,
+    max: u32,
     wnd: u16,
-    up: bool,
-    wl1: usize,
-    wl2: usize,
+    up: Option<u32>,
+    wl1: u32,
+    wl2: u32,
     iss: u32,
+    // the largest window the peer has ever offered us, as a rough idea of its buffer size
+    max_wnd: u16,
 }
 
+// up is just past the last byte of urgent data the peer has sent, until the application has read
+// past it
 #[derive(Copy, Clone, Debug)]
 pub struct RecieveSequence {
     nxt: u32,
     wnd: u16,
-    up: bool,
+    up: Option<u32>,
+    #[allow(dead_code)]
     irs: u32,
 }
 
-impl TcpState {
-    pub fn accept<'a>(
-        //self,
-        nic: &mut tun_tap::Iface,
-        ip_header: etherparse::Ipv4HeaderSlice,
-        tcp_header: etherparse::TcpHeaderSlice,
-        data: &'a [u8],
-    ) -> io::Result<Option<Self>> {
-        let mut buf = [0u8; 1500];
+// Round trip time estimation, and the retransmission timer (RFC 6298)
+struct Timers {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    // when the retransmission timer goes off, if it is running
+    retransmit_at: Option<Instant>,
+    // the segment we are currently timing: once SND.UNA gets past this sequence number, we can take
+    // a round trip sample. only one segment is timed at once, and never a retransmitted one, as
+    // there'd be no telling which transmission the ack was for (Karn's algorithm)
+    rtt_probe: Option<(u32, Instant)>,
+    // with a pacing controller, the earliest the next new segment may go out
+    send_at: Option<Instant>,
+    // when we stop waiting for the peer's window to open far enough to be worth sending into
+    sws_override_at: Option<Instant>,
+    // when we next probe a closed window, and how many probes have gone unanswered
+    persist_at: Option<Instant>,
+    persist_backoff: u32,
+    // when we last heard anything from the peer, and how many keepalives it has ignored since
+    last_heard: Instant,
+    keepalives_sent: u32,
+    // how many times in a row the retransmission timer has gone off
+    retransmissions: u32,
+    // when the data at SND.UNA was sent, or last saw the peer ack something -- whichever was later
+    unacked_since: Option<Instant>,
+    // when TIME-WAIT is over, and the connection with it
+    time_wait_until: Option<Instant>,
+}
 
-        let _source_address = ip_header.source_addr();
-        let source_port = tcp_header.source_port();
-        let _destination_address = ip_header.destination_addr();
-        let destination_port = tcp_header.destination_port();
-        let _payload_size = data.len();
+impl Timers {
+    fn new(now: Instant) -> Self {
+        Timers {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+            retransmit_at: None,
+            rtt_probe: None,
+            send_at: None,
+            sws_override_at: None,
+            persist_at: None,
+            persist_backoff: 0,
+            last_heard: now,
+            keepalives_sent: 0,
+            retransmissions: 0,
+            unacked_since: None,
+            time_wait_until: None,
+        }
+    }
 
-        if !tcp_header.syn() {
-            // got unexpected non-SYN packet
-            Result::Ok(None)
-        } else {
-            // we have received a SYN packet, and we can start to establish a connection by
-            // returning a SYN,ACK packet
-
-            let iss = 0;
-            let wnd = 8;
-            let mut connection = TcpState {
-                connection_state: ConnectionState::SynRcvd,
-                recieve: RecieveSequence {
-                    nxt: tcp_header.sequence_number() + 1,
-                    irs: tcp_header.sequence_number(),
-                    wnd: tcp_header.window_size(),
-                    up: false,
-                },
-                send: SendSequence {
-                    iss,
-                    una: iss,
-                    nxt: iss,
-                    wnd,
-                    up: false,
-                    wl1: 0,
-                    wl2: 0,
-                },
-                ip: etherparse::Ipv4Header::new(
-                    0,
-                    64,
-                    etherparse::ip_number::TCP,
-                    [
-                        ip_header.destination()[0],
-                        ip_header.destination()[1],
-                        ip_header.destination()[2],
-                        ip_header.destination()[3],
-                    ],
-                    [
-                        ip_header.source()[0],
-                        ip_header.source()[1],
-                        ip_header.source()[2],
-                        ip_header.source()[3],
-                    ],
-                ),
-                tcp: etherparse::TcpHeader::new(destination_port, source_port, iss, wnd),
-            };
+    fn on_rtt_sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                // (2.2) the first measurement
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                // (2.3) RTTVAR <- (1 - beta) * RTTVAR + beta * |SRTT - R'|
+                //       SRTT <- (1 - alpha) * SRTT + alpha * R'
+                // with alpha = 1/8, beta = 1/4
+                self.rttvar = self.rttvar * 3 / 4 + srtt.abs_diff(rtt) / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+
+        let srtt = self.srtt.expect("we've just taken a sample");
+        self.rto =
+            (srtt + std::cmp::max(CLOCK_GRANULARITY, 4 * self.rttvar)).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    fn back_off(&mut self) {
+        // (5.5) RTO <- RTO * 2
+        self.rto = std::cmp::min(self.rto * 2, MAX_RTO);
+    }
+
+    // the persist timer goes by the RTO, backing off the same way for each unanswered probe
+    fn arm_persist(&mut self, now: Instant) {
+        let timeout = self
+            .rto
+            .checked_mul(1 << std::cmp::min(self.persist_backoff, 16))
+            .map_or(MAX_RTO, |timeout| std::cmp::min(timeout, MAX_RTO));
+        self.persist_at = Some(now + timeout);
+    }
+}
 
-            // keep track of sender info
+// Delayed acks (RFC 1122 S4.2.3.2)
+//
+// Acking every segment as it arrives doubles the number of packets on the wire for a one-way
+// transfer, and for request/response traffic the ack usually goes out a moment before the response
+// that could have carried it. So we hold acks back for a little while: every segment we send
+// carries an ack anyway, and if two full-sized segments arrive before we have sent anything, one
+// ack covers both.
+struct DelayedAck {
+    // how many full-sized segments have arrived since we last sent an ack
+    segments: u32,
+    // when the ack we're holding back has to go, if we're holding one back
+    ack_at: Option<Instant>,
+    // how long we may hold one back for -- zero acks everything straight away
+    delay: Duration,
+}
+
+impl DelayedAck {
+    fn new() -> Self {
+        DelayedAck {
+            segments: 0,
+            ack_at: None,
+            delay: DEFAULT_ACK_DELAY,
+        }
+    }
+}
+
+// Explicit congestion notification (RFC 3168)
+//
+// A router that would otherwise have to drop a packet to let us know the network is congested can
+// mark it instead, so long as both ends said in the handshake that they'd understand. The receiver
+// echoes the mark back on its acks (ECE) until the sender says it has slowed down (CWR), and the
+// sender slows down just as if the packet had been lost -- without having to send it again.
+#[derive(Default)]
+struct EcnState {
+    // both ends asked for it
+    enabled: bool,
+    // a marked segment has arrived, and the peer hasn't yet told us it has slowed down
+    echo: bool,
+    // we've slowed down, and the next new data we send should say so
+    send_cwr: bool,
+    // SND.MAX when we last slowed down: further echoes don't count until everything in flight
+    // then has been acked, as they're likely about the same congestion
+    recover: Option<u32>,
+}
 
-            // establish what it is we will be sending back
+// Challenge acks (RFC 5961)
+//
+// Someone who knows a connection's addresses and ports, but can't see its packets, can still guess
+// at sequence numbers -- and only has to land somewhere in the window for a reset or SYN to tear
+// the connection down, or for data to be taken in. So segments that are plausible but not quite
+// right get an ack in reply, rather than being acted on. The real peer answers that with whatever
+// it meant to do in the first place; a blind attacker never sees it. Those acks are rate limited
+// across every connection, so they can't be used to make us flood the network either.
+#[derive(Default)]
+pub struct ChallengeAckLimit {
+    // when the current second started, and how many have gone out in it
+    second_started: Option<Instant>,
+    sent: u32,
+}
 
-            // need to start establishing a connection
+impl ChallengeAckLimit {
+    fn allow(&mut self, now: Instant) -> bool {
+        if self
+            .second_started
+            .is_none_or(|at| now >= at + Duration::from_secs(1))
+        {
+            self.second_started = Some(now);
+            self.sent = 0;
+        }
+        if self.sent >= CHALLENGE_ACK_LIMIT {
+            return false;
+        }
+        self.sent += 1;
+        true
+    }
+}
 
-            //connection.tcp.acknowledgment_number = connection.recieve.nxt;
-            connection.tcp.syn = true;
-            connection.tcp.ack = true;
+// What an ICMP error (RFC 792, RFC 4443) says about one of our segments
+//
+// Most are soft errors (RFC 1122 S4.2.3.9): a router couldn't get a segment through, but that may
+// well change, so we keep trying -- they only matter if we end up giving up anyway, as the reason
+// why. Hearing that the peer doesn't do TCP, or isn't listening on the port, is a hard error, but
+// only while we're still trying to connect: after that, nothing but the peer itself can end the
+// connection (RFC 5461 S4).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IcmpError {
+    Unreachable,
+    Refused,
+    // a segment was too big for the path, whose MTU is this
+    PacketTooBig(u32),
+}
 
-            connection.write(nic, &[])?;
+// sequence numbers wrap, so "less than" has to mean "less than, going the short way round"
+// (RFC 1323 S4.2.1)
+pub(crate) fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
+    lhs.wrapping_sub(rhs) > (1 << 31)
+}
 
-            Ok(Some(connection))
+// the contents of the first option of the given kind in a segment. etherparse gives up at the
+// first option it doesn't know, so we walk them ourselves
+fn find_option<'a>(tcp_header: &'a etherparse::TcpHeaderSlice, kind: u8) -> Option<&'a [u8]> {
+    let mut options = tcp_header.options();
+    loop {
+        match *options.first()? {
+            OPTION_END => return None,
+            OPTION_NOP => options = &options[1..],
+            found => {
+                let len = *options.get(1)? as usize;
+                if len < 2 || len > options.len() {
+                    return None;
+                }
+                if found == kind {
+                    return Some(&options[2..len]);
+                }
+                options = &options[len..];
+            }
         }
+    }
+}
 
-        // eprintln!("{source_address}:{source_port} -> {destination_address}:{destination_port} {payload_size}b of tcp");
+// how much the peer said it could take in one segment, going by the MSS option on its SYN
+fn peer_mss(tcp_header: &etherparse::TcpHeaderSlice) -> u32 {
+    find_option(tcp_header, OPTION_MSS)
+        .and_then(|mss| mss.try_into().ok())
+        .map_or(DEFAULT_MSS, |mss| u16::from_be_bytes(mss) as u32)
+}
+
+impl TcpState {
+    fn new(
+        connection_state: ConnectionState,
+        local: (IpAddr, u16),
+        remote: (IpAddr, u16),
+        iss: u32,
+        congestion: Algorithm,
+        now: Instant,
+    ) -> Self {
+        let wnd = RECV_QUEUE_SIZE as u16;
+        let ip = ip::Header::new(local.0, remote.0);
+        let mss = ip.mss();
+        let path_mtu = path_mtu::PathMtu::new(ip.base_mtu(), MTU as u32);
+        TcpState {
+            connection_state,
+            recieve: RecieveSequence {
+                nxt: 0,
+                irs: 0,
+                wnd,
+                up: None,
+            },
+            send: SendSequence {
+                iss,
+                una: iss,
+                nxt: iss,
+                max: iss,
+                wnd: 0,
+                up: None,
+                wl1: 0,
+                wl2: 0,
+                max_wnd: 0,
+            },
+            ip,
+            tcp: etherparse::TcpHeader::new(local.1, remote.1, iss, wnd),
+            timers: Timers::new(now),
+            delayed_ack: DelayedAck::new(),
+            ecn: EcnState::default(),
+            nodelay: false,
+            mss,
+            peer_mss: mss,
+            path_mtu,
+            probe_dup_acks: 0,
+            algorithm: congestion,
+            congestion: congestion.build(mss),
+            incoming: VecDeque::new(),
+            unacked: VecDeque::new(),
+            closed_at: None,
+            recv_shutdown: false,
+            fast_open_option: None,
+            fast_open_cookie: None,
+            keepalive: None,
+            user_timeout: None,
+            aborted: None,
+            soft_error: None,
+            released: false,
+            reset_due: false,
+        }
     }
 
-    fn write(&mut self, nic: &mut tun_tap::Iface, payload: &[u8]) -> io::Result<usize> {
-        let mut buf = [0u8; 1500];
-        self.tcp.sequence_number = self.send.nxt;
-        self.tcp.acknowledgment_number = self.recieve.nxt;
+    // an active open: our SYN goes out the next time the connection is ticked
+    pub fn connect(
+        local: (IpAddr, u16),
+        remote: (IpAddr, u16),
+        iss: u32,
+        congestion: Algorithm,
+        now: Instant,
+    ) -> Self {
+        TcpState::new(
+            ConnectionState::SynSent,
+            local,
+            remote,
+            iss,
+            congestion,
+            now,
+        )
+    }
 
-        let size = std::cmp::min(
-            buf.len(),
-            self.tcp.header_len() as usize + self.ip.header_len() + payload.len(),
+    // the connection a SYN asks for, in SYN-RECEIVED with our SYN-ACK still to go
+    fn syn_received(
+        ip_header: &IpHeader,
+        tcp_header: &etherparse::TcpHeaderSlice,
+        iss: u32,
+        congestion: Algorithm,
+        now: Instant,
+    ) -> Self {
+        let mut connection = TcpState::new(
+            ConnectionState::SynRcvd,
+            (ip_header.destination(), tcp_header.destination_port()),
+            (ip_header.source(), tcp_header.source_port()),
+            iss,
+            congestion,
+            now,
         );
-        self.ip.set_payload_len(size - self.ip.header_len());
+        connection.recieve.nxt = tcp_header.sequence_number().wrapping_add(1);
+        connection.recieve.irs = tcp_header.sequence_number();
+        connection.send.wnd = tcp_header.window_size();
+        connection.send.max_wnd = tcp_header.window_size();
+        connection.send.wl1 = tcp_header.sequence_number();
+        connection.set_mss(peer_mss(tcp_header));
+        // an ECN-setup SYN has both ECE and CWR set (RFC 3168 S6.1.1)
+        connection.ecn.enabled = tcp_header.ece() && tcp_header.cwr();
+        connection.tcp.ack = true;
+        connection
+    }
 
-        // the kernel does the checksum for us !
-        self.tcp.checksum = self
-            .tcp
-            .calc_checksum_ipv4(&self.ip, &[])
-            .expect("failed to compute checksum");
-
-        // write the headers to a buffer, then send everything written, and exclude any
-        // empty part of the buffer
-
-        use std::io::Write;
-        let mut unwritten = &mut buf[..];
-        self.ip.write(&mut unwritten);
-        self.tcp.write(&mut unwritten);
-        let payload_bytes = unwritten.write(payload)?;
-        let unwritten = unwritten.len();
-
-        self.send.nxt = self.send.nxt.wrapping_add(payload_bytes as u32);
-        if self.tcp.syn {
-            self.send.nxt = self.send.nxt.wrapping_add(1);
-            self.tcp.syn = false;
+    // fast_open is how we check (and hand out) fast open cookies, if the listener has it turned on
+    #[allow(clippy::too_many_arguments)]
+    pub fn accept<N: Nic>(
+        nic: &mut N,
+        ip_header: IpHeader,
+        tcp_header: etherparse::TcpHeaderSlice,
+        data: &[u8],
+        iss: u32,
+        congestion: Algorithm,
+        fast_open: Option<&FastOpenCookies>,
+        now: Instant,
+    ) -> io::Result<Option<Self>> {
+        if !tcp_header.syn() {
+            // got unexpected non-SYN packet
+            return Ok(None);
         }
-        if self.tcp.fin {
-            self.send.nxt = self.send.nxt.wrapping_add(1);
-            self.tcp.fin = false;
+
+        // we have received a SYN packet, and we can start to establish a connection by returning
+        // a SYN,ACK packet
+        let mut connection = TcpState::syn_received(&ip_header, &tcp_header, iss, congestion, now);
+
+        if let (Some(cookies), Some(cookie)) =
+            (fast_open, find_option(&tcp_header, OPTION_FAST_OPEN))
+        {
+            if cookies.validate(ip_header.source(), cookie) {
+                // the client has been here before, so whatever it sent with its SYN can be read
+                // straight away. our SYN-ACK acks it
+                connection.incoming.extend(data);
+                connection.recieve.nxt = connection.recieve.nxt.wrapping_add(data.len() as u32);
+                connection.recieve.wnd = connection.recieve.wnd.saturating_sub(data.len() as u16);
+            } else {
+                // it's asking for a cookie, or has one that's no good any more: give it a new one,
+                // and it can send its data again once the handshake is done
+                connection.fast_open_option = Some(cookies.cookie(ip_header.source()).to_vec());
+            }
         }
 
-        //eprintln!("{:02x?}", &buf[..buf.len() - unwritten]);
+        // (the SYN goes out because nothing has been sent yet)
+        connection.transmit(nic, now)?;
 
-        nic.send(&buf[..buf.len() - unwritten])?;
-        Ok(payload_bytes)
+        Ok(Some(connection))
     }
 
-    pub fn snd_rst(&mut self, nic: &mut tun_tap::Iface) -> io::Result<()> {
-        self.tcp.rst = true;
-        self.tcp.sequence_number = 0;
-        self.tcp.acknowledgment_number = 0;
-        self.write(nic, &[])?;
-        Ok(())
+    // whether answering this SYN with a cookie loses nothing it asks for. a cookie only has room
+    // for the MSS, so not for ECN, or (if the listener takes it) fast open
+    pub fn fits_in_syn_cookie(tcp_header: &etherparse::TcpHeaderSlice, fast_open: bool) -> bool {
+        let ecn = tcp_header.ece() && tcp_header.cwr();
+        let fast_open = fast_open && find_option(tcp_header, OPTION_FAST_OPEN).is_some();
+        !ecn && !fast_open
     }
 
-    pub fn on_packet<'a>(
-        &mut self,
-        nic: &mut tun_tap::Iface,
-        _ip_header: etherparse::Ipv4HeaderSlice,
+    // answer a SYN without keeping any state for it: the SYN-ACK's ISN is a cookie, which the peer
+    // gives back to us in its ack if it wants the connection
+    pub fn send_syn_cookie<N: Nic>(
+        nic: &mut N,
+        ip_header: IpHeader,
         tcp_header: etherparse::TcpHeaderSlice,
-        data: &'a [u8],
+        cookies: &mut SynCookies,
+        now: Instant,
     ) -> io::Result<()> {
-        // acceptable ack check (RFC 793 S3.3)
-        // SND.UNA < SEG.ACK =< SND.NXT (but it wraps !)
+        let iss = cookies.generate(
+            (ip_header.destination(), tcp_header.destination_port()),
+            (ip_header.source(), tcp_header.source_port()),
+            tcp_header.sequence_number(),
+            peer_mss(&tcp_header),
+            now,
+        );
+        // (the connection only lives long enough to send the SYN-ACK). the cookie has no room to
+        // remember ECN, so don't agree to it
+        let mut connection =
+            TcpState::syn_received(&ip_header, &tcp_header, iss, Algorithm::default(), now);
+        connection.ecn.enabled = false;
+        connection.transmit(nic, now)
+    }
 
-        let mut slen = data.len();
-        if tcp_header.fin() {
-            slen += 1
-        };
-        if tcp_header.syn() {
-            slen += 1
-        };
+    // a segment for a connection we don't have: if it's the last ack of a handshake we answered
+    // with a SYN cookie, set the connection up from what the cookie remembers. the segment itself
+    // is still to be passed on to it, as if the connection had been there all along
+    pub fn from_syn_cookie(
+        ip_header: &IpHeader,
+        tcp_header: &etherparse::TcpHeaderSlice,
+        cookies: &SynCookies,
+        congestion: Algorithm,
+        now: Instant,
+    ) -> Option<Self> {
+        if !tcp_header.ack() || tcp_header.syn() || tcp_header.rst() {
+            return None;
+        }
 
-        // valid segment check
-        // RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
-        let nxt = self.recieve.nxt;
-        let seq = tcp_header.sequence_number();
-        let end = self.recieve.nxt.wrapping_add(self.recieve.wnd as u32);
-        let seq_end = tcp_header.sequence_number().wrapping_add(slen as u32 - 1);
+        let local = (ip_header.destination(), tcp_header.destination_port());
+        let remote = (ip_header.source(), tcp_header.source_port());
+        let iss = tcp_header.acknowledgment_number().wrapping_sub(1);
+        let irs = tcp_header.sequence_number().wrapping_sub(1);
+        let mss = cookies.validate(local, remote, irs, iss, now)?;
 
-        let okay = if slen == 0 {
-            //zero-length segment rules
-            if self.recieve.wnd == 0 {
-                if seq != self.recieve.nxt {
-                    false
-                } else {
-                    true
-                }
-            } else if !(nxt <= seq && (seq < end || (seq > end && nxt > end))
-                || seq < end && nxt > end)
-            {
-                false
-            } else {
-                true
-            }
-        } else if self.recieve.wnd == 0
-            || !(nxt <= seq_end && (seq_end < end || (seq_end > end && nxt > end))
-                || seq_end < end && nxt > end)
-        {
-            false
+        let mut connection = TcpState::new(
+            ConnectionState::SynRcvd,
+            local,
+            remote,
+            iss,
+            congestion,
+            now,
+        );
+        connection.recieve.nxt = irs.wrapping_add(1);
+        connection.recieve.irs = irs;
+        // our SYN-ACK has already gone
+        connection.send.nxt = iss.wrapping_add(1);
+        connection.send.max = iss.wrapping_add(1);
+        connection.send.wl1 = irs;
+        connection.set_mss(mss);
+        connection.tcp.ack = true;
+        Some(connection)
+    }
+
+    // the peer has sent its FIN (or the application doesn't want anything more), so nothing more
+    // will ever arrive
+    pub fn is_recv_closed(&self) -> bool {
+        self.recv_shutdown
+            || matches!(
+                self.connection_state,
+                ConnectionState::CloseWait
+                    | ConnectionState::LastAck
+                    | ConnectionState::Closing
+                    | ConnectionState::TimeWait
+                    | ConnectionState::Closed
+            )
+    }
+
+    // the application has finished writing: our FIN goes out after whatever it has written so far
+    // (the peer can carry on sending to us until it closes too)
+    pub fn close(&mut self) {
+        if self.closed_at.is_some() {
+            return;
+        }
+
+        self.closed_at = Some(self.end_of_data());
+
+        match self.connection_state {
+            ConnectionState::Estab => self.connection_state = ConnectionState::FinWait1,
+            ConnectionState::CloseWait => self.connection_state = ConnectionState::LastAck,
+            // still in the handshake -- we move on to FIN-WAIT-1 once that's done
+            _ => {}
+        }
+    }
+
+    // the sequence number just past everything the application has written
+    fn end_of_data(&self) -> u32 {
+        // before our SYN has been acked, the data starts just after it
+        let data_starts_at = if self.connection_state.is_synchronized() {
+            self.send.una
         } else {
-            true
+            self.send.iss.wrapping_add(1)
         };
+        data_starts_at.wrapping_add(self.unacked.len() as u32)
+    }
 
-        if !okay {
-            self.write(nic, &[])?;
-            return Ok(());
+    // everything the application has written so far is urgent: the peer is told where it ends
+    // straight away, even if the data itself has to wait for the window to open
+    pub fn mark_urgent(&mut self) {
+        self.send.up = Some(self.end_of_data());
+    }
+
+    // how much more there is to read before getting past the peer's urgent data, if it has sent
+    // some the application hasn't read past yet. zero means the last read got to the end of it
+    pub fn urgent_mark(&self) -> Option<usize> {
+        let read_from = self.recieve.nxt.wrapping_sub(self.incoming.len() as u32);
+        let up = self.recieve.up?;
+        if wrapping_lt(up, read_from) {
+            return None;
         }
-        self.recieve.nxt = tcp_header.sequence_number().wrapping_add(slen as u32);
-        // TODO: we've gotta ACK this !
+        Some(up.wrapping_sub(read_from) as usize)
+    }
 
-        if !tcp_header.ack() {
+    // take some of the data the peer has sent. a read never goes past the end of urgent data, so
+    // the application can tell where that is
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let limit = match self.urgent_mark() {
+            Some(0) => {
+                // the last read got to it, so this one moves past it
+                self.recieve.up = None;
+                buf.len()
+            }
+            Some(mark) => std::cmp::min(buf.len(), mark),
+            None => buf.len(),
+        };
+        self.incoming
+            .read(&mut buf[..limit])
+            .expect("reading from memory can't fail")
+    }
+
+    // the application won't read any more: throw away whatever it hasn't read, and anything still
+    // to come
+    pub fn shutdown_read(&mut self) {
+        self.recv_shutdown = true;
+        self.incoming.clear();
+    }
+
+    // there's nothing more to do with the connection, one way or the other
+    pub fn is_finished(&self) -> bool {
+        self.aborted.is_some() || matches!(self.connection_state, ConnectionState::Closed)
+    }
+
+    // the application won't touch the connection again, so it can go once it's finished
+    pub fn release(&mut self) {
+        self.released = true;
+    }
+
+    pub fn is_released(&self) -> bool {
+        self.released
+    }
+
+    // the ABORT call (RFC 9293 S3.10.5): the peer gets a reset on the next tick, and after that the
+    // connection is finished, and let go of
+    pub fn reset(&mut self) {
+        self.reset_due = true;
+        self.released = true;
+    }
+
+    // (re)start the 2MSL timer: a FIN the peer sends again means it didn't get our ack, which we
+    // have to be around to send again (RFC 9293 S3.10.7.4)
+    fn enter_time_wait(&mut self, now: Instant) {
+        self.connection_state = ConnectionState::TimeWait;
+        self.timers.time_wait_until = Some(now + 2 * MSL);
+    }
+
+    // we have sent (or are about to send) our FIN, so nothing more may be written
+    pub fn is_send_closed(&self) -> bool {
+        self.closed_at.is_some()
+    }
+
+    pub fn aborted(&self) -> Option<AbortReason> {
+        self.aborted
+    }
+
+    // stop doing anything more with the connection
+    fn abort(&mut self, reason: AbortReason) {
+        self.aborted = Some(reason);
+    }
+
+    // we've given up on the peer. it probably isn't listening, but tell it in case it is
+    fn time_out<N: Nic>(&mut self, nic: &mut N, now: Instant) -> io::Result<()> {
+        self.snd_rst(nic, now, self.send.nxt)?;
+        self.abort(self.soft_error.unwrap_or(AbortReason::TimedOut));
+        Ok(())
+    }
+
+    // an ICMP error about the segment of ours that starts at seq. only one we've sent that hasn't
+    // been acked yet, so anyone trying to forge one has to guess at sequence numbers (RFC 5927 S4.1)
+    pub fn on_icmp_error<N: Nic>(
+        &mut self,
+        nic: &mut N,
+        error: IcmpError,
+        seq: u32,
+        now: Instant,
+    ) -> io::Result<()> {
+        if self.is_finished() || wrapping_lt(seq, self.send.una) || !wrapping_lt(seq, self.send.max)
+        {
             return Ok(());
         }
 
-        //self.tcp.fin = true;
-        //self.write(nic, &[])?;
-        //self.connection_state = ConnectionState::FinWait1;
-        let una = self.send.una;
-        let ack = tcp_header.acknowledgment_number();
-        if let ConnectionState::SynRcvd = self.connection_state {
-            if (una <= ack && (ack <= nxt || (ack >= nxt && una > nxt)) || ack <= nxt && una > nxt)
-            {
-                self.connection_state = ConnectionState::Estab;
-            } else {
-                // reset
+        match error {
+            IcmpError::Refused if !self.connection_state.is_synchronized() => {
+                self.abort(AbortReason::Refused);
+            }
+            IcmpError::Refused => self.soft_error = Some(AbortReason::Refused),
+            IcmpError::Unreachable => self.soft_error = Some(AbortReason::Unreachable),
+            IcmpError::PacketTooBig(mtu) => {
+                let mtu = self.ip.clamp_mtu(mtu);
+                if !self.path_mtu.on_packet_too_big(mtu, now) {
+                    return Ok(());
+                }
+                // whatever we have in flight may be too big to get there, so send it all again in
+                // smaller segments. it was never lost to congestion, so nothing else changes
+                // (RFC 1191 S6.4)
+                self.update_mss();
+                self.timers.rtt_probe = None;
+                self.send.nxt = self.send.una;
+                self.transmit(nic, now)?;
             }
         }
+        Ok(())
+    }
 
-        if let ConnectionState::Estab | ConnectionState::FinWait1 | ConnectionState::FinWait2 =
-            self.connection_state
-        {
-            let nxt = self.send.nxt;
+    // the path MTU to the peer, as far as we know
+    pub fn path_mtu(&self) -> u32 {
+        self.path_mtu.mtu()
+    }
 
-            if !(una < ack && (ack <= nxt || (ack >= nxt && una > nxt)) || ack <= nxt && una > nxt)
-            {
-                //if !self.connection_state.is_synchronized() {
-                // send reset, as per spec
-                // self.send.nxt = tcp_header.acknowledgment_number();
-                //    self.snd_rst(nic)?;
-                //}
-                return Ok(());
-            };
+    // start out with a path MTU we already know about, from another connection to the same place
+    pub fn set_path_mtu(&mut self, mtu: u32, now: Instant) {
+        self.path_mtu.on_packet_too_big(self.ip.clamp_mtu(mtu), now);
+        self.update_mss();
+    }
+
+    pub fn set_user_timeout(&mut self, user_timeout: Option<Duration>) {
+        self.user_timeout = user_timeout;
+    }
+
+    fn user_timeout_at(&self) -> Option<Instant> {
+        Some(self.timers.unacked_since? + self.user_timeout?)
+    }
+
+    pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>) {
+        self.keepalive = keepalive;
+    }
+
+    // when the next keepalive is due, if the connection is idle and has them turned on. while we
+    // have data outstanding, the retransmission timer does the job instead
+    fn keepalive_at(&self) -> Option<Instant> {
+        let keepalive = self.keepalive?;
+        let idle = matches!(
+            self.connection_state,
+            ConnectionState::Estab | ConnectionState::FinWait2 | ConnectionState::CloseWait
+        ) && self.unacked.is_empty();
+        if !idle {
+            return None;
+        }
+        Some(
+            self.timers.last_heard
+                + keepalive.idle
+                + keepalive.interval * self.timers.keepalives_sent,
+        )
+    }
+
+    // switching controllers part way through starts the new one from scratch, as if this were a
+    // new connection
+    pub fn set_congestion_control(&mut self, algorithm: Algorithm) {
+        self.algorithm = algorithm;
+        self.congestion = algorithm.build(self.mss);
+    }
 
-            self.send.una = tcp_header.acknowledgment_number();
+    // we've found out how much the peer can take in one segment, which is only ever during the
+    // handshake, so the congestion controller can start over with it
+    fn set_mss(&mut self, mss: u32) {
+        self.peer_mss = mss;
+        self.update_mss();
+        self.congestion = self.algorithm.build(self.mss);
+    }
+
+    fn update_mss(&mut self) {
+        self.mss = std::cmp::min(self.peer_mss, self.ip.mss_for(self.path_mtu.mtu()));
+    }
+
+    // ask for fast open on an active open. with a cookie from an earlier connection to the same
+    // server, our SYN carries whatever has been written so far; without one, it asks for one
+    pub fn set_fast_open(&mut self, cookie: Option<Vec<u8>>) {
+        self.fast_open_option = Some(cookie.unwrap_or_default());
+    }
+
+    // the fast open cookie the server gave us, once the handshake is done
+    pub fn fast_open_cookie(&self) -> Option<&[u8]> {
+        self.fast_open_cookie.as_deref()
+    }
+
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.nodelay = nodelay;
+    }
+
+    // how long an ack may be held back for, in the hope of sending it along with some data
+    pub fn set_ack_delay(&mut self, delay: Duration) {
+        self.delayed_ack.delay = std::cmp::min(delay, MAX_ACK_DELAY);
+    }
 
-            assert!(data.is_empty());
+    // send the segment starting at seq, carrying up to limit bytes of data from unacked
+    fn write<N: Nic>(
+        &mut self,
+        nic: &mut N,
+        now: Instant,
+        seq: u32,
+        limit: usize,
+    ) -> io::Result<usize> {
+        let mut buf = [0u8; MTU];
+        self.tcp.sequence_number = seq;
+        self.tcp.acknowledgment_number = self.recieve.nxt;
+        self.update_recv_window();
+        self.tcp.window_size = self.recieve.wnd;
 
-            if let ConnectionState::Estab = self.connection_state {
-                self.tcp.fin = true;
-                self.write(nic, &[])?;
-                self.connection_state = ConnectionState::FinWait1;
+        // our SYN takes up the first sequence number, and carries no data (except with fast open).
+        // it tells the peer how much we can take in one segment
+        self.tcp.syn =
+            !self.tcp.rst && !self.connection_state.is_synchronized() && seq == self.send.iss;
+        let mut options = Vec::new();
+        if self.tcp.syn {
+            options.extend([OPTION_MSS, 4]);
+            options.extend((self.ip.mss() as u16).to_be_bytes());
+            if let Some(cookie) = &self.fast_open_option {
+                options.extend([OPTION_FAST_OPEN, 2 + cookie.len() as u8]);
+                options.extend(cookie);
             }
         }
+        self.tcp
+            .set_options_raw(&options)
+            .expect("options always fit in a tcp header");
 
-        if let ConnectionState::FinWait1 = self.connection_state {
-            if self.send.una == self.send.iss + 2 {
-                // our fin has been ack'd
+        // work out which part of unacked this segment covers
+        let (offset, limit) = if self.tcp.syn {
+            (0, limit)
+        } else if self.connection_state.is_synchronized() {
+            (seq.wrapping_sub(self.send.una) as usize, limit)
+        } else {
+            (
+                seq.wrapping_sub(self.send.iss.wrapping_add(1)) as usize,
+                limit,
+            )
+        };
 
-                // they must have ack'd our fin ! (it's all that we have sent)
-                self.connection_state = ConnectionState::FinWait2;
+        let header_size = self.ip.header_len() + self.tcp.header_len() as usize;
+        let payload_size = std::cmp::min(
+            buf.len() - header_size,
+            std::cmp::min(limit, self.unacked.len().saturating_sub(offset)),
+        );
+        // (a window probe comes from before SND.UNA, and never carries data)
+        if payload_size > 0 {
+            for (to, from) in buf[header_size..]
+                .iter_mut()
+                .zip(self.unacked.range(offset..offset + payload_size))
+            {
+                *to = *from;
             }
         }
+        let payload = &buf[header_size..header_size + payload_size];
 
-        if tcp_header.fin() {
-            match self.connection_state {
-                ConnectionState::SynRcvd => unimplemented!(),
-                ConnectionState::Estab => unimplemented!(),
-                ConnectionState::FinWait1 => unimplemented!(),
-                ConnectionState::FinWait2 => {
-                    // we're done
-                    self.write(nic, &[])?;
-                    self.connection_state = ConnectionState::TimeWait;
-                }
-                ConnectionState::Closing => unimplemented!(),
-                ConnectionState::TimeWait => unimplemented!(),
+        // while there's urgent data still to go, every segment tells the peer where it ends, as an
+        // offset from its own sequence number (RFC 9293 S3.8.5)
+        match self.send.up {
+            Some(up) if wrapping_lt(seq, up) && !self.tcp.syn && !self.tcp.rst => {
+                self.tcp.urg = true;
+                self.tcp.urgent_pointer =
+                    std::cmp::min(up.wrapping_sub(seq), u16::MAX as u32) as u16;
+            }
+            _ => {
+                self.tcp.urg = false;
+                self.tcp.urgent_pointer = 0;
             }
         }
 
-        // if let ConnectionState::FinWait2 = self.connection_state {
-        //     if !tcp_header.fin() || !data.is_empty() {
-        //         unimplemented!()
-        //     }
+        // ECN: our SYN asks for it (with ECE and CWR), and a SYN-ACK agrees to it (with just ECE).
+        // after that, ECE echoes a mark back, and CWR goes out with the next new data after we've
+        // slowed down. new data is the only thing routers may mark (RFC 3168 S6.1.4 - S6.1.5)
+        let new_data = !wrapping_lt(seq, self.send.max) && payload_size > 0 && !self.tcp.syn;
+        if self.tcp.syn {
+            let active_open = matches!(self.connection_state, ConnectionState::SynSent);
+            self.tcp.ece = active_open || self.ecn.enabled;
+            self.tcp.cwr = active_open;
+        } else {
+            self.tcp.ece = self.ecn.enabled && self.ecn.echo && !self.tcp.rst;
+            self.tcp.cwr = self.ecn.enabled && self.ecn.send_cwr && new_data && !self.tcp.rst;
+        }
+        let ect = self.ecn.enabled && new_data && !self.tcp.rst;
+        self.ip.set_ecn(if ect { Ecn::Ect0 } else { Ecn::NotEct });
 
-        //     self.tcp.fin = false;
-        //      self.write(nic, &[])?;
-        //      self.connection_state = ConnectionState::Closing;
-        //  }
+        // if this segment gets to the end of the data after we've closed, our FIN goes along too.
+        // not on a probe though, whose sequence number is from before SND.UNA -- which, once our
+        // FIN has been acked, is where the FIN was
+        let probe = self.connection_state.is_synchronized() && wrapping_lt(seq, self.send.una);
+        self.tcp.fin = !self.tcp.syn
+            && !self.tcp.rst
+            && !probe
+            && self.closed_at == Some(seq.wrapping_add(payload_size as u32));
 
-        Ok(())
+        self.ip
+            .set_payload_len(self.tcp.header_len() as usize + payload_size);
+
+        // the kernel does the checksum for us !
+        self.tcp.checksum = self.ip.checksum(&self.tcp, payload);
+
+        // write the headers to the front of the buffer (the payload is already in place), then
+        // send everything written, and exclude any empty part of the buffer
+        let mut unwritten = &mut buf[..header_size];
+        self.ip.write(&mut unwritten)?;
+        self.tcp.write(&mut unwritten)?;
+
+        //eprintln!("{:02x?}", &buf[..header_size + payload_size]);
+
+        nic.send(&buf[..header_size + payload_size])?;
+
+        if self.tcp.cwr {
+            self.ecn.send_cwr = false;
+        }
+        if self.tcp.ack {
+            // whatever ack we were holding back just went out with this segment
+            self.delayed_ack.segments = 0;
+            self.delayed_ack.ack_at = None;
+        }
+
+        let mut next_seq = seq.wrapping_add(payload_size as u32);
+        if self.tcp.syn {
+            next_seq = next_seq.wrapping_add(1);
+        }
+        if self.tcp.fin {
+            next_seq = next_seq.wrapping_add(1);
+        }
+
+        if next_seq != seq {
+            if wrapping_lt(seq, self.send.max) {
+                // a retransmission, so any ack we get can't be trusted for timing
+                self.timers.rtt_probe = None;
+            } else if self.timers.rtt_probe.is_none() {
+                self.timers.rtt_probe = Some((next_seq, now));
+            }
+
+            if wrapping_lt(self.send.nxt, next_seq) {
+                self.send.nxt = next_seq;
+            }
+            if wrapping_lt(self.send.max, next_seq) {
+                self.send.max = next_seq;
+            }
+
+            // (5.1) start the retransmission timer, if it isn't already running
+            if self.timers.retransmit_at.is_none() {
+                self.timers.retransmit_at = Some(now + self.timers.rto);
+            }
+            if self.timers.unacked_since.is_none() {
+                self.timers.unacked_since = Some(now);
+            }
+        }
+
+        Ok(payload_size)
+    }
+
+    // send whatever we're allowed to: our SYN if it hasn't gone yet, as much new data as both the
+    // peer's window and the congestion window allow, and our FIN once all the data has gone
+    fn transmit<N: Nic>(&mut self, nic: &mut N, now: Instant) -> io::Result<()> {
+        if !self.connection_state.is_synchronized() {
+            if self.send.nxt == self.send.iss {
+                // with a fast open cookie, our SYN carries data too -- but not when it's sent again,
+                // in case the data is why it's getting dropped, and no more than any peer has to be
+                // able to take
+                let fast_open = matches!(self.connection_state, ConnectionState::SynSent)
+                    && self
+                        .fast_open_option
+                        .as_ref()
+                        .is_some_and(|cookie| !cookie.is_empty())
+                    && self.timers.retransmissions == 0;
+                let limit = if fast_open { DEFAULT_MSS as usize } else { 0 };
+                self.write(nic, now, self.send.iss, limit)?;
+            }
+            return Ok(());
+        }
+
+        loop {
+            let in_flight = self.send.nxt.wrapping_sub(self.send.una);
+            let unsent = self.unacked.len().saturating_sub(in_flight as usize);
+            if unsent == 0 {
+                if self.closed_at == Some(self.send.nxt) {
+                    self.write(nic, now, self.send.nxt, 0)?;
+                }
+                return Ok(());
+            }
+
+            let window = std::cmp::min(self.congestion.cwnd(), self.send.wnd as u32);
+            let allowed = window.saturating_sub(in_flight) as usize;
+            if allowed == 0 {
+                // the peer's window is closed, and with nothing in flight there's no ack coming to
+                // tell us when it opens again -- that might get lost anyway. so probe it every so
+                // often until it does (RFC 9293 S3.8.6.1)
+                if self.send.wnd == 0 && in_flight == 0 && self.timers.persist_at.is_none() {
+                    self.timers.arm_persist(now);
+                }
+                return Ok(());
+            }
+
+            // sender silly window avoidance (RFC 1122 S4.2.3.4): only send a small segment if
+            // it's all the data we have, or if the peer's window is small because its buffer is
+            let usable = std::cmp::min(unsent, allowed);
+            let send_now = if usable >= self.mss as usize {
+                true
+            } else if usable == unsent {
+                // Nagle's algorithm (RFC 896): while anything is unacknowledged, wait for the ack
+                // (and for the application to write some more in the meantime). the last segment
+                // before our FIN isn't worth holding back, as nothing more is coming to fill it up
+                // urgent data doesn't wait either
+                let carries_fin = self.closed_at == Some(self.send.nxt.wrapping_add(unsent as u32));
+                let urgent = self
+                    .send
+                    .up
+                    .is_some_and(|up| wrapping_lt(self.send.nxt, up));
+                self.nodelay || in_flight == 0 || carries_fin || urgent
+            } else {
+                usable >= self.send.max_wnd as usize / 2
+                    || self.timers.sws_override_at.is_some_and(|at| now >= at)
+            };
+            if !send_now {
+                if usable < unsent && self.timers.sws_override_at.is_none() {
+                    self.timers.sws_override_at = Some(now + SWS_OVERRIDE);
+                }
+                return Ok(());
+            }
+            self.timers.sws_override_at = None;
+
+            let pacing_rate = self.congestion.pacing_rate();
+            if pacing_rate.is_some() && self.timers.send_at.is_some_and(|at| at > now) {
+                // not yet -- on_tick will get back to us
+                return Ok(());
+            }
+
+            let mut limit = std::cmp::min(unsent, std::cmp::min(allowed, self.mss as usize));
+            // every so often, while searching for the path MTU, a segment bigger than the rest: as
+            // long as there's the new data to fill it, and room for it (RFC 4821 S7.4)
+            let seq = self.send.nxt;
+            let probe = match self.path_mtu.next_probe(now) {
+                Some(mtu) if self.send.nxt == self.send.max => {
+                    let size = self.ip.mss_for(mtu);
+                    let fits =
+                        size <= self.peer_mss && size as usize <= std::cmp::min(unsent, allowed);
+                    fits.then_some((mtu, size))
+                }
+                _ => None,
+            };
+            if let Some((_, size)) = probe {
+                limit = size as usize;
+            }
+            let written = self.write(nic, now, seq, limit)?;
+            if let Some((mtu, _)) = probe {
+                self.path_mtu
+                    .on_probe_sent(seq, seq.wrapping_add(written as u32), mtu);
+            }
+
+            if let Some(rate) = pacing_rate {
+                // space segments out by how long each takes to send at the pacing rate. we only get
+                // to run every so often, so let up to a tick's worth of sending build up, but no more
+                // than that, or an idle connection would come back with one big burst
+                let earliest = now.checked_sub(CLOCK_GRANULARITY).unwrap_or(now);
+                let from = self
+                    .timers
+                    .send_at
+                    .map_or(now, |at| std::cmp::max(at, earliest));
+                self.timers.send_at = Some(from + Duration::from_secs_f64(written as f64 / rate));
+            }
+        }
+    }
+
+    // when on_tick next has something to do, so the packet loop knows how long it may wait
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if self.is_finished() {
+            return None;
+        }
+        if let ConnectionState::TimeWait = self.connection_state {
+            return self.timers.time_wait_until;
+        }
+
+        let in_flight = self.send.nxt.wrapping_sub(self.send.una) as usize;
+        let (send_at, sws_override_at) = if self.unacked.len() > in_flight {
+            (self.timers.send_at, self.timers.sws_override_at)
+        } else {
+            (None, None)
+        };
+
+        [
+            self.timers.retransmit_at,
+            send_at,
+            sws_override_at,
+            self.timers.persist_at,
+            self.keepalive_at(),
+            self.user_timeout_at(),
+            self.delayed_ack.ack_at,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    // receiver silly window avoidance (RFC 1122 S4.2.3.3): as data arrives, the window shrinks to
+    // keep its right edge where it was. it only moves right again once the application has read
+    // enough to open it by a full segment (as big as we told the peer it could send us), or half
+    // the buffer, so the peer isn't tempted into sending lots of tiny segments. returns whether it
+    // opened
+    fn update_recv_window(&mut self) -> bool {
+        let free = RECV_QUEUE_SIZE.saturating_sub(self.incoming.len());
+        let step = std::cmp::min(RECV_QUEUE_SIZE / 2, self.ip.mss() as usize);
+        if free >= self.recieve.wnd as usize + step {
+            self.recieve.wnd = free as u16;
+            true
+        } else {
+            false
+        }
+    }
+
+    // we've taken in a segment of new data: ack it now if this makes two full-sized segments we
+    // haven't acked, otherwise give ourselves a little while to find something to send it with
+    fn delay_ack<N: Nic>(&mut self, nic: &mut N, now: Instant, len: usize) -> io::Result<()> {
+        // full-sized is the MSS we advertised, which is less over IPv6. and the peer can't send more
+        // than our window in one go, however large its segments can be
+        if len >= std::cmp::min(self.ip.mss(), self.recieve.wnd as u32) as usize {
+            self.delayed_ack.segments += 1;
+        }
+
+        if self.delayed_ack.segments >= 2 || self.delayed_ack.delay.is_zero() {
+            self.write(nic, now, self.send.nxt, 0)?;
+        } else if self.delayed_ack.ack_at.is_none() {
+            self.delayed_ack.ack_at = Some(now + self.delayed_ack.delay);
+        }
+        Ok(())
+    }
+
+    pub fn on_tick<N: Nic>(&mut self, nic: &mut N, now: Instant) -> io::Result<()> {
+        if self.is_finished() {
+            return Ok(());
+        }
+
+        if self.reset_due {
+            self.connection_state = ConnectionState::Closed;
+            return self.snd_rst(nic, now, self.send.nxt);
+        }
+
+        // everything has been sent and acked both ways, so there's nothing to do but wait out
+        // TIME-WAIT
+        if let ConnectionState::TimeWait = self.connection_state {
+            if self
+                .timers
+                .time_wait_until
+                .is_some_and(|until| now >= until)
+            {
+                self.connection_state = ConnectionState::Closed;
+            }
+            return Ok(());
+        }
+
+        if self.keepalive_at().is_some_and(|at| now >= at) {
+            let keepalive = self.keepalive.expect("keepalives are on");
+            if self.timers.keepalives_sent >= keepalive.probes {
+                return self.time_out(nic, now);
+            }
+            // like a window probe: an old sequence number, so all the peer can do is ack it
+            self.write(nic, now, self.send.una.wrapping_sub(1), 0)?;
+            self.timers.keepalives_sent += 1;
+        }
+
+        if self.user_timeout_at().is_some_and(|at| now >= at) {
+            return self.time_out(nic, now);
+        }
+
+        if let Some(retransmit_at) = self.timers.retransmit_at {
+            if now >= retransmit_at {
+                // a probe that's lost was too big for the path, which says nothing about
+                // congestion (RFC 4821 S7.5)
+                let probe_lost = self.path_mtu.on_retransmit(self.send.una, now);
+                let flight_size = self.send.max.wrapping_sub(self.send.una);
+                if !probe_lost {
+                    self.timers.retransmissions += 1;
+                    let limit = if self.connection_state.is_synchronized() {
+                        MAX_RETRANSMISSIONS
+                    } else {
+                        MAX_SYN_RETRANSMISSIONS
+                    };
+                    if self.timers.retransmissions > limit {
+                        return self.time_out(nic, now);
+                    }
+
+                    // segments too big for the path, with nobody telling us so, look just like
+                    // this: so go down to a size that should get through, and search from there
+                    let base_mss = self.ip.mss_for(self.ip.base_mtu());
+                    if self.timers.retransmissions == BLACK_HOLE_RETRANSMISSIONS
+                        && self.connection_state.is_synchronized()
+                        && flight_size > base_mss
+                        && self.path_mtu.on_black_hole()
+                    {
+                        self.update_mss();
+                    }
+
+                    // (5.4 - 5.6) everything in flight is presumed lost: back off the timer, and
+                    // go back to the first unacknowledged segment
+                    self.congestion.on_rto(flight_size, self.send.max, now);
+                    self.timers.back_off();
+                }
+                self.timers.retransmit_at = None;
+                self.timers.rtt_probe = None;
+                self.send.nxt = self.send.una;
+            }
+        }
+
+        if let Some(persist_at) = self.timers.persist_at {
+            if now >= persist_at {
+                // an old sequence number and no data, so there's nothing for the peer to take in
+                // -- it just acks, and tells us its window
+                self.write(nic, now, self.send.una.wrapping_sub(1), 0)?;
+                self.timers.persist_backoff += 1;
+                self.timers.arm_persist(now);
+            }
+        }
+
+        self.transmit(nic, now)?;
+
+        // nothing went out for the ack to ride along with, so send it on its own. likewise if the
+        // application has read enough to open our window, as the peer may be waiting on it
+        let ack_due = self.delayed_ack.ack_at.is_some_and(|at| now >= at);
+        if ack_due || (self.connection_state.is_synchronized() && self.update_recv_window()) {
+            self.write(nic, now, self.send.nxt, 0)?;
+        }
+        Ok(())
+    }
+
+    // send the segment at SND.UNA again, as the congestion controller has asked -- or as a probe
+    // there has gone missing, in which case the MTU it was sent with is too big
+    fn retransmit<N: Nic>(&mut self, nic: &mut N, now: Instant) -> io::Result<()> {
+        self.path_mtu.on_retransmit(self.send.una, now);
+        self.write(nic, now, self.send.una, self.mss as usize)?;
+        Ok(())
+    }
+
+    pub fn snd_rst<N: Nic>(&mut self, nic: &mut N, now: Instant, seq: u32) -> io::Result<()> {
+        self.tcp.rst = true;
+        self.write(nic, now, seq, 0)?;
+        self.tcp.rst = false;
+        Ok(())
+    }
+
+    // SND.UNA < SEG.ACK =< SND.MAX -- the peer has acknowledged something new, which may include
+    // our SYN
+    fn on_ack<N: Nic>(
+        &mut self,
+        nic: &mut N,
+        ack: u32,
+        syn_acked: bool,
+        now: Instant,
+    ) -> io::Result<()> {
+        let una = self.send.una;
+        let mut bytes_acked = ack.wrapping_sub(una);
+        let mut flight_size = self.send.max.wrapping_sub(una);
+        if syn_acked {
+            bytes_acked -= 1;
+            flight_size -= 1;
+        }
+
+        let data_acked = std::cmp::min(bytes_acked as usize, self.unacked.len());
+        self.unacked.drain(..data_acked);
+        self.send.una = ack;
+        self.probe_dup_acks = 0;
+        if self.send.up.is_some_and(|up| !wrapping_lt(ack, up)) {
+            // the urgent data has all arrived
+            self.send.up = None;
+        }
+        if wrapping_lt(self.send.nxt, ack) {
+            // we'd gone back to resend after a timeout, but the original made it after all
+            self.send.nxt = ack;
+        }
+        if self.path_mtu.on_ack(ack, now) {
+            // a probe got through, so segments that big can from now on
+            self.update_mss();
+        }
+
+        if let Some((seq, sent_at)) = self.timers.rtt_probe {
+            if !wrapping_lt(ack, seq) {
+                let rtt = now.saturating_duration_since(sent_at);
+                self.timers.on_rtt_sample(rtt);
+                self.congestion.on_rtt_sample(rtt, now);
+                self.timers.rtt_probe = None;
+            }
+        }
+
+        // (5.2) everything is acknowledged, so stop the timer, otherwise (5.3) restart it
+        self.timers.retransmit_at = if ack == self.send.max {
+            None
+        } else {
+            Some(now + self.timers.rto)
+        };
+        // either way, the peer is making progress
+        self.timers.retransmissions = 0;
+        self.timers.unacked_since = self.timers.retransmit_at.map(|_| now);
+
+        if bytes_acked == 0 {
+            // only our SYN, which the congestion controller has nothing to say about
+            return Ok(());
+        }
+
+        let response = self.congestion.on_ack(&Ack {
+            ack,
+            bytes_acked,
+            flight_size,
+            snd_nxt: self.send.max,
+            duplicate: false,
+            now,
+        });
+        if let AckResponse::Retransmit = response {
+            self.retransmit(nic, now)?;
+        }
+
+        Ok(())
+    }
+
+    // a segment arriving while we wait for the peer to answer our SYN (RFC 793 S3.9)
+    fn on_syn_sent<N: Nic>(
+        &mut self,
+        nic: &mut N,
+        tcp_header: &etherparse::TcpHeaderSlice,
+        now: Instant,
+    ) -> io::Result<()> {
+        let seq = tcp_header.sequence_number();
+        let ack = tcp_header.acknowledgment_number();
+
+        // first the ack, which can only be for our SYN
+        let ack_acceptable =
+            tcp_header.ack() && wrapping_lt(self.send.iss, ack) && !wrapping_lt(self.send.max, ack);
+        if tcp_header.ack() && !ack_acceptable {
+            if !tcp_header.rst() {
+                self.snd_rst(nic, now, ack)?;
+            }
+            return Ok(());
+        }
+
+        if tcp_header.rst() {
+            if ack_acceptable {
+                self.abort(AbortReason::Refused);
+            }
+            return Ok(());
+        }
+
+        if !tcp_header.syn() {
+            return Ok(());
+        }
+
+        self.recieve.irs = seq;
+        self.recieve.nxt = seq.wrapping_add(1);
+        self.send.wnd = tcp_header.window_size();
+        self.send.max_wnd = self.send.wnd;
+        self.send.wl1 = seq;
+        self.send.wl2 = ack;
+        self.set_mss(peer_mss(tcp_header));
+        self.tcp.ack = true;
+        if let Some(cookie) = find_option(tcp_header, OPTION_FAST_OPEN) {
+            if !cookie.is_empty() {
+                self.fast_open_cookie = Some(cookie.to_vec());
+            }
+        }
+
+        if ack_acceptable {
+            // a SYN-ACK agreeing to ECN has ECE, but not CWR
+            self.ecn.enabled = tcp_header.ece() && !tcp_header.cwr();
+            self.on_ack(nic, ack, true, now)?;
+            if wrapping_lt(ack, self.send.max) {
+                // the server didn't take the data we sent with our SYN, so send it again now
+                self.send.nxt = ack;
+            }
+            self.connection_state = if self.closed_at.is_some() {
+                ConnectionState::FinWait1
+            } else {
+                ConnectionState::Estab
+            };
+            // ack their SYN, then send whatever we've been waiting to
+            self.write(nic, now, self.send.nxt, 0)?;
+            self.transmit(nic, now)
+        } else {
+            // a SYN without an ack: they opened at the same time we did, and sent their SYN
+            // before ours got to them. answer it with a SYN-ACK, as a passive open would
+            self.connection_state = ConnectionState::SynRcvd;
+            self.ecn.enabled = tcp_header.ece() && tcp_header.cwr();
+            self.write(nic, now, self.send.iss, 0)?;
+            Ok(())
+        }
+    }
+
+    // the segment isn't to be trusted, so just ack, and let the peer sort it out
+    fn challenge_ack<N: Nic>(
+        &mut self,
+        nic: &mut N,
+        challenge_acks: &mut ChallengeAckLimit,
+        now: Instant,
+    ) -> io::Result<()> {
+        if challenge_acks.allow(now) {
+            self.write(nic, now, self.send.nxt, 0)?;
+        }
+        Ok(())
+    }
+
+    // whether the handshake is still going on
+    pub fn is_connecting(&self) -> bool {
+        matches!(
+            self.connection_state,
+            ConnectionState::SynSent | ConnectionState::SynRcvd
+        )
+    }
+
+    pub fn on_packet<N: Nic>(
+        &mut self,
+        nic: &mut N,
+        ip_header: IpHeader,
+        tcp_header: etherparse::TcpHeaderSlice,
+        data: &[u8],
+        challenge_acks: &mut ChallengeAckLimit,
+        now: Instant,
+    ) -> io::Result<()> {
+        if self.is_finished() {
+            return Ok(());
+        }
+
+        // whatever this is, the peer is still there
+        self.timers.last_heard = now;
+        self.timers.keepalives_sent = 0;
+
+        if let ConnectionState::SynSent = self.connection_state {
+            return self.on_syn_sent(nic, &tcp_header, now);
+        }
+
+        // in SYN-RECEIVED, the peer may send its SYN again: either it didn't get our SYN-ACK, or
+        // we both opened at once and this is its SYN-ACK (RFC 793 figure 8). we've already taken
+        // the SYN, so drop it and carry on with the rest of the segment
+        let repeated_syn = matches!(self.connection_state, ConnectionState::SynRcvd)
+            && tcp_header.syn()
+            && tcp_header.sequence_number().wrapping_add(1) == self.recieve.nxt;
+        if repeated_syn && !tcp_header.ack() {
+            self.write(nic, now, self.send.iss, 0)?;
+            return Ok(());
+        }
+        let syn = tcp_header.syn() && !repeated_syn;
+
+        // acceptable ack check (RFC 793 S3.3)
+        // SND.UNA < SEG.ACK =< SND.NXT (but it wraps !)
+
+        let mut slen = data.len();
+        if tcp_header.fin() {
+            slen += 1
+        };
+        if syn {
+            slen += 1
+        };
+
+        // valid segment check
+        // RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
+        let nxt = self.recieve.nxt;
+        let mut seq = tcp_header.sequence_number();
+        if repeated_syn {
+            seq = seq.wrapping_add(1);
+        }
+        let end = self.recieve.nxt.wrapping_add(self.recieve.wnd as u32);
+        let seq_end = seq.wrapping_add((slen as u32).wrapping_sub(1));
+
+        let okay = if slen == 0 {
+            //zero-length segment rules
+            if self.recieve.wnd == 0 {
+                seq == self.recieve.nxt
+            } else {
+                nxt <= seq && (seq < end || (seq > end && nxt > end)) || seq < end && nxt > end
+            }
+        } else {
+            self.recieve.wnd != 0
+                && (nxt <= seq_end && (seq_end < end || (seq_end > end && nxt > end))
+                    || seq_end < end && nxt > end)
+        };
+
+        // with our window closed, even a segment right at its edge has no room for its data. the
+        // peer is probably probing to see whether the window has opened, and the ack and window
+        // it carries are still good, so take those (RFC 793 S3.3: "special allowance should be
+        // made to accept valid ACKs, URGs and RSTs")
+        let probe = !okay && self.recieve.wnd == 0 && seq == self.recieve.nxt;
+        if !okay {
+            // (a reset from outside the window is just dropped -- acking it could start a loop)
+            if !tcp_header.rst() {
+                self.write(nic, now, self.send.nxt, 0)?;
+            }
+            // their FIN again, so they never got our ack of it
+            if tcp_header.fin() && matches!(self.connection_state, ConnectionState::TimeWait) {
+                self.enter_time_wait(now);
+            }
+            if !probe {
+                return Ok(());
+            }
+        }
+
+        // a reset tears the connection down, without any reply (RFC 793 S3.4) -- but only if it's
+        // exactly where we expect the next segment. anywhere else in the window, it could be a
+        // lucky guess (RFC 5961 S3.2)
+        if tcp_header.rst() {
+            if seq != self.recieve.nxt {
+                return self.challenge_ack(nic, challenge_acks, now);
+            }
+            self.abort(if self.connection_state.is_synchronized() {
+                AbortReason::Reset
+            } else {
+                AbortReason::Refused
+            });
+            return Ok(());
+        }
+        // we've had their SYN already, so another can only be from a peer that has restarted --
+        // or a guess. either way, wherever it lands (RFC 5961 S4.2)
+        if syn {
+            return self.challenge_ack(nic, challenge_acks, now);
+        }
+
+        let data = if probe { &data[..0] } else { data };
+        let fin = tcp_header.fin() && !probe;
+
+        if self.ecn.enabled {
+            // the peer has slowed down, so we can stop telling it to -- unless this segment was
+            // marked too
+            if tcp_header.cwr() {
+                self.ecn.echo = false;
+            }
+            if let Ecn::Ce = ip_header.ecn() {
+                self.ecn.echo = true;
+            }
+        }
+
+        if !tcp_header.ack() {
+            return Ok(());
+        }
+
+        let ack = tcp_header.acknowledgment_number();
+        let mut syn_acked = false;
+        if let ConnectionState::SynRcvd = self.connection_state {
+            let una = self.send.una;
+            let nxt = self.send.max;
+            // SND.UNA < SEG.ACK =< SND.NXT (RFC 9293 S3.10.7.4 -- the SYN itself must be acked)
+            if una < ack && (ack <= nxt || una > nxt) || ack <= nxt && una > nxt {
+                // our SYN has been acknowledged
+                syn_acked = true;
+                self.connection_state = if self.closed_at.is_some() {
+                    // the application closed before the handshake was done
+                    ConnectionState::FinWait1
+                } else {
+                    ConnectionState::Estab
+                };
+                self.send.wl1 = seq;
+                self.send.wl2 = ack;
+                if repeated_syn {
+                    // ack their SYN-ACK, just as they'll ack ours
+                    self.write(nic, now, self.send.nxt, 0)?;
+                }
+            } else {
+                // reset (RFC 793 S3.9: <SEQ=SEG.ACK><CTL=RST>)
+                self.snd_rst(nic, now, ack)?;
+                return Ok(());
+            }
+        }
+
+        if self.connection_state.is_synchronized() {
+            // an ack for something we haven't sent yet, or from further back than the peer could
+            // still be acking: challenge it, and drop the segment (RFC 5961 S5.2)
+            let oldest = self.send.una.wrapping_sub(self.send.max_wnd as u32);
+            if wrapping_lt(self.send.max, ack) || wrapping_lt(ack, oldest) {
+                return self.challenge_ack(nic, challenge_acks, now);
+            }
+
+            let una = self.send.una;
+            let flight_size = self.send.max.wrapping_sub(una);
+            let duplicate = ack == una
+                && data.is_empty()
+                && !syn
+                && !fin
+                && tcp_header.window_size() == self.send.wnd
+                && self.send.max != una;
+
+            if wrapping_lt(una, ack) {
+                self.on_ack(nic, ack, syn_acked, now)?;
+            } else if duplicate && (self.probe_dup_acks > 0 || self.path_mtu.is_probe(una)) {
+                // what's missing is a probe: it was too big for the path, which says nothing about
+                // congestion (RFC 4821 S7.5), so the congestion controller doesn't hear about it.
+                // we still send it again (at the size that gets through) after three duplicates
+                self.probe_dup_acks += 1;
+                if self.probe_dup_acks == 3 {
+                    self.retransmit(nic, now)?;
+                }
+            } else if duplicate {
+                let response = self.congestion.on_ack(&Ack {
+                    ack,
+                    bytes_acked: 0,
+                    flight_size,
+                    snd_nxt: self.send.max,
+                    duplicate,
+                    now,
+                });
+                if let AckResponse::Retransmit = response {
+                    self.retransmit(nic, now)?;
+                }
+            }
+
+            // the network is congested: slow down as if something had been lost, but only once for
+            // everything that was in flight at the time (RFC 3168 S6.1.2). in fast recovery, we
+            // already have, so all that's left is to let the peer know
+            if self.ecn.enabled
+                && tcp_header.ece()
+                && self
+                    .ecn
+                    .recover
+                    .is_none_or(|recover| wrapping_lt(recover, ack))
+            {
+                if !self.congestion.in_recovery() {
+                    self.congestion.on_loss(flight_size, now);
+                }
+                self.ecn.recover = Some(self.send.max);
+                self.ecn.send_cwr = true;
+            }
+
+            // update the send window, unless this segment is older than the one we last took it
+            // from (RFC 793 S3.9)
+            if !wrapping_lt(ack, self.send.una)
+                && (wrapping_lt(self.send.wl1, seq)
+                    || (self.send.wl1 == seq && !wrapping_lt(ack, self.send.wl2)))
+            {
+                self.send.wnd = tcp_header.window_size();
+                self.send.max_wnd = std::cmp::max(self.send.max_wnd, self.send.wnd);
+                if self.send.wnd != 0 {
+                    self.timers.persist_at = None;
+                    self.timers.persist_backoff = 0;
+                }
+                self.send.wl1 = seq;
+                self.send.wl2 = ack;
+            }
+        }
+
+        if self.closed_at.map(|fin| fin.wrapping_add(1)) == Some(self.send.una) {
+            // our fin has been ack'd
+            match self.connection_state {
+                ConnectionState::FinWait1 => self.connection_state = ConnectionState::FinWait2,
+                ConnectionState::Closing => self.enter_time_wait(now),
+                ConnectionState::LastAck => {
+                    // and theirs already had been, so we're done
+                    self.connection_state = ConnectionState::Closed;
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+
+        if let ConnectionState::Estab | ConnectionState::FinWait1 | ConnectionState::FinWait2 =
+            self.connection_state
+        {
+            // the peer has urgent data, which ends here -- unless we already knew of some that
+            // ends later, or the application has already read past it
+            if tcp_header.urg() && !probe {
+                let up = seq.wrapping_add(tcp_header.urgent_pointer() as u32);
+                let read_from = self.recieve.nxt.wrapping_sub(self.incoming.len() as u32);
+                if wrapping_lt(read_from, up)
+                    && self
+                        .recieve
+                        .up
+                        .is_none_or(|current| wrapping_lt(current, up))
+                {
+                    self.recieve.up = Some(up);
+                }
+            }
+
+            if !data.is_empty() {
+                if wrapping_lt(self.recieve.nxt, seq) {
+                    // out of order: we can't hold on to it, so ack what we do have straight away
+                    // -- the duplicate ack is how the peer finds out about the hole
+                    self.write(nic, now, self.send.nxt, 0)?;
+                } else {
+                    // some of this may be a retransmission of data we've already got
+                    let unread_data_at = self.recieve.nxt.wrapping_sub(seq) as usize;
+                    if unread_data_at < data.len() {
+                        let new_data = &data[unread_data_at..];
+                        if !self.recv_shutdown {
+                            self.incoming.extend(new_data);
+                            self.recieve.wnd =
+                                self.recieve.wnd.saturating_sub(new_data.len() as u16);
+                        }
+                        self.recieve.nxt = seq.wrapping_add(data.len() as u32);
+                        self.delay_ack(nic, now, data.len())?;
+                    } else {
+                        // nothing new, so our last ack probably got lost: send another now
+                        self.write(nic, now, self.send.nxt, 0)?;
+                    }
+                }
+            }
+        }
+
+        if fin {
+            let fin = seq.wrapping_add(data.len() as u32);
+            // only once everything before it has arrived
+            if fin == self.recieve.nxt {
+                self.recieve.nxt = fin.wrapping_add(1);
+
+                match self.connection_state {
+                    ConnectionState::SynRcvd | ConnectionState::Estab => {
+                        // the peer is done sending, but we can carry on until we close too
+                        self.connection_state = ConnectionState::CloseWait;
+                    }
+                    ConnectionState::FinWait1 => {
+                        // we both closed at once: their FIN crossed ours, which they haven't acked
+                        // yet
+                        self.connection_state = ConnectionState::Closing;
+                    }
+                    ConnectionState::FinWait2 => {
+                        // we're done
+                        self.enter_time_wait(now);
+                    }
+                    // we've had their FIN already, so it can't be in order again
+                    ConnectionState::SynSent
+                    | ConnectionState::Closing
+                    | ConnectionState::TimeWait
+                    | ConnectionState::CloseWait
+                    | ConnectionState::LastAck
+                    | ConnectionState::Closed => {}
+                }
+            }
+
+            // ack it straight away -- or ack it again, if the peer didn't get our last one
+            self.write(nic, now, self.send.nxt, 0)?;
+        }
+
+        // if let ConnectionState::FinWait2 = self.connection_state {
+        //     if !tcp_header.fin() || !data.is_empty() {
+        //         unimplemented!()
+        //     }
+
+        //     self.tcp.fin = false;
+        //      self.write(nic, &[])?;
+        //      self.connection_state = ConnectionState::Closing;
+        //  }
+
+        // the peer may have opened its window, or acked enough to let more data out
+        self.transmit(nic, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use etherparse::TcpOptionElement;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    // the most data in a segment over IPv4: a 1500 byte packet, less 20 bytes each of IP and TCP
+    // header
+    const MSS: u32 = 1460;
+
+    // a segment from 192.168.0.2:4000 to us on 192.168.0.1:9000
+    fn segment(seq: u32, ack: Option<u32>, window: u16, syn: bool, payload: &[u8]) -> Vec<u8> {
+        let mut tcp = etherparse::TcpHeader::new(4000, 9000, seq, window);
+        tcp.syn = syn;
+        if syn {
+            tcp.set_options(&[TcpOptionElement::MaximumSegmentSize(MSS as u16)])
+                .unwrap();
+        }
+        if let Some(ack) = ack {
+            tcp.ack = true;
+            tcp.acknowledgment_number = ack;
+        }
+        packet(tcp, payload)
+    }
+
+    fn fin(seq: u32, ack: u32) -> Vec<u8> {
+        let mut tcp = etherparse::TcpHeader::new(4000, 9000, seq, 65535);
+        tcp.fin = true;
+        tcp.ack = true;
+        tcp.acknowledgment_number = ack;
+        packet(tcp, &[])
+    }
+
+    fn reset(seq: u32) -> Vec<u8> {
+        let mut tcp = etherparse::TcpHeader::new(4000, 9000, seq, 0);
+        tcp.rst = true;
+        packet(tcp, &[])
+    }
+
+    fn packet(tcp: etherparse::TcpHeader, payload: &[u8]) -> Vec<u8> {
+        let ip = etherparse::Ipv4Header::new(
+            tcp.header_len() + payload.len() as u16,
+            64,
+            etherparse::ip_number::TCP,
+            [192, 168, 0, 2],
+            [192, 168, 0, 1],
+        );
+
+        let mut packet = Vec::new();
+        ip.write(&mut packet).unwrap();
+        tcp.write(&mut packet).unwrap();
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn headers(packet: &[u8]) -> (IpHeader, etherparse::TcpHeaderSlice<'_>, &[u8]) {
+        let (ip_header, segment) = if packet[0] >> 4 == 6 {
+            let (segment, ip_header) = crate::network_parse::parse_ipv6(packet).unwrap();
+            (IpHeader::from(&ip_header), segment)
+        } else {
+            let ip_header = etherparse::Ipv4HeaderSlice::from_slice(packet).unwrap();
+            (
+                IpHeader::from(&ip_header),
+                &packet[ip_header.slice().len()..],
+            )
+        };
+        let tcp_header = etherparse::TcpHeaderSlice::from_slice(segment).unwrap();
+        let data = &segment[tcp_header.slice().len()..];
+        (ip_header, tcp_header, data)
+    }
+
+    fn accept(nic: &mut Vec<Vec<u8>>, window: u16, now: Instant) -> TcpState {
+        let syn = segment(1000, None, window, true, &[]);
+        let (ip_header, tcp_header, data) = headers(&syn);
+        TcpState::accept(
+            nic,
+            ip_header,
+            tcp_header,
+            data,
+            0,
+            Algorithm::NewReno,
+            None,
+            now,
+        )
+        .unwrap()
+        .unwrap()
+    }
+
+    fn deliver(connection: &mut TcpState, nic: &mut Vec<Vec<u8>>, packet: &[u8], now: Instant) {
+        let (ip_header, tcp_header, data) = headers(packet);
+        connection
+            .on_packet(
+                nic,
+                ip_header,
+                tcp_header,
+                data,
+                &mut ChallengeAckLimit::default(),
+                now,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn syn_ack_is_retransmitted_with_backoff() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = accept(&mut nic, 1000, now);
+        assert_eq!(nic.len(), 1);
+        assert!(headers(&nic[0]).1.syn());
+
+        connection
+            .on_tick(&mut nic, now + Duration::from_millis(500))
+            .unwrap();
+        assert_eq!(nic.len(), 1);
+
+        connection
+            .on_tick(&mut nic, now + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(nic.len(), 2);
+        assert!(headers(&nic[1]).1.syn());
+        assert_eq!(headers(&nic[1]).1.sequence_number(), 0);
+
+        // the timer has backed off to 2 seconds
+        connection
+            .on_tick(&mut nic, now + Duration::from_secs(2))
+            .unwrap();
+        assert_eq!(nic.len(), 2);
+        connection
+            .on_tick(&mut nic, now + Duration::from_secs(3))
+            .unwrap();
+        assert_eq!(nic.len(), 3);
+    }
+
+    #[test]
+    fn connections_are_rebuilt_from_syn_cookies() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut cookies = SynCookies::new(now);
+
+        let mut syn = etherparse::TcpHeader::new(4000, 9000, 1000, 65535);
+        syn.syn = true;
+        syn.set_options(&[TcpOptionElement::MaximumSegmentSize(1300)])
+            .unwrap();
+        let syn = packet(syn, &[]);
+        let (ip_header, tcp_header, _) = headers(&syn);
+        TcpState::send_syn_cookie(&mut nic, ip_header, tcp_header, &mut cookies, now).unwrap();
+        let (_, syn_ack, _) = headers(&nic[0]);
+        assert!(syn_ack.syn() && syn_ack.ack());
+        assert_eq!(syn_ack.acknowledgment_number(), 1001);
+        let iss = syn_ack.sequence_number();
+
+        // a made-up ack gets nowhere
+        let bogus = segment(1001, Some(iss.wrapping_add(2)), 65535, false, &[]);
+        let (ip_header, tcp_header, _) = headers(&bogus);
+        assert!(TcpState::from_syn_cookie(
+            &ip_header,
+            &tcp_header,
+            &cookies,
+            Algorithm::NewReno,
+            now
+        )
+        .is_none());
+
+        // but the real one, with some data along with it, sets up the connection
+        let ack = segment(1001, Some(iss.wrapping_add(1)), 65535, false, b"hello");
+        let (ip_header, tcp_header, _) = headers(&ack);
+        let mut connection =
+            TcpState::from_syn_cookie(&ip_header, &tcp_header, &cookies, Algorithm::NewReno, now)
+                .unwrap();
+        deliver(&mut connection, &mut nic, &ack, now);
+        assert!(matches!(
+            connection.connection_state,
+            ConnectionState::Estab
+        ));
+        assert_eq!(connection.incoming, b"hello");
+
+        // and it remembers what the peer could take
+        nic.clear();
+        connection.unacked.extend(vec![7u8; 2 * MSS as usize]);
+        connection.transmit(&mut nic, now).unwrap();
+        assert_eq!(headers(&nic[0]).2.len(), 1300);
+    }
+
+    #[test]
+    fn sending_is_limited_by_the_congestion_window() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = accept(&mut nic, 65535, now);
+        connection.unacked.extend(vec![7u8; 10 * MSS as usize]);
+
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001, Some(1), 65535, false, &[]),
+            now,
+        );
+
+        // the initial window is 3 segments, even though the peer would take more
+        let sent: Vec<_> = nic[1..].iter().map(|p| headers(p).2.len()).collect();
+        assert_eq!(sent, vec![MSS as usize; 3]);
+
+        // acking one segment in slow start lets two more out
+        nic.clear();
+        let ack = 1 + MSS;
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001, Some(ack), 65535, false, &[]),
+            now,
+        );
+        assert_eq!(nic.len(), 2);
+        assert_eq!(headers(&nic[0]).1.sequence_number(), 1 + 3 * MSS);
+    }
+
+    // a controller that never limits the window, but wants a segment every 10ms
+    struct Paced;
+
+    impl CongestionControl for Paced {
+        fn cwnd(&self) -> u32 {
+            u32::MAX
+        }
+
+        fn pacing_rate(&self) -> Option<f64> {
+            Some(MSS as f64 * 100.0)
+        }
+
+        fn on_ack(&mut self, _ack: &congestion::Ack) -> AckResponse {
+            AckResponse::Continue
+        }
+
+        fn on_loss(&mut self, _flight_size: u32, _now: Instant) {}
+
+        fn on_rto(&mut self, _flight_size: u32, _snd_max: u32, _now: Instant) {}
+
+        fn on_rtt_sample(&mut self, _rtt: Duration, _now: Instant) {}
+    }
+
+    #[test]
+    fn sending_is_paced() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = accept(&mut nic, 65535, now);
+        connection.congestion = Box::new(Paced);
+        connection.unacked.extend(vec![7u8; 10 * MSS as usize]);
+
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001, Some(1), 65535, false, &[]),
+            now,
+        );
+
+        // one segment now, and the next not for another 10ms
+        assert_eq!(nic.len(), 2);
+        let next = now + Duration::from_millis(10);
+        assert_eq!(connection.next_deadline(), Some(next));
+
+        connection
+            .on_tick(&mut nic, now + Duration::from_millis(5))
+            .unwrap();
+        assert_eq!(nic.len(), 2);
+
+        connection.on_tick(&mut nic, next).unwrap();
+        assert_eq!(nic.len(), 3);
+        assert_eq!(headers(&nic[2]).1.sequence_number(), 1 + MSS);
+
+        // a late tick can catch up on what it missed, but only by a tick's worth
+        connection
+            .on_tick(&mut nic, next + Duration::from_millis(100))
+            .unwrap();
+        assert_eq!(nic.len(), 5);
+    }
+
+    // past the handshake, with nothing sent either way yet
+    fn established(nic: &mut Vec<Vec<u8>>, now: Instant) -> TcpState {
+        let mut connection = accept(nic, 65535, now);
+        deliver(
+            &mut connection,
+            nic,
+            &segment(1001, Some(1), 65535, false, &[]),
+            now,
+        );
+        nic.clear();
+        connection
+    }
+
+    #[test]
+    fn small_segments_wait_for_outstanding_data() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+
+        // nothing is outstanding, so the first write goes straight out
+        connection.unacked.extend([1; 100]);
+        connection.transmit(&mut nic, now).unwrap();
+        assert_eq!(nic.len(), 1);
+
+        // but the next waits for it to be acked
+        connection.unacked.extend([2; 100]);
+        connection.transmit(&mut nic, now).unwrap();
+        assert_eq!(nic.len(), 1);
+
+        // unless there's a full segment's worth
+        connection.unacked.extend(vec![3; MSS as usize]);
+        connection.transmit(&mut nic, now).unwrap();
+        assert_eq!(nic.len(), 2);
+        assert_eq!(headers(&nic[1]).2.len(), MSS as usize);
+
+        nic.clear();
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001, Some(1 + 100 + MSS), 65535, false, &[]),
+            now,
+        );
+        assert_eq!(nic.len(), 1);
+        assert_eq!(headers(&nic[0]).2, &[3; 100][..]);
+    }
+
+    #[test]
+    fn nodelay_sends_small_segments_straight_away() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+        connection.set_nodelay(true);
+
+        connection.unacked.extend([1; 100]);
+        connection.transmit(&mut nic, now).unwrap();
+        connection.unacked.extend([2; 100]);
+        connection.transmit(&mut nic, now).unwrap();
+        assert_eq!(nic.len(), 2);
+    }
+
+    #[test]
+    fn sending_is_limited_by_the_peer_window() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = accept(&mut nic, 1000, now);
+        connection.unacked.extend(vec![7u8; 10 * MSS as usize]);
+
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001, Some(1), 1000, false, &[]),
+            now,
+        );
+        assert_eq!(nic.len(), 2);
+        assert_eq!(headers(&nic[1]).2.len(), 1000);
+    }
+
+    #[test]
+    fn three_duplicate_acks_trigger_fast_retransmit() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = accept(&mut nic, 65535, now);
+        connection.unacked.extend(vec![7u8; 3 * MSS as usize]);
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001, Some(1), 65535, false, &[]),
+            now,
+        );
+        nic.clear();
+
+        // the first segment went missing, and the other two each produce a duplicate ack
+        let dup = segment(1001, Some(1), 65535, false, &[]);
+        deliver(&mut connection, &mut nic, &dup, now);
+        deliver(&mut connection, &mut nic, &dup, now);
+        assert!(nic.is_empty());
+        deliver(&mut connection, &mut nic, &dup, now);
+        assert_eq!(nic.len(), 1);
+        assert_eq!(headers(&nic[0]).1.sequence_number(), 1);
+        assert_eq!(headers(&nic[0]).2.len(), MSS as usize);
+    }
+
+    #[test]
+    fn acks_are_delayed() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001, Some(1), 65535, false, &[1; 100]),
+            now,
+        );
+        assert!(nic.is_empty());
+        assert_eq!(connection.next_deadline(), Some(now + DEFAULT_ACK_DELAY));
+
+        connection
+            .on_tick(&mut nic, now + Duration::from_millis(39))
+            .unwrap();
+        assert!(nic.is_empty());
+
+        connection
+            .on_tick(&mut nic, now + DEFAULT_ACK_DELAY)
+            .unwrap();
+        assert_eq!(nic.len(), 1);
+        assert_eq!(headers(&nic[0]).1.acknowledgment_number(), 1101);
+        assert_eq!(connection.next_deadline(), None);
+    }
+
+    #[test]
+    fn every_second_full_segment_is_acked() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001, Some(1), 65535, false, &[1; MSS as usize]),
+            now,
+        );
+        assert!(nic.is_empty());
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001 + MSS, Some(1), 65535, false, &[1; MSS as usize]),
+            now,
+        );
+        assert_eq!(nic.len(), 1);
+        assert_eq!(headers(&nic[0]).1.acknowledgment_number(), 1001 + 2 * MSS);
+    }
+
+    #[test]
+    fn every_second_full_segment_is_acked_over_ipv6() {
+        let now = Instant::now();
+        let a = (IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1)), 5000);
+        let b = (IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2)), 6000);
+        let (mut a, mut b) = connected_between(a, b, now);
+        let (mut a_out, mut b_out) = (Vec::new(), Vec::new());
+
+        // full-sized segments over IPv6 are a little smaller
+        a.unacked.extend(vec![1; 2 * 1440]);
+        a.transmit(&mut a_out, now).unwrap();
+        assert_eq!(headers(&a_out[0]).2.len(), 1440);
+
+        deliver(&mut b, &mut b_out, &a_out[0], now);
+        assert!(b_out.is_empty());
+        deliver(&mut b, &mut b_out, &a_out[1], now);
+        assert_eq!(b_out.len(), 1);
+        assert_eq!(headers(&b_out[0]).1.acknowledgment_number(), 1 + 2 * 1440);
+    }
+
+    #[test]
+    fn out_of_order_data_is_acked_immediately() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1101, Some(1), 65535, false, &[1; 100]),
+            now,
+        );
+        assert_eq!(nic.len(), 1);
+        assert_eq!(headers(&nic[0]).1.acknowledgment_number(), 1001);
+    }
+
+    #[test]
+    fn acks_ride_along_with_data() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = accept(&mut nic, 65535, now);
+        connection.unacked.extend(vec![7u8; 10 * MSS as usize]);
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001, Some(1), 65535, false, &[]),
+            now,
+        );
+        nic.clear();
+
+        // the peer's data comes with an ack that opens the window, so the ack for it goes out on
+        // the data that lets out
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001, Some(1 + MSS), 65535, false, &[1; 100]),
+            now,
+        );
+        assert_eq!(nic.len(), 2);
+        for packet in &nic {
+            let (_, tcp_header, data) = headers(packet);
+            assert_eq!(tcp_header.acknowledgment_number(), 1101);
+            assert_eq!(data.len(), MSS as usize);
+        }
+
+        connection
+            .on_tick(&mut nic, now + DEFAULT_ACK_DELAY)
+            .unwrap();
+        assert_eq!(nic.len(), 2);
+    }
+
+    #[test]
+    fn receive_window_opens_in_large_steps() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001, Some(1), 65535, false, &[1; MSS as usize]),
+            now,
+        );
+        let wnd = (RECV_QUEUE_SIZE - MSS as usize) as u16;
+        assert_eq!(connection.recieve.wnd, wnd);
+
+        // the application reading a little isn't worth telling the peer about
+        connection.incoming.drain(..1000);
+        connection.on_tick(&mut nic, now).unwrap();
+        assert!(nic.is_empty());
+        assert_eq!(connection.recieve.wnd, wnd);
+
+        // but a full segment's worth is
+        connection.incoming.clear();
+        connection.on_tick(&mut nic, now).unwrap();
+        assert_eq!(nic.len(), 1);
+        let (_, tcp_header, _) = headers(&nic[0]);
+        assert_eq!(tcp_header.window_size(), RECV_QUEUE_SIZE as u16);
+        assert_eq!(tcp_header.acknowledgment_number(), 1001 + MSS);
+    }
+
+    #[test]
+    fn small_windows_are_not_filled_with_tiny_segments() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+        connection.unacked.extend(vec![1; MSS as usize + 1000]);
+        connection.transmit(&mut nic, now).unwrap();
+        assert_eq!(nic.len(), 1);
+        nic.clear();
+
+        // the peer takes the first segment, but only has room for 200 bytes more
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001, Some(1 + MSS), 200, false, &[]),
+            now,
+        );
+        assert!(nic.is_empty());
+        let override_at = now + SWS_OVERRIDE;
+        assert_eq!(connection.next_deadline(), Some(override_at));
+
+        // if the window doesn't open in the meantime, we send what it allows
+        connection.on_tick(&mut nic, override_at).unwrap();
+        assert_eq!(nic.len(), 1);
+        assert_eq!(headers(&nic[0]).2.len(), 200);
+    }
+
+    #[test]
+    fn closed_windows_are_probed() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+        connection.send.wnd = 0;
+        connection.unacked.extend([1; 100]);
+
+        connection.transmit(&mut nic, now).unwrap();
+        assert!(nic.is_empty());
+        let first = now + INITIAL_RTO;
+        assert_eq!(connection.next_deadline(), Some(first));
+
+        // a probe is an empty segment just before the window, and each one backs off the timer
+        connection.on_tick(&mut nic, first).unwrap();
+        assert_eq!(nic.len(), 1);
+        let (_, tcp_header, data) = headers(&nic[0]);
+        assert_eq!(tcp_header.sequence_number(), 0);
+        assert!(data.is_empty());
+        let second = first + 2 * INITIAL_RTO;
+        assert_eq!(connection.next_deadline(), Some(second));
+        connection.on_tick(&mut nic, second).unwrap();
+        assert_eq!(nic.len(), 2);
+
+        // once the window opens, the data goes and the probing stops
+        nic.clear();
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001, Some(1), 1000, false, &[]),
+            second,
+        );
+        assert_eq!(headers(&nic[0]).2.len(), 100);
+        assert_eq!(connection.timers.persist_at, None);
+    }
+
+    #[test]
+    fn probes_into_our_closed_window_still_ack() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+        connection.unacked.extend([1; 100]);
+        connection.transmit(&mut nic, now).unwrap();
+        nic.clear();
+
+        // the application isn't reading, so our window has closed
+        connection.incoming.extend(vec![0; RECV_QUEUE_SIZE]);
+        connection.recieve.wnd = 0;
+
+        // the peer probes with a byte of data, which there's no room for -- but the ack on it
+        // counts
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001, Some(101), 65535, false, &[9]),
+            now,
+        );
+        let (_, tcp_header, _) = headers(&nic[0]);
+        assert_eq!(tcp_header.acknowledgment_number(), 1001);
+        assert_eq!(tcp_header.window_size(), 0);
+        assert_eq!(connection.send.una, 101);
+        assert_eq!(connection.incoming.len(), RECV_QUEUE_SIZE);
+    }
+
+    #[test]
+    fn idle_connections_are_kept_alive() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+        let keepalive = Keepalive {
+            idle: Duration::from_secs(10),
+            interval: Duration::from_secs(1),
+            probes: 3,
+        };
+        connection.set_keepalive(Some(keepalive));
+
+        let first = now + keepalive.idle;
+        assert_eq!(connection.next_deadline(), Some(first));
+        connection.on_tick(&mut nic, first).unwrap();
+        assert_eq!(nic.len(), 1);
+        let (_, tcp_header, data) = headers(&nic[0]);
+        assert_eq!(tcp_header.sequence_number(), 0);
+        assert!(data.is_empty());
+
+        // the peer answers, so it's another full idle period before the next
+        let answered = first + Duration::from_millis(100);
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001, Some(1), 65535, false, &[]),
+            answered,
+        );
+        assert_eq!(connection.next_deadline(), Some(answered + keepalive.idle));
+    }
+
+    #[test]
+    fn unanswered_keepalives_abort_the_connection() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+        let keepalive = Keepalive {
+            idle: Duration::from_secs(10),
+            interval: Duration::from_secs(1),
+            probes: 3,
+        };
+        connection.set_keepalive(Some(keepalive));
+
+        for probe in 0..3 {
+            connection
+                .on_tick(&mut nic, now + keepalive.idle + keepalive.interval * probe)
+                .unwrap();
+        }
+        assert_eq!(nic.len(), 3);
+        assert_eq!(connection.aborted(), None);
+
+        connection
+            .on_tick(&mut nic, now + keepalive.idle + keepalive.interval * 3)
+            .unwrap();
+        assert_eq!(connection.aborted(), Some(AbortReason::TimedOut));
+        assert!(headers(&nic[3]).1.rst());
+        assert_eq!(connection.next_deadline(), None);
+    }
+
+    #[test]
+    fn user_timeout_aborts_unacknowledged_data() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+        connection.set_user_timeout(Some(Duration::from_secs(5)));
+        connection.unacked.extend([1; 100]);
+        connection.transmit(&mut nic, now).unwrap();
+
+        connection
+            .on_tick(&mut nic, now + Duration::from_secs(4))
+            .unwrap();
+        assert_eq!(connection.aborted(), None);
+
+        let timeout = now + Duration::from_secs(5);
+        assert_eq!(connection.next_deadline(), Some(timeout));
+        connection.on_tick(&mut nic, timeout).unwrap();
+        assert_eq!(connection.aborted(), Some(AbortReason::TimedOut));
+        assert!(headers(nic.last().unwrap()).1.rst());
+    }
+
+    #[test]
+    fn icmp_errors_only_refuse_connections_still_connecting() {
+        let now = Instant::now();
+        let mut nic = Vec::new();
+        let mut connection = TcpState::connect(A, B, 0, Algorithm::NewReno, now);
+        connection.on_tick(&mut nic, now).unwrap();
+        let iss = connection.send.una;
+
+        // not about anything we've sent
+        connection
+            .on_icmp_error(&mut nic, IcmpError::Refused, iss + 1, now)
+            .unwrap();
+        assert_eq!(connection.aborted(), None);
+        connection
+            .on_icmp_error(&mut nic, IcmpError::Refused, iss, now)
+            .unwrap();
+        assert_eq!(connection.aborted(), Some(AbortReason::Refused));
+
+        // once connected, errors are only why we gave up, if we do
+        let mut connection = established(&mut nic, now);
+        connection.set_user_timeout(Some(Duration::from_secs(5)));
+        connection.unacked.extend([1; 100]);
+        connection.transmit(&mut nic, now).unwrap();
+        let una = connection.send.una;
+        connection
+            .on_icmp_error(&mut nic, IcmpError::Refused, una, now)
+            .unwrap();
+        connection
+            .on_icmp_error(&mut nic, IcmpError::Unreachable, una, now)
+            .unwrap();
+        assert_eq!(connection.aborted(), None);
+        connection
+            .on_tick(&mut nic, now + Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(connection.aborted(), Some(AbortReason::Unreachable));
+    }
+
+    #[test]
+    fn packets_too_big_for_the_path_are_sent_again_smaller() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+        connection.unacked.extend([1; 3000]);
+        connection.transmit(&mut nic, now).unwrap();
+        assert_eq!(headers(&nic[0]).2.len(), 1460);
+        nic.clear();
+
+        let una = connection.send.una;
+        connection
+            .on_icmp_error(&mut nic, IcmpError::PacketTooBig(1000), una, now)
+            .unwrap();
+        assert_eq!(connection.mss, 960);
+        let sent: Vec<usize> = nic.iter().map(|packet| headers(packet).2.len()).collect();
+        // (the rest is small enough to wait for those to be acked)
+        assert_eq!(sent, [960, 960, 960]);
+        nic.clear();
+
+        // but not smaller than any path can take
+        connection
+            .on_icmp_error(&mut nic, IcmpError::PacketTooBig(68), una, now)
+            .unwrap();
+        assert_eq!(connection.mss, 536);
+        // and never bigger again
+        connection
+            .on_icmp_error(&mut nic, IcmpError::PacketTooBig(1500), una, now)
+            .unwrap();
+        assert_eq!(connection.mss, 536);
+    }
+
+    #[test]
+    fn black_holes_are_searched_past() {
+        let mut nic = Vec::new();
+        let mut now = Instant::now();
+        let mut connection = established(&mut nic, now);
+        connection.unacked.extend([1; 2 * 1460]);
+        connection.transmit(&mut nic, now).unwrap();
+
+        // full-sized segments go missing, and nothing tells us why
+        for _ in 0..BLACK_HOLE_RETRANSMISSIONS {
+            now = connection.timers.retransmit_at.unwrap();
+            nic.clear();
+            connection.on_tick(&mut nic, now).unwrap();
+        }
+        assert_eq!(connection.mss, 984);
+        assert_eq!(headers(&nic[0]).2.len(), 984);
+
+        // the next new data starts with a probe for something bigger
+        let acked = 1 + 2 * 1460;
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001, Some(acked), 65535, false, &[]),
+            now,
+        );
+        nic.clear();
+        connection.unacked.extend([1; 5000]);
+        connection.transmit(&mut nic, now).unwrap();
+        let probe = headers(&nic[0]).2.len() as u32;
+        assert_eq!(probe, 1222);
+        assert_eq!(headers(&nic[1]).2.len(), 984);
+
+        // which gets through
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001, Some(acked + probe), 65535, false, &[]),
+            now,
+        );
+        assert_eq!(connection.mss, probe);
+    }
+
+    #[test]
+    fn lost_probes_are_not_congestion() {
+        let mut nic = Vec::new();
+        let mut now = Instant::now();
+        let mut connection = established(&mut nic, now);
+        connection.unacked.extend([1; 2 * 1460]);
+        connection.transmit(&mut nic, now).unwrap();
+        for _ in 0..BLACK_HOLE_RETRANSMISSIONS {
+            now = connection.timers.retransmit_at.unwrap();
+            connection.on_tick(&mut nic, now).unwrap();
+        }
+        let acked = 1 + 2 * 1460;
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001, Some(acked), 65535, false, &[]),
+            now,
+        );
+        nic.clear();
+        connection.unacked.extend([1; 5000]);
+        connection.transmit(&mut nic, now).unwrap();
+        assert_eq!(headers(&nic[0]).2.len(), 1222);
+        let cwnd = connection.congestion.cwnd();
+
+        // the probe is too big, and the segments behind it are duplicate acked
+        nic.clear();
+        let dup = segment(1001, Some(acked), 65535, false, &[]);
+        for _ in 0..3 {
+            deliver(&mut connection, &mut nic, &dup, now);
+        }
+        // its data goes again, at the size that gets through, but the window is left alone
+        assert_eq!(headers(&nic[0]).1.sequence_number(), acked);
+        assert_eq!(headers(&nic[0]).2.len(), 984);
+        assert_eq!(connection.mss, 984);
+        assert!(!connection.congestion.in_recovery());
+        assert_eq!(connection.congestion.cwnd(), cwnd);
+
+        // including for the rest of the duplicates it brings on
+        for _ in 0..3 {
+            deliver(&mut connection, &mut nic, &dup, now);
+        }
+        assert!(!connection.congestion.in_recovery());
+        assert_eq!(connection.congestion.cwnd(), cwnd);
+    }
+
+    #[test]
+    fn retransmissions_give_up_eventually() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = accept(&mut nic, 1000, now);
+
+        while connection.aborted().is_none() {
+            let at = connection.timers.retransmit_at.unwrap();
+            connection.on_tick(&mut nic, at).unwrap();
+        }
+        assert_eq!(connection.aborted(), Some(AbortReason::TimedOut));
+        // the SYN-ACK, each retransmission of it, and a reset
+        assert_eq!(nic.len(), 1 + MAX_SYN_RETRANSMISSIONS as usize + 1);
+    }
+
+    #[test]
+    fn resets_abort_the_connection() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+
+        // one from outside the window is ignored
+        deliver(&mut connection, &mut nic, &reset(500), now);
+        assert_eq!(connection.aborted(), None);
+
+        deliver(&mut connection, &mut nic, &reset(1001), now);
+        assert_eq!(connection.aborted(), Some(AbortReason::Reset));
+        assert!(nic.is_empty());
+
+        // before the handshake is done, the connection was refused
+        let mut connection = accept(&mut nic, 1000, now);
+        deliver(&mut connection, &mut nic, &reset(1001), now);
+        assert_eq!(connection.aborted(), Some(AbortReason::Refused));
+    }
+
+    #[test]
+    fn resets_in_the_window_are_challenged() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+
+        deliver(&mut connection, &mut nic, &reset(2000), now);
+        assert_eq!(connection.aborted(), None);
+        assert_eq!(nic.len(), 1);
+        assert_eq!(headers(&nic[0]).1.acknowledgment_number(), 1001);
+
+        // the real peer comes back with one in the right place
+        deliver(&mut connection, &mut nic, &reset(1001), now);
+        assert_eq!(connection.aborted(), Some(AbortReason::Reset));
+    }
+
+    #[test]
+    fn syns_on_established_connections_are_challenged() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(2000, None, 65535, true, &[]),
+            now,
+        );
+        assert!(matches!(
+            connection.connection_state,
+            ConnectionState::Estab
+        ));
+        assert_eq!(nic.len(), 1);
+        assert_eq!(headers(&nic[0]).1.acknowledgment_number(), 1001);
+    }
+
+    #[test]
+    fn acks_from_too_far_back_are_challenged() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+
+        // further back than the peer's biggest window could be holding
+        let ack = 1u32.wrapping_sub(65535 + 1);
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001, Some(ack), 65535, false, b"hello"),
+            now,
+        );
+        assert!(connection.incoming.is_empty());
+        assert_eq!(nic.len(), 1);
+        assert_eq!(headers(&nic[0]).1.acknowledgment_number(), 1001);
+
+        // but one that's merely old is fine
+        nic.clear();
+        let ack = 1u32.wrapping_sub(65535);
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001, Some(ack), 65535, false, b"hello"),
+            now,
+        );
+        assert_eq!(connection.incoming, b"hello");
+    }
+
+    #[test]
+    fn challenge_acks_are_rate_limited() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+        let mut challenge_acks = ChallengeAckLimit::default();
+
+        let mut challenge = |connection: &mut TcpState, nic: &mut Vec<Vec<u8>>, now| {
+            let packet = reset(2000);
+            let (ip_header, tcp_header, data) = headers(&packet);
+            connection
+                .on_packet(nic, ip_header, tcp_header, data, &mut challenge_acks, now)
+                .unwrap();
+        };
+        for _ in 0..CHALLENGE_ACK_LIMIT + 10 {
+            challenge(&mut connection, &mut nic, now);
+        }
+        assert_eq!(nic.len(), CHALLENGE_ACK_LIMIT as usize);
+
+        // a second later, there's room for more
+        challenge(&mut connection, &mut nic, now + Duration::from_secs(1));
+        assert_eq!(nic.len(), CHALLENGE_ACK_LIMIT as usize + 1);
+    }
+
+    #[test]
+    fn urgent_data_is_pointed_out_until_acked() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+
+        connection.unacked.extend(b"ab");
+        connection.mark_urgent();
+        connection.unacked.extend(b"cd");
+        connection.transmit(&mut nic, now).unwrap();
+        let (_, tcp_header, data) = headers(&nic[0]);
+        assert_eq!(data, b"abcd");
+        assert!(tcp_header.urg());
+        // just past the last urgent byte
+        assert_eq!(tcp_header.urgent_pointer(), 2);
+
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001, Some(5), 65535, false, &[]),
+            now,
+        );
+        nic.clear();
+        connection.unacked.extend(b"ef");
+        connection.transmit(&mut nic, now).unwrap();
+        assert!(!headers(&nic[0]).1.urg());
+    }
+
+    #[test]
+    fn reads_stop_at_the_end_of_urgent_data() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+        assert_eq!(connection.urgent_mark(), None);
+
+        let mut tcp = etherparse::TcpHeader::new(4000, 9000, 1001, 65535);
+        tcp.ack = true;
+        tcp.acknowledgment_number = 1;
+        tcp.urg = true;
+        tcp.urgent_pointer = 3;
+        deliver(&mut connection, &mut nic, &packet(tcp, b"abcdef"), now);
+        assert_eq!(connection.urgent_mark(), Some(3));
+
+        let mut buf = [0u8; 16];
+        assert_eq!(connection.read(&mut buf), 3);
+        assert_eq!(&buf[..3], b"abc");
+        assert_eq!(connection.urgent_mark(), Some(0));
+
+        assert_eq!(connection.read(&mut buf), 3);
+        assert_eq!(&buf[..3], b"def");
+        assert_eq!(connection.urgent_mark(), None);
+    }
+
+    #[test]
+    fn half_closed_connections_keep_reading() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+        connection.unacked.extend([1; 100]);
+        connection.close();
+        assert!(connection.is_send_closed());
+
+        // the FIN goes along with the last of the data
+        connection.transmit(&mut nic, now).unwrap();
+        assert_eq!(nic.len(), 1);
+        let (_, tcp_header, data) = headers(&nic[0]);
+        assert!(tcp_header.fin());
+        assert_eq!(data.len(), 100);
+
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1001, Some(102), 65535, false, &[2; 50]),
+            now,
+        );
+        assert!(matches!(
+            connection.connection_state,
+            ConnectionState::FinWait2
+        ));
+        assert_eq!(connection.incoming.len(), 50);
+        assert!(!connection.is_recv_closed());
+
+        nic.clear();
+        deliver(&mut connection, &mut nic, &fin(1051, 102), now);
+        assert!(matches!(
+            connection.connection_state,
+            ConnectionState::TimeWait
+        ));
+        assert_eq!(headers(&nic[0]).1.acknowledgment_number(), 1052);
+        assert!(connection.is_recv_closed());
+    }
+
+    #[test]
+    fn we_can_keep_sending_after_the_peer_closes() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+
+        deliver(&mut connection, &mut nic, &fin(1001, 1), now);
+        assert!(matches!(
+            connection.connection_state,
+            ConnectionState::CloseWait
+        ));
+        assert_eq!(headers(&nic[0]).1.acknowledgment_number(), 1002);
+        assert!(connection.is_recv_closed());
+        assert!(!connection.is_send_closed());
+
+        nic.clear();
+        connection.unacked.extend([1; 100]);
+        connection.transmit(&mut nic, now).unwrap();
+        assert_eq!(headers(&nic[0]).2.len(), 100);
+        assert!(!headers(&nic[0]).1.fin());
+
+        connection.close();
+        assert!(matches!(
+            connection.connection_state,
+            ConnectionState::LastAck
+        ));
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1002, Some(101), 65535, false, &[]),
+            now,
+        );
+        assert!(headers(nic.last().unwrap()).1.fin());
+
+        deliver(
+            &mut connection,
+            &mut nic,
+            &segment(1002, Some(102), 65535, false, &[]),
+            now,
+        );
+        assert!(matches!(
+            connection.connection_state,
+            ConnectionState::Closed
+        ));
+        assert_eq!(connection.next_deadline(), None);
+    }
+
+    const A: (IpAddr, u16) = (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 5000);
+    const B: (IpAddr, u16) = (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 6000);
+
+    // two connections talking to each other: keep passing on whatever each one sends, until
+    // neither has anything more to say
+    fn exchange(
+        a: &mut TcpState,
+        a_out: &mut Vec<Vec<u8>>,
+        b: &mut TcpState,
+        b_out: &mut Vec<Vec<u8>>,
+        now: Instant,
+    ) {
+        while !a_out.is_empty() || !b_out.is_empty() {
+            for packet in std::mem::take(a_out) {
+                deliver(b, b_out, &packet, now);
+            }
+            for packet in std::mem::take(b_out) {
+                deliver(a, a_out, &packet, now);
+            }
+        }
+    }
+
+    // a connects, b accepts
+    fn connected(now: Instant) -> (TcpState, TcpState) {
+        connected_between(A, B, now)
+    }
+
+    fn connected_between(a: (IpAddr, u16), b: (IpAddr, u16), now: Instant) -> (TcpState, TcpState) {
+        let (mut a_out, mut b_out) = (Vec::new(), Vec::new());
+        let mut a = TcpState::connect(a, b, 0, Algorithm::NewReno, now);
+        a.on_tick(&mut a_out, now).unwrap();
+        let syn = a_out.remove(0);
+        let (ip_header, tcp_header, data) = headers(&syn);
+        let mut b = TcpState::accept(
+            &mut b_out,
+            ip_header,
+            tcp_header,
+            data,
+            0,
+            Algorithm::NewReno,
+            None,
+            now,
+        )
+        .unwrap()
+        .unwrap();
+        exchange(&mut a, &mut a_out, &mut b, &mut b_out, now);
+        (a, b)
+    }
+
+    #[test]
+    fn active_open() {
+        let now = Instant::now();
+        let (a, b) = connected(now);
+        assert!(matches!(a.connection_state, ConnectionState::Estab));
+        assert!(matches!(b.connection_state, ConnectionState::Estab));
+    }
+
+    #[test]
+    fn ipv6_connections() {
+        let now = Instant::now();
+        let a = (IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1)), 5000);
+        let b = (IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2)), 6000);
+        let (mut a, mut b) = connected_between(a, b, now);
+        assert!(matches!(a.connection_state, ConnectionState::Estab));
+        assert!(matches!(b.connection_state, ConnectionState::Estab));
+        // the bigger IPv6 header leaves less room for data
+        assert_eq!(a.mss, 1440);
+        assert_eq!(b.mss, 1440);
+
+        let (mut a_out, mut b_out) = (Vec::new(), Vec::new());
+        a.unacked.extend(b"hello");
+        a.transmit(&mut a_out, now).unwrap();
+        let (ip_header, _, data) = headers(&a_out[0]);
+        assert!(ip_header.source().is_ipv6());
+        assert_eq!(data, b"hello");
+        exchange(&mut a, &mut a_out, &mut b, &mut b_out, now);
+        assert_eq!(b.incoming, b"hello");
+    }
+
+    #[test]
+    fn simultaneous_open() {
+        let now = Instant::now();
+        let (mut a_out, mut b_out) = (Vec::new(), Vec::new());
+        let mut a = TcpState::connect(A, B, 0, Algorithm::NewReno, now);
+        let mut b = TcpState::connect(B, A, 0, Algorithm::NewReno, now);
+        a.on_tick(&mut a_out, now).unwrap();
+        b.on_tick(&mut b_out, now).unwrap();
+
+        // the SYNs cross: each side gets the other's before hearing anything back
+        let (a_syn, b_syn) = (a_out.remove(0), b_out.remove(0));
+        assert!(!headers(&a_syn).1.ack());
+        deliver(&mut b, &mut b_out, &a_syn, now);
+        deliver(&mut a, &mut a_out, &b_syn, now);
+        for (connection, out) in [(&a, &a_out), (&b, &b_out)] {
+            assert!(matches!(
+                connection.connection_state,
+                ConnectionState::SynRcvd
+            ));
+            let (_, tcp_header, _) = headers(&out[0]);
+            assert!(tcp_header.syn() && tcp_header.ack());
+        }
+
+        exchange(&mut a, &mut a_out, &mut b, &mut b_out, now);
+        assert!(matches!(a.connection_state, ConnectionState::Estab));
+        assert!(matches!(b.connection_state, ConnectionState::Estab));
+
+        // and it works
+        a.unacked.extend(b"hello");
+        a.transmit(&mut a_out, now).unwrap();
+        exchange(&mut a, &mut a_out, &mut b, &mut b_out, now);
+        assert_eq!(b.incoming, b"hello");
+    }
+
+    #[test]
+    fn simultaneous_close() {
+        let now = Instant::now();
+        let (mut a, mut b) = connected(now);
+        let (mut a_out, mut b_out) = (Vec::new(), Vec::new());
+
+        a.close();
+        b.close();
+        a.transmit(&mut a_out, now).unwrap();
+        b.transmit(&mut b_out, now).unwrap();
+
+        // the FINs cross
+        let (a_fin, b_fin) = (a_out.remove(0), b_out.remove(0));
+        deliver(&mut b, &mut b_out, &a_fin, now);
+        deliver(&mut a, &mut a_out, &b_fin, now);
+        assert!(matches!(a.connection_state, ConnectionState::Closing));
+        assert!(matches!(b.connection_state, ConnectionState::Closing));
+
+        exchange(&mut a, &mut a_out, &mut b, &mut b_out, now);
+        assert!(matches!(a.connection_state, ConnectionState::TimeWait));
+        assert!(matches!(b.connection_state, ConnectionState::TimeWait));
+    }
+
+    #[test]
+    fn time_wait_lasts_twice_the_msl() {
+        let mut nic = Vec::new();
+        let now = Instant::now();
+        let mut connection = established(&mut nic, now);
+        connection.close();
+        connection.transmit(&mut nic, now).unwrap();
+        deliver(&mut connection, &mut nic, &fin(1001, 2), now);
+        assert!(matches!(
+            connection.connection_state,
+            ConnectionState::TimeWait
+        ));
+        assert_eq!(connection.next_deadline(), Some(now + 2 * MSL));
+
+        // the peer didn't get our ack, so sends its FIN again: we ack it again, and wait longer
+        let later = now + MSL;
+        nic.clear();
+        deliver(&mut connection, &mut nic, &fin(1001, 2), later);
+        assert_eq!(headers(&nic[0]).1.acknowledgment_number(), 1002);
+        connection.on_tick(&mut nic, now + 2 * MSL).unwrap();
+        assert!(!connection.is_finished());
+
+        connection.on_tick(&mut nic, later + 2 * MSL).unwrap();
+        assert!(connection.is_finished());
+        assert_eq!(connection.next_deadline(), None);
+    }
+
+    #[test]
+    fn ecn_is_negotiated_in_the_handshake() {
+        let now = Instant::now();
+        let (mut a_out, mut b_out) = (Vec::new(), Vec::new());
+        let mut a = TcpState::connect(A, B, 0, Algorithm::NewReno, now);
+        a.on_tick(&mut a_out, now).unwrap();
+        let (_, syn, _) = headers(&a_out[0]);
+        assert!(syn.ece() && syn.cwr());
+
+        let (ip_header, tcp_header, data) = headers(&a_out[0]);
+        let mut b = TcpState::accept(
+            &mut b_out,
+            ip_header,
+            tcp_header,
+            data,
+            0,
+            Algorithm::NewReno,
+            None,
+            now,
+        )
+        .unwrap()
+        .unwrap();
+        let (_, syn_ack, _) = headers(&b_out[0]);
+        assert!(syn_ack.ece() && !syn_ack.cwr());
+        a_out.clear();
+        exchange(&mut a, &mut a_out, &mut b, &mut b_out, now);
+        assert!(a.ecn.enabled && b.ecn.enabled);
+
+        // but not with a peer that didn't ask for it
+        let mut nic = Vec::new();
+        let connection = accept(&mut nic, 65535, now);
+        assert!(!connection.ecn.enabled);
+        assert!(!headers(&nic[0]).1.ece());
+    }
+
+    #[test]
+    fn congestion_marks_are_echoed_until_the_sender_slows_down() {
+        let now = Instant::now();
+        let (mut a, mut b) = connected(now);
+        let (mut a_out, mut b_out) = (Vec::new(), Vec::new());
+        b.set_ack_delay(Duration::ZERO);
+
+        a.unacked.extend(vec![7u8; 4 * MSS as usize]);
+        a.transmit(&mut a_out, now).unwrap();
+        assert_eq!(a_out.len(), 3);
+        let cwnd = a.congestion.cwnd();
+
+        // a router on the way marks the first segment
+        let mut marked = a_out.remove(0);
+        assert_eq!(headers(&marked).0.ecn(), Ecn::Ect0);
+        marked[1] |= u8::from(Ecn::Ce);
+        deliver(&mut b, &mut b_out, &marked, now);
+        let (ip_header, ack, _) = headers(&b_out[0]);
+        assert!(ack.ece());
+        assert_eq!(ip_header.ecn(), Ecn::NotEct);
+
+        // the sender slows down as if it had been lost
+        deliver(&mut a, &mut a_out, &b_out.remove(0), now);
+        let reduced = a.congestion.cwnd();
+        assert!(reduced < cwnd);
+
+        // the peer carries on echoing it, but that's still about the same congestion
+        for packet in std::mem::take(&mut a_out) {
+            deliver(&mut b, &mut b_out, &packet, now);
+        }
+        for packet in std::mem::take(&mut b_out) {
+            assert!(headers(&packet).1.ece());
+            deliver(&mut a, &mut a_out, &packet, now);
+        }
+        assert!(a.congestion.cwnd() >= reduced);
+
+        // until the next new data tells it we've slowed down
+        let last = a_out.last().unwrap();
+        assert!(headers(last).1.cwr());
+        for packet in std::mem::take(&mut a_out) {
+            deliver(&mut b, &mut b_out, &packet, now);
+        }
+        assert!(!headers(b_out.last().unwrap()).1.ece());
+    }
+
+    #[test]
+    fn congestion_marks_during_fast_recovery_are_not_reacted_to_again() {
+        let now = Instant::now();
+        let (mut a, mut b) = connected(now);
+        let (mut a_out, mut b_out) = (Vec::new(), Vec::new());
+
+        a.unacked.extend(vec![7u8; 6 * MSS as usize]);
+        a.transmit(&mut a_out, now).unwrap();
+        assert_eq!(a_out.len(), 3);
+
+        // the first segment is lost, and the other two get a duplicate ack each -- one of which
+        // the network happens to deliver twice
+        a_out.remove(0);
+        for packet in std::mem::take(&mut a_out) {
+            deliver(&mut b, &mut b_out, &packet, now);
+        }
+        b_out.push(b_out[1].clone());
+        for packet in std::mem::take(&mut b_out) {
+            deliver(&mut a, &mut a_out, &packet, now);
+        }
+        // the lost segment goes again, and the inflated window lets some new data out too
+        assert_eq!(headers(&a_out[0]).1.sequence_number(), 1);
+        assert!(a_out.len() > 1);
+        let cwnd = a.congestion.cwnd();
+
+        // a router marks some of the new data, and the peer echoes the mark back
+        let mut marked = a_out.remove(1);
+        marked[1] |= u8::from(Ecn::Ce);
+        deliver(&mut b, &mut b_out, &marked, now);
+        assert!(headers(&b_out[0]).1.ece());
+
+        // but fast recovery has already slowed us down for this window
+        deliver(&mut a, &mut a_out, &b_out.remove(0), now);
+        assert!(a.congestion.cwnd() >= cwnd);
+        // though the peer still hears that we have
+        assert!(headers(a_out.last().unwrap()).1.cwr());
+    }
+
+    #[test]
+    fn fast_open_lets_syns_carry_data() {
+        let now = Instant::now();
+        let cookies = FastOpenCookies::default();
+
+        // a fast open connection, as far as the server answering the SYN
+        let open = |cookie: Option<Vec<u8>>, data: &[u8]| {
+            let (mut a_out, mut b_out) = (Vec::new(), Vec::new());
+            let mut a = TcpState::connect(A, B, 0, Algorithm::NewReno, now);
+            a.set_fast_open(cookie);
+            a.unacked.extend(data);
+            a.on_tick(&mut a_out, now).unwrap();
+            let syn = a_out.remove(0);
+            let (ip_header, tcp_header, data) = headers(&syn);
+            let b = TcpState::accept(
+                &mut b_out,
+                ip_header,
+                tcp_header,
+                data,
+                0,
+                Algorithm::NewReno,
+                Some(&cookies),
+                now,
+            )
+            .unwrap()
+            .unwrap();
+            (a, a_out, b, b_out, syn)
+        };
+
+        // the first time, we ask for a cookie, and the data waits for the handshake
+        let (mut a, mut a_out, mut b, mut b_out, syn) = open(None, b"hello");
+        assert!(headers(&syn).2.is_empty());
+        assert!(b.incoming.is_empty());
+        exchange(&mut a, &mut a_out, &mut b, &mut b_out, now);
+        assert_eq!(b.incoming, b"hello");
+        let cookie = a.fast_open_cookie().unwrap().to_vec();
+
+        // with the cookie, the data comes with the SYN, and can be read before the handshake is
+        // done
+        let (mut a, mut a_out, mut b, mut b_out, syn) = open(Some(cookie.clone()), b"again");
+        assert_eq!(headers(&syn).2, b"again");
+        assert!(b.is_connecting());
+        assert_eq!(b.incoming, b"again");
+        exchange(&mut a, &mut a_out, &mut b, &mut b_out, now);
+        assert!(a.unacked.is_empty());
+        assert_eq!(b.incoming, b"again");
+
+        // a cookie that's no good gets the data ignored (and sent again after the handshake),
+        // and a new cookie in its place
+        let (mut a, mut a_out, mut b, mut b_out, syn) = open(Some(vec![0; 8]), b"stale");
+        assert_eq!(headers(&syn).2, b"stale");
+        assert!(b.incoming.is_empty());
+        exchange(&mut a, &mut a_out, &mut b, &mut b_out, now);
+        assert_eq!(b.incoming, b"stale");
+        assert_eq!(a.fast_open_cookie(), Some(&cookie[..]));
+    }
+
+    #[test]
+    fn rto_follows_rtt_samples() {
+        let mut timers = Timers::new(Instant::now());
+        assert_eq!(timers.rto, INITIAL_RTO);
+
+        timers.on_rtt_sample(Duration::from_millis(800));
+        // 800ms + 4 * 400ms
+        assert_eq!(timers.rto, Duration::from_millis(2400));
+
+        timers.on_rtt_sample(Duration::from_millis(800));
+        // 800ms + 4 * 300ms
+        assert_eq!(timers.rto, Duration::from_millis(2000));
+
+        // but never below a second
+        for _ in 0..50 {
+            timers.on_rtt_sample(Duration::from_millis(10));
+        }
+        assert_eq!(timers.rto, MIN_RTO);
     }
-}
 
-#[cfg(test)]
-mod tests {
     #[test]
     fn ack_acceptance() {
         let una = 0;
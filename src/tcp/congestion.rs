@@ -0,0 +1,181 @@
+use crate::tcp::wrapping_lt;
+use std::time::{Duration, Instant};
+
+mod bbr;
+mod cubic;
+mod newreno;
+
+pub use bbr::Bbr;
+pub use cubic::Cubic;
+pub use newreno::NewReno;
+
+// Congestion control (RFC 5681)
+//
+// The peer's advertised window only tells us how much *it* can take -- it says nothing about how
+// much the network in between can carry. Without some notion of that, we will happily send a full
+// receive window's worth of data in one burst, and on a slow or shared path most of it gets
+// dropped.
+//
+// A congestion controller keeps its own window (cwnd), and the sender may only ever have
+// min(cwnd, rwnd) bytes in flight. The controller gets told about everything that tells us
+// something about the state of the network -- acks, losses, timeouts, round trip times -- and
+// adjusts cwnd to match.
+
+// Everything a congestion controller gets to know about an incoming acknowledgment
+#[derive(Debug, Clone, Copy)]
+pub struct Ack {
+    // SEG.ACK
+    pub ack: u32,
+    // how many bytes of sequence space this segment newly acknowledged (zero for a duplicate)
+    pub bytes_acked: u32,
+    // how much was outstanding (SND.NXT - SND.UNA) before this segment arrived
+    pub flight_size: u32,
+    // SND.NXT at the time the segment arrived
+    pub snd_nxt: u32,
+    // the segment acknowledged nothing new, carried no data, and did not change the window, while
+    // we had data outstanding (RFC 5681 S2)
+    pub duplicate: bool,
+    pub now: Instant,
+}
+
+// What the sender should do once the controller has seen an ack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckResponse {
+    // nothing special -- just send whatever the (possibly new) window allows
+    Continue,
+    // the segment starting at SND.UNA is presumed lost, and should be sent again straight away
+    Retransmit,
+}
+
+pub trait CongestionControl: Send {
+    // how many bytes the controller currently allows to be in flight
+    fn cwnd(&self) -> u32;
+
+    // how fast (in bytes per second) segments should be sent out, for controllers that want them
+    // spread out over the round trip rather than sent as fast as the window opens
+    fn pacing_rate(&self) -> Option<f64> {
+        None
+    }
+
+    fn on_ack(&mut self, ack: &Ack) -> AckResponse;
+
+    // whether the controller is in fast recovery, having already cut its window for a loss in the
+    // current window of data
+    fn in_recovery(&self) -> bool {
+        false
+    }
+
+    // something other than the retransmission timer (duplicate acks, for example) has told us that
+    // the network dropped some of our data
+    fn on_loss(&mut self, flight_size: u32, now: Instant);
+
+    // the retransmission timer went off -- everything in flight (up to SND.MAX) is presumed lost
+    fn on_rto(&mut self, flight_size: u32, snd_max: u32, now: Instant);
+
+    fn on_rtt_sample(&mut self, rtt: Duration, now: Instant);
+}
+
+// RFC 5681 S3.1: IW = min (4*SMSS, max (2*SMSS, 4380 bytes))
+pub fn initial_window(mss: u32) -> u32 {
+    std::cmp::min(4 * mss, std::cmp::max(2 * mss, 4380))
+}
+
+// Which congestion controller a connection should use
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Algorithm {
+    #[default]
+    NewReno,
+    Cubic,
+    Bbr,
+}
+
+impl Algorithm {
+    pub fn build(self, mss: u32) -> Box<dyn CongestionControl> {
+        match self {
+            Algorithm::NewReno => Box::new(NewReno::new(mss)),
+            Algorithm::Cubic => Box::new(Cubic::new(mss)),
+            Algorithm::Bbr => Box::new(Bbr::new(mss)),
+        }
+    }
+}
+
+// Where an ack leaves us as far as fast retransmit and fast recovery are concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecoveryEvent {
+    // an ordinary ack, outside of recovery -- the window can grow as usual
+    Open,
+    // a duplicate that doesn't (yet) mean anything
+    Ignore,
+    // the third duplicate ack: retransmit, and shrink the window
+    Enter,
+    // a further duplicate during recovery: another segment has left the network
+    Inflate,
+    // new data was acked, but there is still a hole below the recovery point
+    PartialAck,
+    // everything outstanding at the time of the loss has been acked
+    Exit,
+}
+
+// Duplicate ack counting, and keeping track of fast recovery (RFC 6582)
+//
+// This is the same whatever the controller does with its window, so each controller keeps one of
+// these, and just decides what each event means for cwnd.
+#[derive(Debug, Default)]
+struct FastRecovery {
+    dup_acks: u32,
+    // SND.NXT at the point we last entered fast recovery ("recover" in RFC 6582)
+    recover: Option<u32>,
+    in_recovery: bool,
+}
+
+impl FastRecovery {
+    fn on_ack(&mut self, ack: &Ack) -> RecoveryEvent {
+        if ack.duplicate {
+            if self.in_recovery {
+                return RecoveryEvent::Inflate;
+            }
+
+            self.dup_acks += 1;
+            if self.dup_acks != 3 {
+                return RecoveryEvent::Ignore;
+            }
+
+            // only start a new recovery if this ack covers more than the last one did, otherwise
+            // we would shrink the window again for losses we have already reacted to
+            // (RFC 6582 S3.2 step 2)
+            if let Some(recover) = self.recover {
+                if !wrapping_lt(recover, ack.ack.wrapping_sub(1)) {
+                    return RecoveryEvent::Ignore;
+                }
+            }
+
+            self.recover = Some(ack.snd_nxt);
+            self.in_recovery = true;
+            return RecoveryEvent::Enter;
+        }
+
+        self.dup_acks = 0;
+        if !self.in_recovery {
+            return RecoveryEvent::Open;
+        }
+
+        let recover = self
+            .recover
+            .expect("fast recovery always records a recovery point");
+        if wrapping_lt(ack.ack, recover) {
+            RecoveryEvent::PartialAck
+        } else {
+            self.in_recovery = false;
+            RecoveryEvent::Exit
+        }
+    }
+
+    // the retransmission timer went off, which trumps any recovery in progress. duplicate acks for
+    // what we had sent by then are down to the retransmissions, not to fresh losses, so they don't
+    // start a fast retransmit (RFC 6582 S4.1)
+    fn on_rto(&mut self, snd_max: u32) {
+        self.dup_acks = 0;
+        self.in_recovery = false;
+        self.recover = Some(snd_max);
+    }
+}
@@ -0,0 +1,452 @@
+use super::{initial_window, Ack, AckResponse, CongestionControl, FastRecovery, RecoveryEvent};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// BBR, version 1 (draft-cardwell-iccrg-bbr-congestion-control-00)
+//
+// The loss-based controllers take a dropped segment to mean the network is full. On a lossy link
+// (wireless, say) segments get dropped whether it is full or not, and they end up with a tiny
+// window and an idle link.
+//
+// BBR instead builds a model of the path from two measurements: the bottleneck bandwidth (the
+// fastest we have seen data get delivered lately) and the round trip propagation time (the
+// shortest round trip we have seen lately). Their product is how much data the path can hold
+// without a queue building up anywhere. BBR paces segments out at about the bottleneck rate, and
+// keeps about twice that much in flight -- losses don't come into it.
+//
+// It moves through a few modes:
+//  * Startup: double the sending rate every round trip until the bandwidth stops going up
+//  * Drain: send slower than the bottleneck for a bit, to empty the queue startup built up
+//  * ProbeBW: send at the bottleneck rate, but every few round trips try a bit faster (in case
+//    there is more bandwidth now) and then a bit slower (to drain whatever queue that made)
+//  * ProbeRTT: if the round trip estimate hasn't been refreshed in a while, cut right back for a
+//    moment so any queue drains and we can measure it again
+pub struct Bbr {
+    mss: u32,
+    mode: Mode,
+    cwnd: u32,
+    // the window from before recovery or ProbeRTT, to go back to afterwards
+    prior_cwnd: u32,
+    pacing_gain: f64,
+    cwnd_gain: f64,
+
+    // the bottleneck bandwidth: the highest delivery rate (in bytes per second) of the last few
+    // rounds
+    bw_samples: VecDeque<(u64, f64)>,
+    btl_bw: f64,
+    // the round trip propagation time: the shortest round trip of the last few seconds
+    min_rtt: Option<Duration>,
+    min_rtt_stamp: Option<Instant>,
+
+    // a round trip ends once everything that had been sent when it started has been acked
+    round_count: u64,
+    round_end: Option<u32>,
+    round_start: Option<Instant>,
+    round_delivered: u64,
+
+    // startup has filled the pipe once the bandwidth stops growing by at least a quarter a round
+    full_bw: f64,
+    full_bw_rounds: u32,
+    filled_pipe: bool,
+
+    // where we are in the ProbeBW gain cycle, and when we got there
+    cycle_index: usize,
+    cycle_start: Option<Instant>,
+    // ProbeRTT lasts at least PROBE_RTT_DURATION and a whole round trip, both counted from when
+    // the flight first shrinks to the reduced window
+    probe_rtt_done: Option<Instant>,
+    probe_rtt_round_done: bool,
+
+    recovery: FastRecovery,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Startup,
+    Drain,
+    ProbeBw,
+    ProbeRtt,
+}
+
+// 2/ln(2): the smallest gain that still doubles the sending rate every round trip
+const HIGH_GAIN: f64 = 2.885;
+const PROBE_BW_GAINS: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+const PROBE_BW_CWND_GAIN: f64 = 2.0;
+const BTL_BW_ROUNDS: u64 = 10;
+const MIN_RTT_WINDOW: Duration = Duration::from_secs(10);
+const PROBE_RTT_DURATION: Duration = Duration::from_millis(200);
+const MIN_CWND_SEGMENTS: u32 = 4;
+
+impl Bbr {
+    pub fn new(mss: u32) -> Self {
+        Bbr {
+            mss,
+            mode: Mode::Startup,
+            cwnd: initial_window(mss),
+            prior_cwnd: 0,
+            pacing_gain: HIGH_GAIN,
+            cwnd_gain: HIGH_GAIN,
+            bw_samples: VecDeque::new(),
+            btl_bw: 0.0,
+            min_rtt: None,
+            min_rtt_stamp: None,
+            round_count: 0,
+            round_end: None,
+            round_start: None,
+            round_delivered: 0,
+            full_bw: 0.0,
+            full_bw_rounds: 0,
+            filled_pipe: false,
+            cycle_index: 0,
+            cycle_start: None,
+            probe_rtt_done: None,
+            probe_rtt_round_done: false,
+            recovery: FastRecovery::default(),
+        }
+    }
+
+    fn min_cwnd(&self) -> u32 {
+        MIN_CWND_SEGMENTS * self.mss
+    }
+
+    // the bandwidth-delay product: how much the path holds without queueing
+    fn bdp(&self) -> Option<f64> {
+        match self.min_rtt {
+            Some(min_rtt) if self.btl_bw > 0.0 => Some(self.btl_bw * min_rtt.as_secs_f64()),
+            _ => None,
+        }
+    }
+
+    // count bytes delivered this round, and when the round ends, take its delivery rate as a
+    // bandwidth sample. returns whether a round ended
+    fn update_round(&mut self, ack: &Ack) -> bool {
+        self.round_delivered += ack.bytes_acked as u64;
+
+        if let Some(round_end) = self.round_end {
+            if crate::tcp::wrapping_lt(ack.ack, round_end) {
+                return false;
+            }
+        }
+
+        if let Some(round_start) = self.round_start {
+            let elapsed = ack.now.saturating_duration_since(round_start).as_secs_f64();
+            if elapsed > 0.0 {
+                let rate = self.round_delivered as f64 / elapsed;
+                self.bw_samples.push_back((self.round_count, rate));
+            }
+        }
+        self.round_count += 1;
+        while let Some(&(round, _)) = self.bw_samples.front() {
+            if round + BTL_BW_ROUNDS > self.round_count {
+                break;
+            }
+            self.bw_samples.pop_front();
+        }
+        self.btl_bw = self
+            .bw_samples
+            .iter()
+            .map(|&(_, rate)| rate)
+            .fold(0.0, f64::max);
+
+        self.round_end = Some(ack.snd_nxt);
+        self.round_start = Some(ack.now);
+        self.round_delivered = 0;
+        true
+    }
+
+    fn check_full_pipe(&mut self) {
+        if self.filled_pipe {
+            return;
+        }
+        if self.btl_bw >= self.full_bw * 1.25 {
+            self.full_bw = self.btl_bw;
+            self.full_bw_rounds = 0;
+            return;
+        }
+        self.full_bw_rounds += 1;
+        if self.full_bw_rounds >= 3 {
+            self.filled_pipe = true;
+        }
+    }
+
+    fn enter_probe_bw(&mut self, now: Instant) {
+        self.mode = Mode::ProbeBw;
+        self.cwnd_gain = PROBE_BW_CWND_GAIN;
+        // start off cruising, rather than draining a queue we haven't built yet
+        self.cycle_index = 2;
+        self.cycle_start = Some(now);
+        self.pacing_gain = PROBE_BW_GAINS[self.cycle_index];
+    }
+
+    fn update_mode(&mut self, ack: &Ack, round_ended: bool) {
+        let in_flight = ack.flight_size.saturating_sub(ack.bytes_acked) as f64;
+
+        match self.mode {
+            Mode::Startup if self.filled_pipe => {
+                self.mode = Mode::Drain;
+                self.pacing_gain = 1.0 / HIGH_GAIN;
+                self.cwnd_gain = HIGH_GAIN;
+            }
+            Mode::Drain => {
+                if self.bdp().is_some_and(|bdp| in_flight <= bdp) {
+                    self.enter_probe_bw(ack.now);
+                }
+            }
+            Mode::ProbeBw => {
+                let min_rtt = self.min_rtt.unwrap_or(Duration::ZERO);
+                let cycle_start = self.cycle_start.unwrap_or(ack.now);
+                if ack.now.saturating_duration_since(cycle_start) > min_rtt {
+                    self.cycle_index = (self.cycle_index + 1) % PROBE_BW_GAINS.len();
+                    self.cycle_start = Some(ack.now);
+                    self.pacing_gain = PROBE_BW_GAINS[self.cycle_index];
+                }
+            }
+            Mode::ProbeRtt => {
+                let Some(done) = self.probe_rtt_done else {
+                    if in_flight <= self.min_cwnd() as f64 {
+                        // start the clock, and a fresh round: the one in progress began with a
+                        // full window out
+                        self.probe_rtt_done = Some(ack.now + PROBE_RTT_DURATION);
+                        self.probe_rtt_round_done = false;
+                        self.round_end = Some(ack.snd_nxt);
+                    }
+                    return;
+                };
+                if round_ended {
+                    self.probe_rtt_round_done = true;
+                }
+                if self.probe_rtt_round_done && ack.now >= done {
+                    self.cwnd = std::cmp::max(self.cwnd, self.prior_cwnd);
+                    if self.filled_pipe {
+                        self.enter_probe_bw(ack.now);
+                    } else {
+                        self.mode = Mode::Startup;
+                        self.pacing_gain = HIGH_GAIN;
+                        self.cwnd_gain = HIGH_GAIN;
+                    }
+                }
+            }
+            Mode::Startup => {}
+        }
+    }
+
+    fn update_cwnd(&mut self, ack: &Ack) {
+        if let Mode::ProbeRtt = self.mode {
+            self.cwnd = std::cmp::min(self.cwnd, self.min_cwnd());
+            return;
+        }
+
+        match self.bdp() {
+            Some(bdp) => {
+                let target = std::cmp::max((self.cwnd_gain * bdp) as u32, self.min_cwnd());
+                if self.filled_pipe {
+                    self.cwnd = std::cmp::min(self.cwnd + ack.bytes_acked, target);
+                } else if self.cwnd < target {
+                    self.cwnd += ack.bytes_acked;
+                }
+            }
+            // no model yet, so grow as slow start would
+            None => self.cwnd += ack.bytes_acked,
+        }
+        self.cwnd = std::cmp::max(self.cwnd, self.min_cwnd());
+    }
+}
+
+impl CongestionControl for Bbr {
+    fn cwnd(&self) -> u32 {
+        self.cwnd
+    }
+
+    fn pacing_rate(&self) -> Option<f64> {
+        if self.btl_bw > 0.0 {
+            return Some(self.pacing_gain * self.btl_bw);
+        }
+        // no bandwidth sample until the first round is over, so until then take the initial
+        // window per round trip as the bandwidth -- pacing from the very first ack
+        let min_rtt = self.min_rtt.filter(|rtt| !rtt.is_zero())?;
+        Some(self.pacing_gain * initial_window(self.mss) as f64 / min_rtt.as_secs_f64())
+    }
+
+    fn on_ack(&mut self, ack: &Ack) -> AckResponse {
+        let (response, in_recovery) = match self.recovery.on_ack(ack) {
+            RecoveryEvent::Ignore => return AckResponse::Continue,
+            RecoveryEvent::Enter => {
+                // packet conservation: only send as segments leave the network, until recovery is
+                // over
+                self.prior_cwnd = self.cwnd;
+                self.cwnd = std::cmp::max(ack.flight_size, self.min_cwnd());
+                return AckResponse::Retransmit;
+            }
+            RecoveryEvent::Inflate => {
+                self.cwnd += self.mss;
+                return AckResponse::Continue;
+            }
+            RecoveryEvent::PartialAck => (AckResponse::Retransmit, true),
+            RecoveryEvent::Exit => {
+                self.cwnd = std::cmp::max(self.cwnd, self.prior_cwnd);
+                (AckResponse::Continue, false)
+            }
+            RecoveryEvent::Open => (AckResponse::Continue, false),
+        };
+
+        let round_ended = self.update_round(ack);
+        if round_ended {
+            self.check_full_pipe();
+        }
+        self.update_mode(ack, round_ended);
+        if !in_recovery {
+            self.update_cwnd(ack);
+        }
+
+        response
+    }
+
+    fn in_recovery(&self) -> bool {
+        self.recovery.in_recovery
+    }
+
+    // a lost segment on its own says nothing about the bandwidth or the round trip, so the model
+    // stays as it is -- recovery has already kept us from sending more than is getting through
+    fn on_loss(&mut self, _flight_size: u32, _now: Instant) {}
+
+    fn on_rto(&mut self, _flight_size: u32, snd_max: u32, _now: Instant) {
+        self.prior_cwnd = self.cwnd;
+        self.cwnd = self.mss;
+        self.recovery.on_rto(snd_max);
+    }
+
+    fn on_rtt_sample(&mut self, rtt: Duration, now: Instant) {
+        let expired = self
+            .min_rtt_stamp
+            .is_none_or(|stamp| now.saturating_duration_since(stamp) > MIN_RTT_WINDOW);
+
+        if self.min_rtt.is_none_or(|min_rtt| rtt <= min_rtt) || expired {
+            self.min_rtt = Some(rtt);
+            self.min_rtt_stamp = Some(now);
+        }
+
+        if expired && self.mode != Mode::ProbeRtt {
+            if self.btl_bw == 0.0 {
+                // nothing to probe yet -- this was our first sample
+                return;
+            }
+            self.mode = Mode::ProbeRtt;
+            self.pacing_gain = 1.0;
+            self.prior_cwnd = self.cwnd;
+            self.cwnd = std::cmp::min(self.cwnd, self.min_cwnd());
+            self.probe_rtt_done = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: u32 = 1000;
+    const RTT: Duration = Duration::from_millis(100);
+    // the bottleneck: 1MB/s, so the pipe holds 100KB
+    const RATE: f64 = 1_000_000.0;
+
+    // a sender that keeps a full window in flight over a path with the bottleneck above, and
+    // feeds the controller one ack per segment delivered
+    fn run(cc: &mut Bbr, start: Instant, acks: usize) -> Instant {
+        let mut now = start;
+        let mut una: u32 = 0;
+        for _ in 0..acks {
+            let cwnd = cc.cwnd();
+            let rate = f64::min(cwnd as f64 / RTT.as_secs_f64(), RATE);
+            now += Duration::from_secs_f64(MSS as f64 / rate);
+            una = una.wrapping_add(MSS);
+
+            // any more than the pipe holds sits in the bottleneck queue
+            let rtt = Duration::from_secs_f64(f64::max(RTT.as_secs_f64(), cwnd as f64 / RATE));
+            cc.on_rtt_sample(rtt, now);
+            cc.on_ack(&Ack {
+                ack: una,
+                bytes_acked: MSS,
+                flight_size: cwnd,
+                snd_nxt: una.wrapping_add(cwnd),
+                duplicate: false,
+                now,
+            });
+        }
+        now
+    }
+
+    #[test]
+    fn startup_finds_the_bottleneck() {
+        let mut cc = Bbr::new(MSS);
+        run(&mut cc, Instant::now(), 2000);
+
+        assert!(cc.filled_pipe);
+        assert_ne!(cc.mode, Mode::Startup);
+        assert!(
+            cc.btl_bw > 0.9 * RATE && cc.btl_bw < 1.1 * RATE,
+            "{}",
+            cc.btl_bw
+        );
+        assert_eq!(cc.min_rtt, Some(RTT));
+        assert!(cc.pacing_rate().is_some());
+    }
+
+    #[test]
+    fn losses_do_not_shrink_the_model() {
+        let mut cc = Bbr::new(MSS);
+        let now = run(&mut cc, Instant::now(), 2000);
+        let btl_bw = cc.btl_bw;
+
+        cc.on_loss(cc.cwnd(), now);
+        assert_eq!(cc.btl_bw, btl_bw);
+        assert_eq!(cc.pacing_rate(), Some(cc.pacing_gain * btl_bw));
+    }
+
+    #[test]
+    fn stale_min_rtt_triggers_probe_rtt() {
+        let mut cc = Bbr::new(MSS);
+        let now = run(&mut cc, Instant::now(), 2000);
+
+        // ten seconds on, with a queue keeping every sample above the old minimum
+        let later = now + MIN_RTT_WINDOW + Duration::from_millis(1);
+        cc.on_rtt_sample(RTT * 2, later);
+        assert_eq!(cc.mode, Mode::ProbeRtt);
+        assert_eq!(cc.cwnd(), MIN_CWND_SEGMENTS * MSS);
+        assert_eq!(cc.min_rtt, Some(RTT * 2));
+
+        // the clock starts once the flight is down to the reduced window, and a round trip starts
+        // with it
+        let prior_cwnd = cc.prior_cwnd;
+        let una = 2000 * MSS;
+        let ack = |ack: u32, now: Instant| Ack {
+            ack,
+            bytes_acked: MSS,
+            flight_size: 5 * MSS,
+            snd_nxt: una + 8 * MSS,
+            duplicate: false,
+            now,
+        };
+        cc.on_ack(&ack(una + MSS, later));
+        assert_eq!(cc.probe_rtt_done, Some(later + PROBE_RTT_DURATION));
+
+        // 200ms on its own isn't enough: that round trip has to be over as well
+        cc.on_ack(&ack(una + 2 * MSS, later + PROBE_RTT_DURATION));
+        assert_eq!(cc.mode, Mode::ProbeRtt);
+        assert_eq!(cc.cwnd(), MIN_CWND_SEGMENTS * MSS);
+
+        // and once that's done, we go back to where we were
+        cc.on_ack(&ack(una + 8 * MSS, later + PROBE_RTT_DURATION * 2));
+        assert_eq!(cc.mode, Mode::ProbeBw);
+        assert!(cc.cwnd() >= std::cmp::min(prior_cwnd, 2 * 100 * MSS));
+    }
+
+    #[test]
+    fn pacing_starts_with_the_first_rtt_sample() {
+        let mut cc = Bbr::new(MSS);
+        assert_eq!(cc.pacing_rate(), None);
+
+        // the handshake gives us a round trip, but no bandwidth sample yet
+        cc.on_rtt_sample(RTT, Instant::now());
+        let rate = HIGH_GAIN * initial_window(MSS) as f64 / RTT.as_secs_f64();
+        assert_eq!(cc.pacing_rate(), Some(rate));
+    }
+}
@@ -0,0 +1,287 @@
+use super::{initial_window, Ack, AckResponse, CongestionControl, FastRecovery, RecoveryEvent};
+use std::time::{Duration, Instant};
+
+// CUBIC (RFC 9438)
+//
+// Reno grows its window by one segment per round trip, so on a path with lots of bandwidth and a
+// long round trip it can take minutes to get back up to speed after a single loss. CUBIC instead
+// grows the window as a cubic function of the time since the last loss: quickly at first, then
+// levelling off as it gets back to the window where the loss happened (W_max), then probing
+// beyond it, slowly at first and then more and more quickly. As the growth depends on time rather
+// than on acks, it doesn't matter how long the round trip is.
+//
+// On short paths, where Reno would actually do better, CUBIC keeps track of the window Reno would
+// have had, and never uses less than that (the Reno-friendly region).
+pub struct Cubic {
+    mss: u32,
+    // the window and ssthresh are kept in segments, which is the unit the cubic function works in
+    cwnd: f64,
+    ssthresh: f64,
+    // the window just before the last reduction, less a bit if it keeps falling (fast convergence)
+    w_max: f64,
+    // when the current congestion avoidance stage started
+    epoch_start: Option<Instant>,
+    // how long (in seconds) the cubic function takes to get from the window at the start of the
+    // epoch back to w_max
+    k: f64,
+    // the window Reno would have by now, had it started the epoch alongside us
+    w_est: f64,
+    srtt: Option<Duration>,
+    recovery: FastRecovery,
+}
+
+// RFC 9438 S5: C = 0.4, beta_cubic = 0.7
+const C: f64 = 0.4;
+const BETA: f64 = 0.7;
+// the additive increase that gives the same average window as Reno does (RFC 9438 S4.3)
+const ALPHA: f64 = 3.0 * (1.0 - BETA) / (1.0 + BETA);
+
+impl Cubic {
+    pub fn new(mss: u32) -> Self {
+        let cwnd = initial_window(mss) as f64 / mss as f64;
+        Cubic {
+            mss,
+            cwnd,
+            ssthresh: f64::INFINITY,
+            w_max: cwnd,
+            epoch_start: None,
+            k: 0.0,
+            w_est: cwnd,
+            srtt: None,
+            recovery: FastRecovery::default(),
+        }
+    }
+
+    // W_cubic(t) = C*(t-K)^3 + W_max (RFC 9438 S4.2)
+    fn w_cubic(&self, t: f64) -> f64 {
+        C * (t - self.k).powi(3) + self.w_max
+    }
+
+    fn segments(&self, bytes: u32) -> f64 {
+        bytes as f64 / self.mss as f64
+    }
+
+    fn begin_epoch(&mut self, now: Instant) {
+        self.epoch_start = Some(now);
+        self.w_est = self.cwnd;
+        if self.cwnd < self.w_max {
+            self.k = ((self.w_max - self.cwnd) / C).cbrt();
+        } else {
+            // we never got cut back below where we are (after slow start, say), so just start
+            // probing from here
+            self.k = 0.0;
+            self.w_max = self.cwnd;
+        }
+    }
+
+    fn congestion_avoidance(&mut self, ack: &Ack) {
+        let epoch_start = match self.epoch_start {
+            Some(epoch_start) => epoch_start,
+            None => {
+                self.begin_epoch(ack.now);
+                ack.now
+            }
+        };
+
+        let segments_acked = self.segments(ack.bytes_acked);
+        let t = ack.now.saturating_duration_since(epoch_start).as_secs_f64();
+        let rtt = self.srtt.unwrap_or(Duration::ZERO).as_secs_f64();
+
+        // Reno-friendly region (S4.3): once we're past the old maximum, Reno would be growing by
+        // a full segment per round trip
+        let alpha = if self.w_est >= self.w_max { 1.0 } else { ALPHA };
+        self.w_est += alpha * segments_acked / self.cwnd;
+        if self.w_cubic(t) < self.w_est {
+            self.cwnd = self.w_est;
+            return;
+        }
+
+        // concave and convex regions (S4.4, S4.5): aim for where the cubic function will be in a
+        // round trip's time, but never more than half as much again as we have now
+        let target = self.w_cubic(t + rtt).clamp(self.cwnd, 1.5 * self.cwnd);
+        self.cwnd += (target - self.cwnd) / self.cwnd * segments_acked;
+    }
+
+    // the multiplicative decrease (S4.6), and fast convergence (S4.7): if the window is falling
+    // short of where it got to last time, another flow has probably joined, so give it up sooner
+    fn reduce(&mut self) {
+        self.w_max = if self.cwnd < self.w_max {
+            self.cwnd * (1.0 + BETA) / 2.0
+        } else {
+            self.cwnd
+        };
+        self.ssthresh = f64::max(self.cwnd * BETA, 2.0);
+        self.epoch_start = None;
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn cwnd(&self) -> u32 {
+        (self.cwnd * self.mss as f64) as u32
+    }
+
+    fn on_ack(&mut self, ack: &Ack) -> AckResponse {
+        match self.recovery.on_ack(ack) {
+            RecoveryEvent::Ignore => AckResponse::Continue,
+            RecoveryEvent::Enter => {
+                self.on_loss(ack.flight_size, ack.now);
+                self.cwnd = self.ssthresh + 3.0;
+                AckResponse::Retransmit
+            }
+            RecoveryEvent::Inflate => {
+                self.cwnd += 1.0;
+                AckResponse::Continue
+            }
+            RecoveryEvent::PartialAck => {
+                self.cwnd = f64::max(self.cwnd - self.segments(ack.bytes_acked), 1.0);
+                if ack.bytes_acked >= self.mss {
+                    self.cwnd += 1.0;
+                }
+                AckResponse::Retransmit
+            }
+            RecoveryEvent::Exit => {
+                let flight_size = self.segments(ack.flight_size.saturating_sub(ack.bytes_acked));
+                self.cwnd = f64::min(self.ssthresh, f64::max(flight_size, 1.0) + 1.0);
+                AckResponse::Continue
+            }
+            RecoveryEvent::Open => {
+                if self.cwnd < self.ssthresh {
+                    // slow start, just as in Reno
+                    self.cwnd += f64::min(self.segments(ack.bytes_acked), 1.0);
+                } else {
+                    self.congestion_avoidance(ack);
+                }
+                AckResponse::Continue
+            }
+        }
+    }
+
+    fn in_recovery(&self) -> bool {
+        self.recovery.in_recovery
+    }
+
+    fn on_loss(&mut self, _flight_size: u32, _now: Instant) {
+        self.reduce();
+        self.cwnd = self.ssthresh;
+    }
+
+    fn on_rto(&mut self, _flight_size: u32, snd_max: u32, _now: Instant) {
+        self.reduce();
+        // the loss window: start again from a single segment (S4.8)
+        self.cwnd = 1.0;
+        self.recovery.on_rto(snd_max);
+    }
+
+    fn on_rtt_sample(&mut self, rtt: Duration, _now: Instant) {
+        self.srtt = Some(match self.srtt {
+            None => rtt,
+            Some(srtt) => srtt * 7 / 8 + rtt / 8,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: u32 = 1000;
+
+    fn ack(bytes_acked: u32, now: Instant) -> Ack {
+        Ack {
+            ack: bytes_acked,
+            bytes_acked,
+            flight_size: 100 * MSS,
+            snd_nxt: 100 * MSS,
+            duplicate: false,
+            now,
+        }
+    }
+
+    fn after_loss_at(cwnd: f64, now: Instant) -> Cubic {
+        let mut cc = Cubic::new(MSS);
+        cc.cwnd = cwnd;
+        cc.on_loss(cc.cwnd(), now);
+        cc
+    }
+
+    #[test]
+    fn loss_reduces_the_window_by_beta() {
+        let cc = after_loss_at(100.0, Instant::now());
+        assert_eq!(cc.w_max, 100.0);
+        assert!((cc.cwnd - 70.0).abs() < 1e-9);
+        assert_eq!(cc.cwnd(), 70 * MSS);
+    }
+
+    #[test]
+    fn fast_convergence_gives_up_bandwidth_sooner() {
+        let now = Instant::now();
+        let mut cc = after_loss_at(100.0, now);
+
+        // the next loss comes before we've got back to the old maximum
+        cc.cwnd = 80.0;
+        cc.on_loss(cc.cwnd(), now);
+        assert!((cc.w_max - 68.0).abs() < 1e-9);
+        assert!((cc.cwnd - 56.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn window_plateaus_at_the_old_maximum() {
+        let now = Instant::now();
+        let mut cc = after_loss_at(100.0, now);
+        cc.on_ack(&ack(MSS, now));
+        // K = cbrt((100 - 70) / 0.4)
+        assert!((cc.k - 75f64.cbrt()).abs() < 1e-9);
+
+        // halfway to K, we're most of the way back (concave region)
+        let halfway = now + Duration::from_secs_f64(cc.k / 2.0);
+        for _ in 0..1000 {
+            cc.on_ack(&ack(MSS, halfway));
+        }
+        let expected = cc.w_cubic(cc.k / 2.0);
+        assert!(
+            (cc.cwnd - expected).abs() < 0.5,
+            "{} vs {}",
+            cc.cwnd,
+            expected
+        );
+        assert!(cc.cwnd > 95.0 && cc.cwnd < 100.0);
+
+        // and at K we sit at W_max, however many acks arrive
+        let at_k = now + Duration::from_secs_f64(cc.k);
+        for _ in 0..1000 {
+            cc.on_ack(&ack(MSS, at_k));
+        }
+        assert!(cc.cwnd <= 100.0 + 1e-9);
+        assert!(cc.cwnd > 99.5);
+
+        // beyond K we probe for more (convex region)
+        let beyond = now + Duration::from_secs_f64(cc.k + 5.0);
+        for _ in 0..1000 {
+            cc.on_ack(&ack(MSS, beyond));
+        }
+        assert!(cc.cwnd > 140.0);
+    }
+
+    #[test]
+    fn reno_friendly_region() {
+        let now = Instant::now();
+        let mut cc = after_loss_at(100.0, now);
+
+        // with no time passing, the cubic function stays where it started, but a window's worth
+        // of acks would have grown Reno by alpha segments
+        for _ in 0..70 {
+            cc.on_ack(&ack(MSS, now));
+        }
+        assert!(cc.cwnd > 70.0 + ALPHA * 0.9);
+        assert_eq!(cc.cwnd, cc.w_est);
+    }
+
+    #[test]
+    fn timeout_collapses_the_window() {
+        let mut cc = Cubic::new(MSS);
+        cc.cwnd = 100.0;
+        cc.on_rto(100 * MSS, 100 * MSS, Instant::now());
+        assert_eq!(cc.cwnd(), MSS);
+        assert!((cc.ssthresh - 70.0).abs() < 1e-9);
+    }
+}
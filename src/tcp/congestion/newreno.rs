@@ -0,0 +1,224 @@
+use super::{initial_window, Ack, AckResponse, CongestionControl, FastRecovery, RecoveryEvent};
+use std::time::{Duration, Instant};
+
+// NewReno (RFC 5681, with the fast recovery changes from RFC 6582)
+//
+// Below ssthresh we are in slow start, and cwnd grows by (up to) one segment for every ack -- so it
+// doubles every round trip. Above ssthresh we are in congestion avoidance, and cwnd grows by about
+// one segment per round trip.
+//
+// Three duplicate acks mean a segment was lost but later ones are still getting through, so we
+// retransmit the missing segment straight away (fast retransmit), halve the window, and stay in
+// fast recovery until everything that was outstanding at the time of the loss has been acked.
+pub struct NewReno {
+    mss: u32,
+    cwnd: u32,
+    ssthresh: u32,
+    recovery: FastRecovery,
+    // bytes acked since cwnd last grew in congestion avoidance (RFC 5681 S3.1, byte counting)
+    bytes_acked: u32,
+}
+
+impl NewReno {
+    pub fn new(mss: u32) -> Self {
+        NewReno {
+            mss,
+            cwnd: initial_window(mss),
+            ssthresh: u32::MAX,
+            recovery: FastRecovery::default(),
+            bytes_acked: 0,
+        }
+    }
+}
+
+impl CongestionControl for NewReno {
+    fn cwnd(&self) -> u32 {
+        self.cwnd
+    }
+
+    fn on_ack(&mut self, ack: &Ack) -> AckResponse {
+        match self.recovery.on_ack(ack) {
+            RecoveryEvent::Ignore => AckResponse::Continue,
+            RecoveryEvent::Enter => {
+                self.on_loss(ack.flight_size, ack.now);
+                // the three duplicates mean three segments have left the network
+                self.cwnd = self.ssthresh + 3 * self.mss;
+                AckResponse::Retransmit
+            }
+            RecoveryEvent::Inflate => {
+                // every duplicate ack means another segment has left the network, so we can
+                // artificially inflate the window to let a new one in (RFC 6582 S3.2 step 4)
+                self.cwnd += self.mss;
+                AckResponse::Continue
+            }
+            RecoveryEvent::PartialAck => {
+                // the next hole starts at the new SND.UNA, so send it again, and deflate the
+                // window by what left the network (RFC 6582 S3.2 step 5)
+                self.cwnd = self.cwnd.saturating_sub(ack.bytes_acked);
+                if ack.bytes_acked >= self.mss {
+                    self.cwnd += self.mss;
+                }
+                AckResponse::Retransmit
+            }
+            RecoveryEvent::Exit => {
+                let flight_size = ack.flight_size.saturating_sub(ack.bytes_acked);
+                self.cwnd = std::cmp::min(
+                    self.ssthresh,
+                    std::cmp::max(flight_size, self.mss) + self.mss,
+                );
+                AckResponse::Continue
+            }
+            RecoveryEvent::Open => {
+                if self.cwnd < self.ssthresh {
+                    // slow start
+                    self.cwnd += std::cmp::min(ack.bytes_acked, self.mss);
+                } else {
+                    // congestion avoidance
+                    self.bytes_acked += ack.bytes_acked;
+                    if self.bytes_acked >= self.cwnd {
+                        self.bytes_acked -= self.cwnd;
+                        self.cwnd += self.mss;
+                    }
+                }
+                AckResponse::Continue
+            }
+        }
+    }
+
+    fn in_recovery(&self) -> bool {
+        self.recovery.in_recovery
+    }
+
+    fn on_loss(&mut self, flight_size: u32, _now: Instant) {
+        // RFC 5681 S3.1, equation (4)
+        self.ssthresh = std::cmp::max(flight_size / 2, 2 * self.mss);
+        self.cwnd = self.ssthresh;
+        self.bytes_acked = 0;
+    }
+
+    fn on_rto(&mut self, flight_size: u32, snd_max: u32, _now: Instant) {
+        self.ssthresh = std::cmp::max(flight_size / 2, 2 * self.mss);
+        // the loss window: start again from a single segment
+        self.cwnd = self.mss;
+        self.bytes_acked = 0;
+        self.recovery.on_rto(snd_max);
+    }
+
+    fn on_rtt_sample(&mut self, _rtt: Duration, _now: Instant) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: u32 = 1000;
+
+    fn ack(ack: u32, bytes_acked: u32, flight_size: u32, snd_nxt: u32) -> Ack {
+        Ack {
+            ack,
+            bytes_acked,
+            flight_size,
+            snd_nxt,
+            duplicate: bytes_acked == 0,
+            now: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn slow_start_then_congestion_avoidance() {
+        let mut cc = NewReno::new(MSS);
+        assert_eq!(cc.cwnd(), 4 * MSS);
+
+        // every ack grows the window by a segment
+        cc.on_ack(&ack(MSS, MSS, 4 * MSS, 4 * MSS));
+        assert_eq!(cc.cwnd(), 5 * MSS);
+
+        cc.ssthresh = 5 * MSS;
+        // above ssthresh, it takes a full window of acks to grow by a segment
+        for i in 0..4 {
+            cc.on_ack(&ack((i + 2) * MSS, MSS, 5 * MSS, 10 * MSS));
+            assert_eq!(cc.cwnd(), 5 * MSS);
+        }
+        cc.on_ack(&ack(6 * MSS, MSS, 5 * MSS, 10 * MSS));
+        assert_eq!(cc.cwnd(), 6 * MSS);
+    }
+
+    #[test]
+    fn fast_retransmit_and_recovery() {
+        let mut cc = NewReno::new(MSS);
+        cc.cwnd = 10 * MSS;
+
+        // segments 0..10 are outstanding, and the first one goes missing
+        assert_eq!(
+            cc.on_ack(&ack(0, 0, 10 * MSS, 10 * MSS)),
+            AckResponse::Continue
+        );
+        assert_eq!(
+            cc.on_ack(&ack(0, 0, 10 * MSS, 10 * MSS)),
+            AckResponse::Continue
+        );
+        assert_eq!(
+            cc.on_ack(&ack(0, 0, 10 * MSS, 10 * MSS)),
+            AckResponse::Retransmit
+        );
+        assert_eq!(cc.ssthresh, 5 * MSS);
+        assert_eq!(cc.cwnd(), 8 * MSS);
+
+        // more duplicates inflate the window
+        cc.on_ack(&ack(0, 0, 10 * MSS, 10 * MSS));
+        assert_eq!(cc.cwnd(), 9 * MSS);
+
+        // a partial ack means another hole
+        assert_eq!(
+            cc.on_ack(&ack(3 * MSS, 3 * MSS, 10 * MSS, 10 * MSS)),
+            AckResponse::Retransmit
+        );
+        assert_eq!(cc.cwnd(), 7 * MSS);
+
+        // a full ack ends recovery, with cwnd sized to what is still in flight
+        assert_eq!(
+            cc.on_ack(&ack(10 * MSS, 7 * MSS, 7 * MSS, 10 * MSS)),
+            AckResponse::Continue
+        );
+        assert_eq!(cc.cwnd(), 2 * MSS);
+        assert!(!cc.recovery.in_recovery);
+    }
+
+    #[test]
+    fn no_second_reduction_for_the_same_loss() {
+        let mut cc = NewReno::new(MSS);
+        cc.cwnd = 10 * MSS;
+        for _ in 0..3 {
+            cc.on_ack(&ack(0, 0, 10 * MSS, 10 * MSS));
+        }
+        cc.on_ack(&ack(10 * MSS, 10 * MSS, 10 * MSS, 10 * MSS));
+        let cwnd = cc.cwnd();
+
+        // duplicates that don't get past the recovery point must not trigger another one
+        for _ in 0..3 {
+            assert_eq!(
+                cc.on_ack(&ack(10 * MSS, 0, 5 * MSS, 15 * MSS)),
+                AckResponse::Continue
+            );
+        }
+        assert_eq!(cc.cwnd(), cwnd);
+    }
+
+    #[test]
+    fn timeout_collapses_the_window() {
+        let mut cc = NewReno::new(MSS);
+        cc.cwnd = 10 * MSS;
+        cc.on_rto(10 * MSS, 10 * MSS, Instant::now());
+        assert_eq!(cc.cwnd(), MSS);
+        assert_eq!(cc.ssthresh, 5 * MSS);
+
+        // duplicates for what was in flight at the timeout don't start a fast retransmit
+        for _ in 0..3 {
+            assert_eq!(
+                cc.on_ack(&ack(MSS, 0, 9 * MSS, 10 * MSS)),
+                AckResponse::Continue
+            );
+        }
+        assert!(!cc.recovery.in_recovery);
+    }
+}
@@ -0,0 +1,68 @@
+// TCP Fast Open cookies (RFC 7413 S4.1)
+//
+// A client that has connected to us before can send data along with its SYN, and have it handed
+// to the application straight away, rather than a round trip later. Anyone can put anything in a
+// SYN from any address they like though, so we only take the data from a client that can show it
+// really is at the address it's sending from: by giving back a cookie we sent that address in the
+// SYN-ACK of an earlier connection. The cookie is a keyed hash of the address, so there's nothing
+// to remember in between.
+
+use super::secret::SecretKey;
+use std::net::IpAddr;
+
+pub const COOKIE_LEN: usize = 8;
+
+pub struct FastOpenCookies {
+    // a cookie is only proof of the address if nobody but us can work out which one goes with it --
+    // anyone who could would be able to have their data taken from any address they liked
+    key: SecretKey,
+}
+
+impl Default for FastOpenCookies {
+    fn default() -> Self {
+        FastOpenCookies {
+            key: SecretKey::random(),
+        }
+    }
+}
+
+impl FastOpenCookies {
+    pub fn cookie(&self, client: IpAddr) -> [u8; COOKIE_LEN] {
+        self.key.hash_one(client).to_be_bytes()
+    }
+
+    pub fn validate(&self, client: IpAddr, cookie: &[u8]) -> bool {
+        // look at every byte, however early a wrong one turns up: if we gave up at the first, how
+        // long we took would tell a client how much of its guess was right, and it could work the
+        // cookie out a byte at a time
+        cookie.len() == COOKIE_LEN
+            && cookie
+                .iter()
+                .zip(self.cookie(client))
+                .fold(0, |difference, (a, b)| difference | (a ^ b))
+                == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn cookies_only_work_from_their_own_address() {
+        let cookies = FastOpenCookies::default();
+        let client = Ipv4Addr::new(192, 168, 0, 2).into();
+        let cookie = cookies.cookie(client);
+        assert!(cookies.validate(client, &cookie));
+        assert!(!cookies.validate(Ipv4Addr::new(192, 168, 0, 3).into(), &cookie));
+        assert!(!cookies.validate(client, &[]));
+        assert!(!cookies.validate(client, &cookie[..COOKIE_LEN - 1]));
+        let mut wrong = cookie;
+        wrong[COOKIE_LEN - 1] ^= 1;
+        assert!(!cookies.validate(client, &wrong));
+
+        // or with the server that handed them out
+        assert!(!FastOpenCookies::default().validate(client, &cookie));
+    }
+}
@@ -0,0 +1,241 @@
+// The IP layer, as far as a connection is concerned
+//
+// A connection runs over IPv4 or IPv6, depending on the addresses at either end. Either way, all it
+// needs to know about an incoming segment's IP header is who it's from, who it's for, and whether
+// a router on the way marked it; and all it needs to send one is a header to put in front of it,
+// and the right pseudo-header to checksum it with (RFC 9293 S3.1, RFC 8200 S8.1).
+
+use crate::network_parse::{Ecn, IPv6Header};
+use std::io;
+use std::net::IpAddr;
+
+// the biggest packet we send: what we assume any path can take
+pub const MTU: usize = 1500;
+
+// the smallest MTU any path may have (RFC 791 S3.1 -- though RFC 1122 S3.3.3 says any host can take
+// 576 bytes, so nobody should be telling us to go lower -- and RFC 8200 S5)
+const MIN_MTU_V4: u32 = 576;
+const MIN_MTU_V6: u32 = 1280;
+
+// what we fall back to when big packets go missing without a word from any router: as much as any
+// path can realistically be counted on to take (RFC 4821 S7.2)
+const BASE_MTU_V4: u32 = 1024;
+const BASE_MTU_V6: u32 = MIN_MTU_V6;
+
+const TCP_HEADER_LEN: usize = 20;
+
+const HOP_LIMIT: u8 = 64;
+
+// what a segment's IP header tells us, whichever version of IP it came over
+#[derive(Debug, Clone, Copy)]
+pub struct IpHeader {
+    source: IpAddr,
+    destination: IpAddr,
+    ecn: Ecn,
+}
+
+impl IpHeader {
+    pub fn source(&self) -> IpAddr {
+        self.source
+    }
+
+    pub fn destination(&self) -> IpAddr {
+        self.destination
+    }
+
+    pub fn ecn(&self) -> Ecn {
+        self.ecn
+    }
+}
+
+impl From<&etherparse::Ipv4HeaderSlice<'_>> for IpHeader {
+    fn from(ip_header: &etherparse::Ipv4HeaderSlice) -> Self {
+        IpHeader {
+            source: ip_header.source_addr().into(),
+            destination: ip_header.destination_addr().into(),
+            ecn: Ecn::from(ip_header.ecn()),
+        }
+    }
+}
+
+impl From<&IPv6Header> for IpHeader {
+    fn from(ip_header: &IPv6Header) -> Self {
+        IpHeader {
+            source: ip_header.source_address.into(),
+            destination: ip_header.destination_address.into(),
+            ecn: ip_header.ecn(),
+        }
+    }
+}
+
+// the IP header every segment on a connection goes out with
+pub enum Header {
+    V4(etherparse::Ipv4Header),
+    V6(etherparse::Ipv6Header),
+}
+
+impl Header {
+    // an IPv4 address talking to an IPv6 one can only be doing so as an IPv4-mapped address. over
+    // IPv4, routers aren't to fragment what we send, but tell us it's too big (RFC 1191 S3)
+    pub fn new(local: IpAddr, remote: IpAddr) -> Self {
+        let tcp = etherparse::ip_number::TCP;
+        match (local, remote) {
+            (IpAddr::V4(local), IpAddr::V4(remote)) => {
+                let mut ip =
+                    etherparse::Ipv4Header::new(0, HOP_LIMIT, tcp, local.octets(), remote.octets());
+                ip.dont_fragment = true;
+                Header::V4(ip)
+            }
+            (local, remote) => Header::V6(etherparse::Ipv6Header {
+                traffic_class: 0,
+                flow_label: 0,
+                payload_length: 0,
+                next_header: tcp,
+                hop_limit: HOP_LIMIT,
+                source: ipv6_octets(local),
+                destination: ipv6_octets(remote),
+            }),
+        }
+    }
+
+    pub fn header_len(&self) -> usize {
+        match self {
+            Header::V4(ip) => ip.header_len(),
+            Header::V6(ip) => ip.header_len(),
+        }
+    }
+
+    // the most data that fits in one segment, once the headers are in
+    pub fn mss(&self) -> u32 {
+        self.mss_for(MTU as u32)
+    }
+
+    // the same, on a path with an MTU of mtu
+    pub fn mss_for(&self, mtu: u32) -> u32 {
+        self.clamp_mtu(mtu) - (self.header_len() + TCP_HEADER_LEN) as u32
+    }
+
+    // no path has an MTU bigger than our own link's, or less than any path can take, whatever
+    // anyone says
+    pub fn clamp_mtu(&self, mtu: u32) -> u32 {
+        let min_mtu = match self {
+            Header::V4(_) => MIN_MTU_V4,
+            Header::V6(_) => MIN_MTU_V6,
+        };
+        mtu.clamp(min_mtu, MTU as u32)
+    }
+
+    pub fn base_mtu(&self) -> u32 {
+        match self {
+            Header::V4(_) => BASE_MTU_V4,
+            Header::V6(_) => BASE_MTU_V6,
+        }
+    }
+
+    pub fn set_ecn(&mut self, ecn: Ecn) {
+        match self {
+            Header::V4(ip) => ip.explicit_congestion_notification = u8::from(ecn),
+            Header::V6(ip) => ip.traffic_class = (ip.traffic_class & !0b11) | u8::from(ecn),
+        }
+    }
+
+    pub fn set_payload_len(&mut self, len: usize) {
+        match self {
+            Header::V4(ip) => ip.set_payload_len(len),
+            Header::V6(ip) => ip.set_payload_length(len),
+        }
+        .expect("payload always fits in an ip packet");
+    }
+
+    pub fn checksum(&self, tcp: &etherparse::TcpHeader, payload: &[u8]) -> u16 {
+        match self {
+            Header::V4(ip) => tcp.calc_checksum_ipv4(ip, payload),
+            Header::V6(ip) => tcp.calc_checksum_ipv6(ip, payload),
+        }
+        .expect("failed to compute checksum")
+    }
+
+    pub fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Header::V4(ip) => ip.write(writer),
+            Header::V6(ip) => ip.write(writer),
+        }
+        .map_err(io::Error::other)
+    }
+}
+
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    // the ones' complement sum of a packet, in 16 bit words
+    fn sum(bytes: &[u8]) -> u32 {
+        bytes
+            .chunks(2)
+            .map(|word| u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32)
+            .sum()
+    }
+
+    #[test]
+    fn ipv6_segments_are_checksummed_with_the_ipv6_pseudo_header() {
+        let local = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+        let remote = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+        let mut ip = Header::new(local.into(), remote.into());
+        assert_eq!(ip.mss(), 1440);
+
+        let payload = b"hello";
+        let mut tcp = etherparse::TcpHeader::new(9000, 4000, 1, 1024);
+        ip.set_payload_len(tcp.header_len() as usize + payload.len());
+        tcp.checksum = ip.checksum(&tcp, payload);
+
+        let mut segment = Vec::new();
+        tcp.write(&mut segment).unwrap();
+        segment.extend_from_slice(payload);
+
+        // source, destination, upper-layer length, and next header
+        let mut pseudo_header = Vec::new();
+        pseudo_header.extend(local.octets());
+        pseudo_header.extend(remote.octets());
+        pseudo_header.extend((segment.len() as u32).to_be_bytes());
+        pseudo_header.extend([0, 0, 0, etherparse::ip_number::TCP]);
+
+        let mut total = sum(&pseudo_header) + sum(&segment);
+        while total > 0xffff {
+            total = (total & 0xffff) + (total >> 16);
+        }
+        assert_eq!(total, 0xffff);
+    }
+
+    #[test]
+    fn ipv4_packets_are_not_to_be_fragmented() {
+        let ip = Header::new(
+            Ipv4Addr::new(192, 168, 0, 1).into(),
+            Ipv4Addr::new(192, 168, 0, 2).into(),
+        );
+        let mut packet = Vec::new();
+        ip.write(&mut packet).unwrap();
+        let header = etherparse::Ipv4HeaderSlice::from_slice(&packet).unwrap();
+        assert!(header.dont_fragment());
+        assert_eq!(ip.mss_for(1400), 1360);
+        // (nor can anyone talk us down past the minimum)
+        assert_eq!(ip.mss_for(100), 536);
+    }
+
+    #[test]
+    fn ecn_goes_in_the_bottom_of_the_traffic_class() {
+        let mut ip = Header::new(Ipv6Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into());
+        ip.set_ecn(Ecn::Ect0);
+        let mut packet = Vec::new();
+        ip.write(&mut packet).unwrap();
+        let (_, header) = crate::network_parse::parse_ipv6(&packet).unwrap();
+        assert_eq!(header.ecn(), Ecn::Ect0);
+    }
+}
@@ -0,0 +1,75 @@
+// Initial sequence numbers (RFC 6528)
+//
+// Anyone who can guess the ISN of a connection can inject data into it, or reset it, without ever
+// seeing a segment of it -- so ISNs mustn't be predictable. They mustn't repeat quickly either, or
+// segments from an old connection between the same ports could be taken for part of a new one.
+// RFC 6528 gets both by adding a secret, per-connection offset to a clock:
+//
+//   ISN = M + F(localip, localport, remoteip, remoteport, secretkey)
+//
+// where M goes up once every 4 microseconds, and F is a keyed hash nobody outside can work out.
+// Each connection's ISNs go up with the clock, but knowing one tells you nothing about another.
+
+use super::secret::SecretKey;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// how often M goes up by one
+const TICK: Duration = Duration::from_micros(4);
+
+type Endpoint = (IpAddr, u16);
+
+pub struct IsnGenerator {
+    // the secretkey of F: if it could be guessed, so could every ISN we've picked from one we've
+    // sent, and RFC 6528 would be no better than the clock alone
+    key: SecretKey,
+    epoch: Instant,
+}
+
+impl Default for IsnGenerator {
+    fn default() -> Self {
+        IsnGenerator::new(Instant::now())
+    }
+}
+
+impl IsnGenerator {
+    pub fn new(now: Instant) -> Self {
+        IsnGenerator {
+            key: SecretKey::random(),
+            epoch: now,
+        }
+    }
+
+    pub fn generate(&self, local: Endpoint, remote: Endpoint, now: Instant) -> u32 {
+        let m = (now.saturating_duration_since(self.epoch).as_nanos() / TICK.as_nanos()) as u32;
+        m.wrapping_add(self.key.hash_one((local, remote)) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const LOCAL: Endpoint = (IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)), 9000);
+    const REMOTE: Endpoint = (IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)), 4000);
+
+    #[test]
+    fn isns_go_up_with_the_clock() {
+        let now = Instant::now();
+        let isns = IsnGenerator::new(now);
+        let isn = isns.generate(LOCAL, REMOTE, now);
+        let later = now + Duration::from_millis(1);
+        assert_eq!(isns.generate(LOCAL, REMOTE, later), isn.wrapping_add(250));
+    }
+
+    #[test]
+    fn each_connection_has_its_own_isns() {
+        let now = Instant::now();
+        let isns = IsnGenerator::new(now);
+        let isn = isns.generate(LOCAL, REMOTE, now);
+        assert_ne!(isns.generate(LOCAL, (REMOTE.0, 4001), now), isn);
+        // nor can one interface's be worked out from another's
+        assert_ne!(IsnGenerator::new(now).generate(LOCAL, REMOTE, now), isn);
+    }
+}
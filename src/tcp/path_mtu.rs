@@ -0,0 +1,229 @@
+// Path MTU discovery (RFC 1191, RFC 8201), and packetization layer path MTU discovery (RFC 4821)
+//
+// We send everything with Don't Fragment set, as big as our own link allows, and count on routers
+// to tell us with an ICMP error when a packet is too big for the next link on the way. The path MTU
+// they give us is cached for a while, so other connections to the same place start out with it.
+//
+// Those errors don't always make it back though: plenty of firewalls drop ICMP, and then every
+// full-sized segment disappears without a trace. So when the retransmission timer keeps going off
+// with big segments in flight, we drop down to a size any path should take, and search our way back
+// up by sending the odd segment bigger than the rest -- a probe -- and seeing whether it gets acked.
+// A probe that's lost is only a sign it was too big, not that the network is congested.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// how long before we try for a bigger MTU than we've been told the path has (RFC 1191 S6.3, RFC
+// 4821 S7.7)
+const SEARCH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// a search stops once it's this close to the biggest MTU that works
+const SEARCH_GRANULARITY: u32 = 32;
+
+// the path MTU a connection sends with, and how it's searching for a bigger one
+pub struct PathMtu {
+    // what we send with. as far as we know, every packet this big gets through
+    mtu: u32,
+    // where the search starts from when everything big goes missing (RFC 4821's base_pmtu)
+    base: u32,
+    // the largest we could ever send, and the smallest we think doesn't get through (RFC 4821's
+    // search_high)
+    max: u32,
+    high: u32,
+    probe: Option<Probe>,
+    // when to try for a bigger MTU again, once a search has finished
+    search_at: Option<Instant>,
+}
+
+// a probe in flight: the segment from seq up to end, sent in a packet of mtu bytes
+#[derive(Debug, Clone, Copy)]
+struct Probe {
+    seq: u32,
+    end: u32,
+    mtu: u32,
+}
+
+impl PathMtu {
+    pub fn new(base: u32, max: u32) -> Self {
+        PathMtu {
+            mtu: max,
+            base,
+            max,
+            high: max,
+            probe: None,
+            search_at: None,
+        }
+    }
+
+    pub fn mtu(&self) -> u32 {
+        self.mtu
+    }
+
+    // a router has told us the path can take no more than mtu. returns whether anything we have in
+    // flight may have been too big: a probe, or everything, if we're sending too big to begin with
+    pub fn on_packet_too_big(&mut self, mtu: u32, now: Instant) -> bool {
+        let probed = self.probe.take().is_some();
+        self.high = std::cmp::min(self.high, mtu);
+        if mtu >= self.mtu {
+            return probed;
+        }
+        self.mtu = mtu;
+        self.search_at = Some(now + SEARCH_INTERVAL);
+        true
+    }
+
+    // the retransmission timer has gone off too many times with big segments in flight, which
+    // looks like a path that drops them without telling us. returns whether we went any smaller
+    pub fn on_black_hole(&mut self) -> bool {
+        if self.mtu <= self.base {
+            return false;
+        }
+        self.probe = None;
+        self.high = self.mtu;
+        self.mtu = self.base;
+        self.search_at = None;
+        true
+    }
+
+    // the MTU to send a probe with, if it's time for one
+    pub fn next_probe(&mut self, now: Instant) -> Option<u32> {
+        if self.probe.is_some() {
+            return None;
+        }
+        if self.search_at.is_some_and(|at| now >= at) {
+            // it's been long enough that the path may have changed
+            self.search_at = None;
+            self.high = self.max;
+        }
+        if self.search_at.is_some() || self.high - self.mtu <= SEARCH_GRANULARITY {
+            return None;
+        }
+        Some((self.mtu + self.high) / 2)
+    }
+
+    pub fn on_probe_sent(&mut self, seq: u32, end: u32, mtu: u32) {
+        self.probe = Some(Probe { seq, end, mtu });
+    }
+
+    // SND.UNA has moved up to ack. returns whether that got a probe through, so we can send with
+    // its MTU from now on
+    pub fn on_ack(&mut self, ack: u32, now: Instant) -> bool {
+        let Some(probe) = self.probe else {
+            return false;
+        };
+        if super::wrapping_lt(ack, probe.end) {
+            return false;
+        }
+        self.probe = None;
+        self.mtu = probe.mtu;
+        self.finish_search(now);
+        true
+    }
+
+    // whether the probe in flight is the segment at seq
+    pub fn is_probe(&self, seq: u32) -> bool {
+        self.probe.is_some_and(|probe| probe.seq == seq)
+    }
+
+    // we're sending the segment at seq again. returns whether that's because the probe there was
+    // lost -- and not some other segment, which would say nothing about the probe, so it may as
+    // well be sent again later
+    pub fn on_retransmit(&mut self, seq: u32, now: Instant) -> bool {
+        let Some(probe) = self.probe.take() else {
+            return false;
+        };
+        if seq != probe.seq {
+            return false;
+        }
+        self.high = probe.mtu;
+        self.finish_search(now);
+        true
+    }
+
+    fn finish_search(&mut self, now: Instant) {
+        if self.high - self.mtu <= SEARCH_GRANULARITY {
+            self.search_at = Some(now + SEARCH_INTERVAL);
+        }
+    }
+}
+
+// The path MTUs routers have told us about, by destination
+#[derive(Default)]
+pub struct PathMtuCache {
+    mtus: HashMap<IpAddr, (u32, Instant)>,
+}
+
+impl PathMtuCache {
+    pub fn get(&self, destination: IpAddr, now: Instant) -> Option<u32> {
+        let (mtu, expires) = self.mtus.get(&destination)?;
+        (now < *expires).then_some(*mtu)
+    }
+
+    // (an entry only ever gets smaller, until it expires)
+    pub fn insert(&mut self, destination: IpAddr, mtu: u32, now: Instant) {
+        let mtu = self
+            .get(destination, now)
+            .map_or(mtu, |cached| std::cmp::min(cached, mtu));
+        self.mtus.insert(destination, (mtu, now + SEARCH_INTERVAL));
+    }
+
+    pub fn on_tick(&mut self, now: Instant) {
+        self.mtus.retain(|_, (_, expires)| now < *expires);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probes_search_for_the_biggest_mtu_that_gets_through() {
+        let now = Instant::now();
+        let mut path_mtu = PathMtu::new(1024, 1500);
+        assert_eq!(path_mtu.next_probe(now), None);
+
+        // big packets go missing
+        assert!(path_mtu.on_black_hole());
+        assert_eq!(path_mtu.mtu(), 1024);
+        assert!(!path_mtu.on_black_hole());
+
+        // this path takes 1400
+        let mut seq = 0;
+        while let Some(mtu) = path_mtu.next_probe(now) {
+            path_mtu.on_probe_sent(seq, seq + 100, mtu);
+            if mtu <= 1400 {
+                assert!(path_mtu.on_ack(seq + 100, now));
+            } else {
+                assert!(path_mtu.on_retransmit(seq, now));
+            }
+            seq += 100;
+        }
+        assert!(path_mtu.mtu() <= 1400 && path_mtu.mtu() > 1400 - SEARCH_GRANULARITY);
+
+        // and has another go later on
+        assert!(path_mtu.next_probe(now + SEARCH_INTERVAL).is_some());
+    }
+
+    #[test]
+    fn other_retransmissions_dont_count_against_probes() {
+        let now = Instant::now();
+        let mut path_mtu = PathMtu::new(1024, 1500);
+        path_mtu.on_black_hole();
+        let mtu = path_mtu.next_probe(now).unwrap();
+        path_mtu.on_probe_sent(1000, 2000, mtu);
+        assert!(!path_mtu.on_retransmit(500, now));
+        assert_eq!(path_mtu.next_probe(now), Some(mtu));
+    }
+
+    #[test]
+    fn cached_mtus_expire() {
+        let now = Instant::now();
+        let mut cache = PathMtuCache::default();
+        let destination = IpAddr::from([192, 168, 0, 2]);
+        cache.insert(destination, 1400, now);
+        cache.insert(destination, 1450, now);
+        assert_eq!(cache.get(destination, now), Some(1400));
+        assert_eq!(cache.get(destination, now + SEARCH_INTERVAL), None);
+    }
+}
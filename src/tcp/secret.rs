@@ -0,0 +1,138 @@
+// Secret keys, and the keyed hash they go with
+//
+// SYN cookies, fast open cookies and initial sequence numbers all rest on a value nobody outside
+// can work out for themselves, however many others they've seen. That takes a pseudorandom
+// function with a key that really is secret: 128 bits from the OS, and SipHash-2-4 (which was made
+// for just this sort of thing) to hash with it.
+//
+// std's RandomState won't do, even though it hashes with SipHash too: its keys are only drawn from
+// the OS once per thread, and every one after that is the last plus one.
+
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+
+pub struct SecretKey([u64; 2]);
+
+impl SecretKey {
+    pub fn random() -> Self {
+        let mut bytes = [0; 16];
+        File::open("/dev/urandom")
+            .and_then(|mut random| random.read_exact(&mut bytes))
+            .expect("the OS has random numbers to give");
+        SecretKey::from_bytes(bytes)
+    }
+
+    fn from_bytes(bytes: [u8; 16]) -> Self {
+        let (k0, k1) = bytes.split_at(8);
+        SecretKey([
+            u64::from_le_bytes(k0.try_into().unwrap()),
+            u64::from_le_bytes(k1.try_into().unwrap()),
+        ])
+    }
+
+    pub fn hash_one<T: Hash>(&self, value: T) -> u64 {
+        let mut hasher = SipHasher::new(self);
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+// SipHash-2-4 (https://cr.yp.to/siphash/siphash-20120918.pdf): two rounds for each 8 bytes of
+// input, and four to finish
+#[derive(Clone, Copy)]
+struct SipHasher {
+    v: [u64; 4],
+    // the input that doesn't make up a whole 8 bytes yet, and how much there has been altogether
+    tail: u64,
+    length: usize,
+}
+
+impl SipHasher {
+    fn new(key: &SecretKey) -> Self {
+        let [k0, k1] = key.0;
+        SipHasher {
+            v: [
+                k0 ^ 0x736f6d6570736575,
+                k1 ^ 0x646f72616e646f6d,
+                k0 ^ 0x6c7967656e657261,
+                k1 ^ 0x7465646279746573,
+            ],
+            tail: 0,
+            length: 0,
+        }
+    }
+
+    fn round(&mut self) {
+        let [v0, v1, v2, v3] = &mut self.v;
+        *v0 = v0.wrapping_add(*v1);
+        *v1 = v1.rotate_left(13) ^ *v0;
+        *v0 = v0.rotate_left(32);
+        *v2 = v2.wrapping_add(*v3);
+        *v3 = v3.rotate_left(16) ^ *v2;
+        *v0 = v0.wrapping_add(*v3);
+        *v3 = v3.rotate_left(21) ^ *v0;
+        *v2 = v2.wrapping_add(*v1);
+        *v1 = v1.rotate_left(17) ^ *v2;
+        *v2 = v2.rotate_left(32);
+    }
+
+    fn compress(&mut self, m: u64) {
+        self.v[3] ^= m;
+        self.round();
+        self.round();
+        self.v[0] ^= m;
+    }
+}
+
+impl Hasher for SipHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.tail |= (byte as u64) << (8 * (self.length % 8));
+            self.length += 1;
+            if self.length.is_multiple_of(8) {
+                self.compress(self.tail);
+                self.tail = 0;
+            }
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        let mut state = *self;
+        state.compress(((self.length as u64 & 0xff) << 56) | self.tail);
+        state.v[2] ^= 0xff;
+        for _ in 0..4 {
+            state.round();
+        }
+        let [v0, v1, v2, v3] = state.v;
+        v0 ^ v1 ^ v2 ^ v3
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn siphash_matches_the_reference() {
+        // the test vectors from the paper's reference implementation: key 00..0f, and messages
+        // 00, 01, .. of every length
+        let key = SecretKey::from_bytes(std::array::from_fn(|i| i as u8));
+        let hash = |len: usize| {
+            let mut hasher = SipHasher::new(&key);
+            hasher.write(&(0..len as u8).collect::<Vec<_>>());
+            hasher.finish()
+        };
+        assert_eq!(hash(0), 0x726fdb47dd0e0e31);
+        assert_eq!(hash(8), 0x93f5f5799a932462);
+        assert_eq!(hash(15), 0xa129ca6149be45e5);
+    }
+
+    #[test]
+    fn random_keys_differ() {
+        assert_ne!(
+            SecretKey::random().hash_one(1234),
+            SecretKey::random().hash_one(1234)
+        );
+    }
+}
@@ -0,0 +1,172 @@
+// SYN cookies
+//
+// Every SYN we answer normally gets a whole connection set up for it, which sits there until the
+// handshake finishes or times out -- so anyone who can send us SYNs faster than that can use up as
+// much memory as they like. When a listener has too many handshakes on the go, we answer SYNs
+// without keeping anything at all: what we'd need to know later goes into the ISN of our SYN-ACK,
+// and a peer that really wants the connection hands it back to us in its ack.
+//
+// The ISN is laid out much as Linux does it:
+//
+//   bits 31-27: a counter that goes up every minute or so, so old cookies stop working
+//   bits 26-25: which of a few common MSS values the peer's SYN asked for
+//   bits 24-0:  a keyed hash of the connection, the peer's ISN and the counter, so nobody else can
+//               make a cookie up
+
+use super::secret::SecretKey;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// how often the counter goes up, and how many times it can have since a cookie was handed out
+// before the cookie is too old to use
+const PERIOD: Duration = Duration::from_secs(64);
+const MAX_AGE: u32 = 2;
+
+const COUNTER_SHIFT: u32 = 27;
+const MSS_SHIFT: u32 = 25;
+const HASH_MASK: u32 = (1 << MSS_SHIFT) - 1;
+
+// the segment sizes a cookie can remember, smallest first (the first is the RFC 9293 default)
+const MSS_TABLE: [u32; 4] = [536, 1300, 1440, 1460];
+
+type Endpoint = (IpAddr, u16);
+
+pub struct SynCookies {
+    // signs each cookie: without it, anyone could work out the cookie for any address and port they
+    // liked, and open connections from them that we'd never sent a SYN-ACK to
+    key: SecretKey,
+    epoch: Instant,
+    // when we last handed one out -- until we've done that, no ack can be carrying one
+    last_sent: Option<Instant>,
+}
+
+impl Default for SynCookies {
+    fn default() -> Self {
+        SynCookies::new(Instant::now())
+    }
+}
+
+impl SynCookies {
+    pub fn new(now: Instant) -> Self {
+        SynCookies {
+            key: SecretKey::random(),
+            epoch: now,
+            last_sent: None,
+        }
+    }
+
+    fn counter(&self, now: Instant) -> u32 {
+        (now.saturating_duration_since(self.epoch).as_secs() / PERIOD.as_secs()) as u32
+    }
+
+    fn hash(&self, local: Endpoint, remote: Endpoint, peer_isn: u32, counter: u32) -> u32 {
+        self.key.hash_one((local, remote, peer_isn, counter)) as u32 & HASH_MASK
+    }
+
+    // the ISN to answer a SYN with, remembering (about) the MSS the peer asked for
+    pub fn generate(
+        &mut self,
+        local: Endpoint,
+        remote: Endpoint,
+        peer_isn: u32,
+        mss: u32,
+        now: Instant,
+    ) -> u32 {
+        self.last_sent = Some(now);
+        let counter = self.counter(now);
+        // round down, so we never send the peer more than it asked for
+        let mss_index = MSS_TABLE.iter().rposition(|&m| m <= mss).unwrap_or(0) as u32;
+        (counter << COUNTER_SHIFT)
+            | (mss_index << MSS_SHIFT)
+            | self.hash(local, remote, peer_isn, counter)
+    }
+
+    // if cookie is one we handed out recently for this connection, the MSS it remembers
+    pub fn validate(
+        &self,
+        local: Endpoint,
+        remote: Endpoint,
+        peer_isn: u32,
+        cookie: u32,
+        now: Instant,
+    ) -> Option<u32> {
+        let last_sent = self.last_sent?;
+        if now.saturating_duration_since(last_sent) > PERIOD * (MAX_AGE + 1) {
+            return None;
+        }
+
+        // only the bottom few bits of the counter made it into the cookie
+        let counter = self.counter(now);
+        let age = counter.wrapping_sub(cookie >> COUNTER_SHIFT) % (1 << (32 - COUNTER_SHIFT));
+        if age > MAX_AGE {
+            return None;
+        }
+        let counter = counter.wrapping_sub(age);
+
+        if cookie & HASH_MASK != self.hash(local, remote, peer_isn, counter) {
+            return None;
+        }
+        Some(MSS_TABLE[((cookie >> MSS_SHIFT) & 0b11) as usize])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const LOCAL: Endpoint = (IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)), 9000);
+    const REMOTE: Endpoint = (IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)), 4000);
+
+    #[test]
+    fn cookies_remember_the_mss() {
+        let now = Instant::now();
+        let mut cookies = SynCookies::new(now);
+        let cookie = cookies.generate(LOCAL, REMOTE, 1000, 1460, now);
+        assert_eq!(
+            cookies.validate(LOCAL, REMOTE, 1000, cookie, now),
+            Some(1460)
+        );
+
+        // rounded down to something we can remember
+        let cookie = cookies.generate(LOCAL, REMOTE, 1000, 1400, now);
+        assert_eq!(
+            cookies.validate(LOCAL, REMOTE, 1000, cookie, now),
+            Some(1300)
+        );
+    }
+
+    #[test]
+    fn cookies_only_work_for_their_own_connection() {
+        let now = Instant::now();
+        let mut cookies = SynCookies::new(now);
+        let cookie = cookies.generate(LOCAL, REMOTE, 1000, 1460, now);
+        assert_eq!(cookies.validate(LOCAL, REMOTE, 1001, cookie, now), None);
+        assert_eq!(
+            cookies.validate(LOCAL, (REMOTE.0, 4001), 1000, cookie, now),
+            None
+        );
+        assert_eq!(
+            cookies.validate(LOCAL, REMOTE, 1000, cookie.wrapping_add(1), now),
+            None
+        );
+    }
+
+    #[test]
+    fn cookies_expire() {
+        let now = Instant::now();
+        let mut cookies = SynCookies::new(now);
+        let cookie = cookies.generate(LOCAL, REMOTE, 1000, 1460, now);
+
+        let later = now + PERIOD * MAX_AGE;
+        assert_eq!(
+            cookies.validate(LOCAL, REMOTE, 1000, cookie, later),
+            Some(1460)
+        );
+        let too_late = now + PERIOD * (MAX_AGE + 1);
+        assert_eq!(
+            cookies.validate(LOCAL, REMOTE, 1000, cookie, too_late),
+            None
+        );
+    }
+}
@@ -0,0 +1,202 @@
+// UDP (RFC 768)
+//
+// There's nothing to UDP but ports and a checksum. Each datagram that arrives goes to the socket
+// bound to its destination port, to be received whole; each one a socket sends goes out in a
+// packet of its own, the next time the packet loop gets to it. Nothing is acked, or sent again. A
+// datagram for a port nobody is bound to gets an ICMP port unreachable back (RFC 1122 S4.1.3.1).
+
+use crate::network_parse::{self, UdpHeader, UDP_HEADER_LEN};
+use crate::nic::Nic;
+use crate::tcp::{IpHeader, MTU};
+use std::collections::VecDeque;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+pub const PROTOCOL: u8 = 17;
+
+// how many datagrams a socket holds on to either way, before the application has to catch up. past
+// that, the ones that arrive are dropped, and sending waits
+pub const QUEUE_LEN: usize = 64;
+
+const HOP_LIMIT: u8 = 64;
+
+pub struct Socket {
+    // the address it's bound to. unless that's unspecified, datagrams sent to any other address
+    // aren't for it
+    pub local: IpAddr,
+    // datagrams that have arrived, and who from
+    pub incoming: VecDeque<(SocketAddr, Vec<u8>)>,
+    // datagrams still to go, and who to
+    pub outgoing: VecDeque<(SocketAddr, Vec<u8>)>,
+}
+
+impl Socket {
+    pub fn new(local: IpAddr) -> Self {
+        Socket {
+            local,
+            incoming: VecDeque::new(),
+            outgoing: VecDeque::new(),
+        }
+    }
+
+    pub fn accepts(&self, destination: IpAddr) -> bool {
+        self.local.is_unspecified() || self.local == destination
+    }
+
+    pub fn on_datagram(&mut self, from: SocketAddr, data: &[u8]) {
+        if self.incoming.len() < QUEUE_LEN {
+            self.incoming.push_back((from, data.to_vec()));
+        }
+    }
+
+    // send everything queued up, from port. returns whether there was anything
+    pub fn flush<N: Nic>(&mut self, nic: &mut N, port: u16) -> io::Result<bool> {
+        let flushed = !self.outgoing.is_empty();
+        while let Some((remote, data)) = self.outgoing.pop_front() {
+            send(nic, SocketAddr::new(self.local, port), remote, &data)?;
+        }
+        Ok(flushed)
+    }
+}
+
+// the most data one datagram from local can carry: we don't fragment what we send, so it has to
+// fit in one packet
+pub fn max_payload(local: IpAddr) -> usize {
+    let ip_header_len = if local.is_ipv6() { 40 } else { 20 };
+    MTU - ip_header_len - UDP_HEADER_LEN
+}
+
+// the header of a datagram that has come in (the payload of an IP packet with protocol PROTOCOL),
+// and the data it carries, if it's any good
+pub fn parse<'a>(ip_header: &IpHeader, input: &'a [u8]) -> Option<(UdpHeader, &'a [u8])> {
+    let (_, udp_header) = network_parse::parse_udp(input).ok()?;
+    let len = udp_header.length as usize;
+    if len < UDP_HEADER_LEN || len > input.len() {
+        return None;
+    }
+    let datagram = &input[..len];
+
+    // a zero checksum means the sender didn't bother with one, which over IPv6 it has to (RFC 8200
+    // S8.1)
+    if udp_header.checksum != 0 || ip_header.source().is_ipv6() {
+        let pseudo_header = network_parse::pseudo_header(
+            ip_header.source(),
+            ip_header.destination(),
+            PROTOCOL,
+            len,
+        );
+        if network_parse::internet_checksum(&pseudo_header, datagram) != 0 {
+            return None;
+        }
+    }
+    Some((udp_header, &datagram[UDP_HEADER_LEN..]))
+}
+
+pub fn send<N: Nic>(
+    nic: &mut N,
+    local: SocketAddr,
+    remote: SocketAddr,
+    data: &[u8],
+) -> io::Result<()> {
+    let len = UDP_HEADER_LEN + data.len();
+    let mut udp_header = UdpHeader {
+        source_port: local.port(),
+        destination_port: remote.port(),
+        length: len as u16,
+        checksum: 0,
+    };
+    let pseudo_header = network_parse::pseudo_header(local.ip(), remote.ip(), PROTOCOL, len);
+    let mut datagram = udp_header.to_bytes().to_vec();
+    datagram.extend_from_slice(data);
+    // (a checksum that comes out as zero is sent as all ones, as zero means there isn't one)
+    udp_header.checksum = match network_parse::internet_checksum(&pseudo_header, &datagram) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    datagram[..UDP_HEADER_LEN].copy_from_slice(&udp_header.to_bytes());
+
+    let mut packet = Vec::new();
+    match (local.ip(), remote.ip()) {
+        (IpAddr::V4(local), IpAddr::V4(remote)) => etherparse::Ipv4Header::new(
+            len as u16,
+            HOP_LIMIT,
+            PROTOCOL,
+            local.octets(),
+            remote.octets(),
+        )
+        .write(&mut packet),
+        (IpAddr::V6(local), IpAddr::V6(remote)) => etherparse::Ipv6Header {
+            traffic_class: 0,
+            flow_label: 0,
+            payload_length: len as u16,
+            next_header: PROTOCOL,
+            hop_limit: HOP_LIMIT,
+            source: local.octets(),
+            destination: remote.octets(),
+        }
+        .write(&mut packet),
+        _ => return Ok(()),
+    }
+    .map_err(io::Error::other)?;
+    packet.extend(datagram);
+    nic.send(&packet)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    // the packet a datagram goes out in
+    fn sent(local: SocketAddr, remote: SocketAddr, data: &[u8]) -> Vec<u8> {
+        let mut nic = Vec::new();
+        send(&mut nic, local, remote, data).unwrap();
+        nic.remove(0)
+    }
+
+    fn ip_header(packet: &[u8]) -> (IpHeader, &[u8]) {
+        if packet[0] >> 4 == 6 {
+            let (payload, ip_header) = network_parse::parse_ipv6(packet).unwrap();
+            (IpHeader::from(&ip_header), payload)
+        } else {
+            let ip_header = etherparse::Ipv4HeaderSlice::from_slice(packet).unwrap();
+            (IpHeader::from(&ip_header), &packet[20..])
+        }
+    }
+
+    #[test]
+    fn datagrams_are_checksummed_with_the_pseudo_header() {
+        let local = SocketAddr::new(Ipv4Addr::new(192, 168, 0, 1).into(), 4000);
+        let remote = SocketAddr::new(Ipv4Addr::new(192, 168, 0, 2).into(), 53);
+        let mut packet = sent(local, remote, b"hello");
+        let (ip, payload) = ip_header(&packet);
+        let (udp_header, data) = parse(&ip, payload).unwrap();
+        assert_eq!(udp_header.source_port, 4000);
+        assert_eq!(udp_header.destination_port, 53);
+        assert_eq!(data, b"hello");
+
+        // a datagram that has been corrupted on the way is dropped
+        *packet.last_mut().unwrap() ^= 1;
+        let (ip, payload) = ip_header(&packet);
+        assert!(parse(&ip, payload).is_none());
+
+        // unless it didn't come with a checksum
+        packet[26..28].copy_from_slice(&[0, 0]);
+        let (ip, payload) = ip_header(&packet);
+        assert!(parse(&ip, payload).is_some());
+    }
+
+    #[test]
+    fn ipv6_datagrams_have_to_have_a_checksum() {
+        let local = SocketAddr::new(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1).into(), 4000);
+        let remote = SocketAddr::new(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2).into(), 53);
+        let mut packet = sent(local, remote, b"hello");
+        let (ip, payload) = ip_header(&packet);
+        assert_eq!(parse(&ip, payload).unwrap().1, b"hello");
+
+        packet[46..48].copy_from_slice(&[0, 0]);
+        let (ip, payload) = ip_header(&packet);
+        assert!(parse(&ip, payload).is_none());
+    }
+}